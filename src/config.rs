@@ -0,0 +1,377 @@
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+use crate::engine::compaction::CompactionConfig;
+use crate::engine::compression::Compression;
+use crate::engine::sstable::DEFAULT_BLOCK_SIZE_BYTES;
+use crate::engine::wal::{WalSyncMode, DEFAULT_SEGMENT_SIZE_BYTES};
+use crate::error::{Error, Result};
+
+/// Tunables for a `Database`. Build one with `DatabaseConfig::builder()` or load it from
+/// a TOML or JSON file with `DatabaseConfig::from_file`; any setting left out of a file
+/// keeps its default.
+///
+/// ```toml
+/// data_dir = "/var/lib/kassantra"
+/// memtable_flush_threshold_bytes = 67108864
+///
+/// [wal_sync_mode]
+/// mode = "group"
+/// window_ms = 15
+///
+/// [compaction]
+/// strategy = "size_tiered"
+/// min_threshold = 6
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    /// Directory holding the SSTables and WAL segments.
+    pub data_dir: String,
+    /// The MemTable is flushed to an SSTable once it holds this many bytes of keys and values.
+    pub memtable_flush_threshold_bytes: usize,
+    /// How many full MemTables can wait to be flushed before writers have to wait for
+    /// the flushes to catch up.
+    pub max_immutable_memtables: usize,
+    /// Every n-th SSTable entry goes into the sparse index.
+    pub index_every_n_entries: usize,
+    /// Target false-positive chance of the per-SSTable Bloom filters.
+    pub bloom_filter_fp_chance: f64,
+    /// How new SSTables compress their data blocks.
+    pub sstable_compression: Compression,
+    /// SSTable records are grouped into blocks of about this many uncompressed bytes, the
+    /// unit that gets compressed and read back.
+    pub sstable_block_size_bytes: usize,
+    /// How many bytes of merged entries compaction buffers before writing them out.
+    pub compaction_memory_budget_bytes: usize,
+    /// Compaction starts a new output SSTable once the current one reaches this size.
+    pub sstable_target_size_bytes: u64,
+    /// Tombstones older than this are dropped when compaction rewrites them.
+    pub gc_grace_seconds: u64,
+    /// A new WAL segment is started once the active one would grow past this size.
+    pub wal_segment_size_bytes: u64,
+    pub wal_sync_mode: WalSyncMode,
+    /// Which compaction strategy picks the SSTables to merge after a flush.
+    pub compaction: CompactionConfig,
+    /// How many flushes and compactions can be queued for the background task before
+    /// writers have to wait for it to catch up.
+    pub background_queue_size: usize,
+    /// What reads do when they find a corrupted SSTable.
+    pub corruption_policy: CorruptionPolicy,
+}
+
+/// What a read does when an SSTable it reads from turns out to be corrupted, and what
+/// loading the database does with an SSTable that doesn't open. Compactions always fail
+/// instead, since going on without the table would lose its data for good.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CorruptionPolicy {
+    /// The read or the load fails with an `Error::Corruption`.
+    #[default]
+    Fail,
+    /// The read logs the error and goes on as if the table wasn't there. A table that
+    /// doesn't open at all is quarantined.
+    Skip,
+    /// Like `Skip`, but the table is also taken out of the database and its file moved to
+    /// the `quarantine` directory in the data dir for a closer look.
+    Quarantine,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        DatabaseConfig {
+            data_dir: "data".to_string(),
+            memtable_flush_threshold_bytes: 1024,
+            max_immutable_memtables: 4,
+            index_every_n_entries: 10,
+            bloom_filter_fp_chance: 0.01,
+            sstable_compression: Compression::default(),
+            sstable_block_size_bytes: DEFAULT_BLOCK_SIZE_BYTES,
+            compaction_memory_budget_bytes: 4 * 1024 * 1024,
+            // Same as Cassandra's default sstable_size_in_mb for leveled compaction
+            sstable_target_size_bytes: 160 * 1024 * 1024,
+            // Same as Cassandra's default gc_grace_seconds, 10 days
+            gc_grace_seconds: 864000,
+            wal_segment_size_bytes: DEFAULT_SEGMENT_SIZE_BYTES,
+            wal_sync_mode: WalSyncMode::default(),
+            compaction: CompactionConfig::default(),
+            background_queue_size: 16,
+            corruption_policy: CorruptionPolicy::default(),
+        }
+    }
+}
+
+impl DatabaseConfig {
+    pub fn builder() -> DatabaseConfigBuilder {
+        DatabaseConfigBuilder {
+            config: DatabaseConfig::default(),
+        }
+    }
+
+    /// Loads a config file. Files ending in `.json` are parsed as JSON, anything else as TOML.
+    pub fn from_file(path: &str) -> Result<DatabaseConfig> {
+        let contents = std::fs::read_to_string(path)?;
+        let is_json = Path::new(path)
+            .extension()
+            .is_some_and(|extension| extension == "json");
+
+        let config: DatabaseConfig = if is_json {
+            serde_json::from_str(&contents).map_err(|e| invalid(format!("{}: {}", path, e)))?
+        } else {
+            toml::from_str(&contents).map_err(|e| invalid(format!("{}: {}", path, e)))?
+        };
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.data_dir.is_empty() {
+            return Err(invalid("data_dir must not be empty".to_string()));
+        }
+        if self.memtable_flush_threshold_bytes == 0 {
+            return Err(invalid(
+                "memtable_flush_threshold_bytes must be greater than 0".to_string(),
+            ));
+        }
+        if self.max_immutable_memtables == 0 {
+            return Err(invalid(
+                "max_immutable_memtables must be greater than 0".to_string(),
+            ));
+        }
+        if self.index_every_n_entries == 0 {
+            return Err(invalid(
+                "index_every_n_entries must be greater than 0".to_string(),
+            ));
+        }
+        if !(self.bloom_filter_fp_chance > 0.0 && self.bloom_filter_fp_chance < 1.0) {
+            return Err(invalid(
+                "bloom_filter_fp_chance must be between 0 and 1".to_string(),
+            ));
+        }
+        if self.sstable_block_size_bytes == 0 {
+            return Err(invalid(
+                "sstable_block_size_bytes must be greater than 0".to_string(),
+            ));
+        }
+        if self.compaction_memory_budget_bytes == 0 {
+            return Err(invalid(
+                "compaction_memory_budget_bytes must be greater than 0".to_string(),
+            ));
+        }
+        if self.sstable_target_size_bytes == 0 {
+            return Err(invalid(
+                "sstable_target_size_bytes must be greater than 0".to_string(),
+            ));
+        }
+        if self.wal_segment_size_bytes == 0 {
+            return Err(invalid(
+                "wal_segment_size_bytes must be greater than 0".to_string(),
+            ));
+        }
+        if self.background_queue_size == 0 {
+            return Err(invalid(
+                "background_queue_size must be greater than 0".to_string(),
+            ));
+        }
+        self.compaction.validate()
+    }
+}
+
+// A data dir is enough to get a database with default settings, e.g. `Database::new("data")`
+impl From<&str> for DatabaseConfig {
+    fn from(data_dir: &str) -> Self {
+        DatabaseConfig {
+            data_dir: data_dir.to_string(),
+            ..DatabaseConfig::default()
+        }
+    }
+}
+
+impl From<&String> for DatabaseConfig {
+    fn from(data_dir: &String) -> Self {
+        DatabaseConfig::from(data_dir.as_str())
+    }
+}
+
+pub struct DatabaseConfigBuilder {
+    config: DatabaseConfig,
+}
+
+impl DatabaseConfigBuilder {
+    pub fn data_dir(mut self, data_dir: &str) -> Self {
+        self.config.data_dir = data_dir.to_string();
+        self
+    }
+
+    pub fn memtable_flush_threshold_bytes(mut self, bytes: usize) -> Self {
+        self.config.memtable_flush_threshold_bytes = bytes;
+        self
+    }
+
+    pub fn max_immutable_memtables(mut self, max: usize) -> Self {
+        self.config.max_immutable_memtables = max;
+        self
+    }
+
+    pub fn compaction(mut self, compaction: CompactionConfig) -> Self {
+        self.config.compaction = compaction;
+        self
+    }
+
+    pub fn index_every_n_entries(mut self, n: usize) -> Self {
+        self.config.index_every_n_entries = n;
+        self
+    }
+
+    pub fn bloom_filter_fp_chance(mut self, fp_chance: f64) -> Self {
+        self.config.bloom_filter_fp_chance = fp_chance;
+        self
+    }
+
+    pub fn sstable_compression(mut self, compression: Compression) -> Self {
+        self.config.sstable_compression = compression;
+        self
+    }
+
+    pub fn sstable_block_size_bytes(mut self, bytes: usize) -> Self {
+        self.config.sstable_block_size_bytes = bytes;
+        self
+    }
+
+    pub fn compaction_memory_budget_bytes(mut self, bytes: usize) -> Self {
+        self.config.compaction_memory_budget_bytes = bytes;
+        self
+    }
+
+    pub fn sstable_target_size_bytes(mut self, bytes: u64) -> Self {
+        self.config.sstable_target_size_bytes = bytes;
+        self
+    }
+
+    pub fn gc_grace_seconds(mut self, seconds: u64) -> Self {
+        self.config.gc_grace_seconds = seconds;
+        self
+    }
+
+    pub fn wal_segment_size_bytes(mut self, bytes: u64) -> Self {
+        self.config.wal_segment_size_bytes = bytes;
+        self
+    }
+
+    pub fn wal_sync_mode(mut self, mode: WalSyncMode) -> Self {
+        self.config.wal_sync_mode = mode;
+        self
+    }
+
+    pub fn background_queue_size(mut self, size: usize) -> Self {
+        self.config.background_queue_size = size;
+        self
+    }
+
+    pub fn corruption_policy(mut self, policy: CorruptionPolicy) -> Self {
+        self.config.corruption_policy = policy;
+        self
+    }
+
+    pub fn build(self) -> Result<DatabaseConfig> {
+        self.config.validate()?;
+        Ok(self.config)
+    }
+}
+
+fn invalid(msg: String) -> Error {
+    Error::Config(msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::compaction::SizeTieredCompactionStrategy;
+    use std::time::Duration;
+
+    #[test]
+    fn test_config_from_toml_and_json_files() {
+        let dir = std::env::temp_dir().join(format!("kassantra_config_{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let toml_path = dir.join("kassantra.toml");
+        std::fs::write(
+            &toml_path,
+            r#"
+data_dir = "somewhere"
+memtable_flush_threshold_bytes = 4096
+sstable_compression = "none"
+
+[wal_sync_mode]
+mode = "group"
+window_ms = 15
+
+[compaction]
+strategy = "size_tiered"
+min_threshold = 6
+"#,
+        )
+        .unwrap();
+        let config = DatabaseConfig::from_file(toml_path.to_str().unwrap()).unwrap();
+        assert_eq!(config.data_dir, "somewhere");
+        assert_eq!(config.memtable_flush_threshold_bytes, 4096);
+        assert_eq!(config.sstable_compression, Compression::None);
+        assert_eq!(
+            config.wal_sync_mode,
+            WalSyncMode::Group {
+                window: Duration::from_millis(15)
+            }
+        );
+        let CompactionConfig::SizeTiered(size_tiered) = &config.compaction else {
+            panic!(
+                "expected size-tiered compaction, got {:?}",
+                config.compaction
+            );
+        };
+        assert_eq!(size_tiered.min_threshold, 6);
+        // everything else keeps its default
+        assert_eq!(size_tiered.max_threshold, 32);
+        assert_eq!(config.index_every_n_entries, 10);
+
+        let json_path = dir.join("kassantra.json");
+        std::fs::write(
+            &json_path,
+            r#"{"index_every_n_entries": 3, "wal_sync_mode": {"mode": "batch"}}"#,
+        )
+        .unwrap();
+        let config = DatabaseConfig::from_file(json_path.to_str().unwrap()).unwrap();
+        assert_eq!(config.index_every_n_entries, 3);
+        assert_eq!(config.wal_sync_mode, WalSyncMode::Batch);
+
+        std::fs::write(&toml_path, "index_every_n_entries = 0").unwrap();
+        assert!(DatabaseConfig::from_file(toml_path.to_str().unwrap()).is_err());
+        std::fs::write(&toml_path, "no_such_setting = 1").unwrap();
+        assert!(DatabaseConfig::from_file(toml_path.to_str().unwrap()).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_config_builder_validates() {
+        let config = DatabaseConfig::builder()
+            .data_dir("elsewhere")
+            .gc_grace_seconds(60)
+            .build()
+            .unwrap();
+        assert_eq!(config.data_dir, "elsewhere");
+        assert_eq!(config.gc_grace_seconds, 60);
+
+        assert!(DatabaseConfig::builder()
+            .bloom_filter_fp_chance(1.5)
+            .build()
+            .is_err());
+        assert!(DatabaseConfig::builder()
+            .compaction(CompactionConfig::SizeTiered(SizeTieredCompactionStrategy {
+                min_threshold: 1,
+                ..SizeTieredCompactionStrategy::default()
+            }))
+            .build()
+            .is_err());
+    }
+}
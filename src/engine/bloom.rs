@@ -0,0 +1,173 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Bloom filter section layout: magic, number of hash functions, number of bits, then the bit words
+const BLOOM_MAGIC: &[u8; 4] = b"KBLM";
+
+/// A Bloom filter over SSTable keys. Answers "definitely not here" or "maybe here",
+/// which lets reads skip SSTables that can't contain a key without touching the disk.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    num_bits: u64,
+    num_hashes: u32,
+}
+
+impl BloomFilter {
+    /// Creates a filter sized for `expected_items` keys with roughly the given false-positive chance.
+    pub fn new(expected_items: usize, fp_chance: f64) -> BloomFilter {
+        let fp_chance = fp_chance.clamp(1e-9, 0.5);
+        let n = expected_items.max(1) as f64;
+        let ln2 = std::f64::consts::LN_2;
+        // optimal m = -n ln(p) / (ln 2)^2, k = m/n ln 2
+        let num_bits = ((-n * fp_chance.ln()) / (ln2 * ln2)).ceil().max(64.0) as u64;
+        let num_hashes = ((num_bits as f64 / n) * ln2).round().clamp(1.0, 30.0) as u32;
+
+        BloomFilter {
+            bits: vec![0; num_bits.div_ceil(64) as usize],
+            num_bits,
+            num_hashes,
+        }
+    }
+
+    pub fn insert(&mut self, key: &str) {
+        let (h1, h2) = hash_pair(key.as_bytes());
+        for i in 0..self.num_hashes as u64 {
+            let bit = h1.wrapping_add(i.wrapping_mul(h2)) % self.num_bits;
+            self.bits[(bit / 64) as usize] |= 1 << (bit % 64);
+        }
+    }
+
+    /// Returns false if the key was definitely never inserted.
+    pub fn may_contain(&self, key: &str) -> bool {
+        let (h1, h2) = hash_pair(key.as_bytes());
+        (0..self.num_hashes as u64).all(|i| {
+            let bit = h1.wrapping_add(i.wrapping_mul(h2)) % self.num_bits;
+            self.bits[(bit / 64) as usize] & (1 << (bit % 64)) != 0
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(16 + self.bits.len() * 8);
+        buf.extend_from_slice(BLOOM_MAGIC);
+        buf.extend_from_slice(&self.num_hashes.to_le_bytes());
+        buf.extend_from_slice(&self.num_bits.to_le_bytes());
+        for word in self.bits.iter() {
+            buf.extend_from_slice(&word.to_le_bytes());
+        }
+        buf
+    }
+
+    /// Decodes a filter written by `to_bytes`, or returns why the bytes aren't one.
+    pub fn from_bytes(bytes: &[u8]) -> Result<BloomFilter, String> {
+        let corrupt = |msg: &str| format!("corrupt bloom filter: {}", msg);
+
+        if bytes.len() < 16 || &bytes[0..4] != BLOOM_MAGIC {
+            return Err(corrupt("bad header"));
+        }
+        let num_hashes = u32::from_le_bytes(bytes[4..8].try_into().unwrap());
+        let num_bits = u64::from_le_bytes(bytes[8..16].try_into().unwrap());
+        if num_hashes == 0 || num_bits == 0 {
+            return Err(corrupt("empty filter"));
+        }
+        let words = &bytes[16..];
+        if words.len() as u64 != num_bits.div_ceil(64) * 8 {
+            return Err(corrupt("bit array length doesn't match header"));
+        }
+        let bits = words
+            .chunks_exact(8)
+            .map(|chunk| u64::from_le_bytes(chunk.try_into().unwrap()))
+            .collect();
+
+        Ok(BloomFilter {
+            bits,
+            num_bits,
+            num_hashes,
+        })
+    }
+}
+
+// Two independent 64-bit hashes for double hashing (h1 + i * h2). These get persisted,
+// so they have to stay stable across Rust versions, which rules out std's DefaultHasher.
+fn hash_pair(bytes: &[u8]) -> (u64, u64) {
+    // FNV-1a
+    let mut h1: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        h1 ^= *byte as u64;
+        h1 = h1.wrapping_mul(0x100000001b3);
+    }
+    // splitmix64 finalizer over h1, forced odd so the probe sequence never gets stuck
+    let mut h2 = h1.wrapping_add(0x9e3779b97f4a7c15);
+    h2 = (h2 ^ (h2 >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    h2 = (h2 ^ (h2 >> 27)).wrapping_mul(0x94d049bb133111eb);
+    h2 ^= h2 >> 31;
+    (h1, h2 | 1)
+}
+
+/// Counters for how often Bloom filters let reads skip an SSTable.
+#[derive(Debug, Default)]
+pub struct BloomFilterMetrics {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    false_positives: AtomicU64,
+}
+
+impl BloomFilterMetrics {
+    /// The filter said the key may be in the table, so the table was read.
+    pub fn record_hit(&self) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The filter ruled the table out, so no disk I/O was done for it.
+    pub fn record_miss(&self) {
+        self.misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The table was read after a filter hit but the key wasn't there.
+    pub fn record_false_positive(&self) {
+        self.false_positives.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    pub fn false_positives(&self) -> u64 {
+        self.false_positives.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bloom_filter_has_no_false_negatives() {
+        let mut bloom_filter = BloomFilter::new(1000, 0.01);
+        for i in 0..1000 {
+            bloom_filter.insert(&format!("key{}", i));
+        }
+        for i in 0..1000 {
+            assert!(bloom_filter.may_contain(&format!("key{}", i)));
+        }
+
+        let false_positives = (0..10000)
+            .filter(|i| bloom_filter.may_contain(&format!("other{}", i)))
+            .count();
+        assert!(false_positives < 300, "{} false positives", false_positives);
+    }
+
+    #[test]
+    fn test_bloom_filter_round_trips_through_bytes() {
+        let mut bloom_filter = BloomFilter::new(10, 0.05);
+        bloom_filter.insert("foo");
+        bloom_filter.insert("bar");
+
+        let bytes = bloom_filter.to_bytes();
+        assert_eq!(BloomFilter::from_bytes(&bytes).unwrap(), bloom_filter);
+        assert!(BloomFilter::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+    }
+}
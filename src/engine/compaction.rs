@@ -0,0 +1,582 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use super::bloom::BloomFilter;
+use super::compression::Compression;
+use super::operation::Cell;
+use super::sstable::SSTable;
+use crate::error::{Error, Result};
+use crate::{Database, DatabaseConfig};
+
+/// Decides which SSTables get merged together, mirroring Cassandra's compaction strategies.
+pub trait CompactionStrategy: Send + Sync {
+    /// Picks the next SSTables to compact out of `sstables` (oldest first), or returns
+    /// `None` if nothing needs compacting right now.
+    fn next_compaction(&self, sstables: &[Arc<SSTable>]) -> Option<CompactionTask>;
+
+    /// Whether SSTables whose every entry has expired may be dropped as a whole instead of
+    /// waiting for a compaction to purge their entries one by one.
+    fn drops_expired_sstables(&self) -> bool {
+        false
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompactionTask {
+    /// Indexes of the SSTables to merge, oldest first. They don't have to be adjacent, the
+    /// version of a key with the highest write timestamp wins wherever it is.
+    pub inputs: Vec<usize>,
+    /// Whether to split the output into SSTables of about `sstable_target_size_bytes`.
+    pub split_output: bool,
+    /// The level the output SSTables go in. Strategies without levels leave everything at 0.
+    pub output_level: u32,
+}
+
+/// The compaction strategy to use and its settings.
+///
+/// ```toml
+/// [compaction]
+/// strategy = "size_tiered"
+/// min_threshold = 4
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "strategy", rename_all = "snake_case")]
+pub enum CompactionConfig {
+    SizeTiered(SizeTieredCompactionStrategy),
+    Leveled(LeveledCompactionStrategy),
+    TimeWindow(TimeWindowCompactionStrategy),
+}
+
+impl Default for CompactionConfig {
+    fn default() -> Self {
+        CompactionConfig::SizeTiered(SizeTieredCompactionStrategy::default())
+    }
+}
+
+impl CompactionConfig {
+    pub fn validate(&self) -> Result<()> {
+        match self {
+            CompactionConfig::SizeTiered(strategy) => strategy.validate(),
+            CompactionConfig::Leveled(strategy) => strategy.validate(),
+            CompactionConfig::TimeWindow(strategy) => strategy.validate(),
+        }
+    }
+
+    pub fn build(&self) -> Box<dyn CompactionStrategy> {
+        match self {
+            CompactionConfig::SizeTiered(strategy) => Box::new(strategy.clone()),
+            CompactionConfig::Leveled(strategy) => Box::new(strategy.clone()),
+            CompactionConfig::TimeWindow(strategy) => Box::new(strategy.clone()),
+        }
+    }
+}
+
+/// Merges SSTables of similar size, like Cassandra's `SizeTieredCompactionStrategy`. Every
+/// table is rewritten about once per tier instead of on every compaction.
+///
+/// Tables are bucketed by size wherever they are in the list: the version of a key with
+/// the highest write timestamp wins no matter which table it's in (last write wins), so
+/// merging tables that aren't next to each other doesn't change what reads return.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SizeTieredCompactionStrategy {
+    /// A bucket is compacted once it has this many SSTables.
+    pub min_threshold: usize,
+    /// At most this many SSTables are compacted at once.
+    pub max_threshold: usize,
+    /// Tables belong in a bucket if their size is between `bucket_low` and `bucket_high`
+    /// times the bucket's average size.
+    pub bucket_low: f64,
+    pub bucket_high: f64,
+    /// Tables smaller than this all go in the same bucket.
+    pub min_sstable_size_bytes: u64,
+}
+
+impl Default for SizeTieredCompactionStrategy {
+    // Cassandra's defaults
+    fn default() -> Self {
+        SizeTieredCompactionStrategy {
+            min_threshold: 4,
+            max_threshold: 32,
+            bucket_low: 0.5,
+            bucket_high: 1.5,
+            min_sstable_size_bytes: 50 * 1024 * 1024,
+        }
+    }
+}
+
+impl SizeTieredCompactionStrategy {
+    pub fn validate(&self) -> Result<()> {
+        if self.min_threshold < 2 {
+            return Err(Error::Config(
+                "compaction min_threshold must be at least 2".to_string(),
+            ));
+        }
+        if self.max_threshold < self.min_threshold {
+            return Err(Error::Config(
+                "compaction max_threshold must be at least min_threshold".to_string(),
+            ));
+        }
+        if !(self.bucket_low > 0.0 && self.bucket_low <= 1.0 && self.bucket_high >= 1.0) {
+            return Err(Error::Config(
+                "compaction bucket_low must be in (0, 1] and bucket_high at least 1".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    // Groups the tables by size, wherever they are in the list: each goes in the first
+    // bucket whose average size it's similar to. Returns the indexes of every bucket's
+    // tables, smallest first.
+    fn buckets(&self, sizes: &[u64]) -> Vec<Vec<usize>> {
+        let mut by_size = (0..sizes.len()).collect::<Vec<_>>();
+        by_size.sort_by_key(|i| sizes[*i]);
+        // the tables in each bucket and their total size
+        let mut buckets: Vec<(Vec<usize>, u64)> = vec![];
+        for i in by_size {
+            let size = sizes[i];
+            let bucket = buckets.iter_mut().find(|(bucket, total_size)| {
+                let average_size = *total_size as f64 / bucket.len() as f64;
+                let both_small = size < self.min_sstable_size_bytes
+                    && (average_size as u64) < self.min_sstable_size_bytes;
+                let similar = size as f64 >= average_size * self.bucket_low
+                    && size as f64 <= average_size * self.bucket_high;
+                both_small || similar
+            });
+            match bucket {
+                Some((bucket, total_size)) => {
+                    bucket.push(i);
+                    *total_size += size;
+                }
+                None => buckets.push((vec![i], size)),
+            }
+        }
+        buckets.into_iter().map(|(bucket, _)| bucket).collect()
+    }
+}
+
+impl CompactionStrategy for SizeTieredCompactionStrategy {
+    fn next_compaction(&self, sstables: &[Arc<SSTable>]) -> Option<CompactionTask> {
+        let sizes = sstables
+            .iter()
+            .map(|sstable| sstable.size_bytes())
+            .collect::<Vec<_>>();
+
+        // of the buckets that are full enough, compact the one with the smallest tables first
+        // since it's the cheapest way to bring the table count down
+        self.buckets(&sizes)
+            .into_iter()
+            .filter(|bucket| bucket.len() >= self.min_threshold)
+            .min_by_key(|bucket| {
+                bucket.iter().map(|i| sizes[*i]).sum::<u64>() / bucket.len() as u64
+            })
+            .map(|bucket| {
+                // the smallest tables of the bucket, oldest first
+                let mut inputs = bucket
+                    .into_iter()
+                    .take(self.max_threshold)
+                    .collect::<Vec<_>>();
+                inputs.sort_unstable();
+                CompactionTask {
+                    inputs,
+                    // splitting would make the output look like a bucket of its own
+                    split_output: false,
+                    output_level: 0,
+                }
+            })
+    }
+}
+
+/// Keeps SSTables in levels, like Cassandra's `LeveledCompactionStrategy`. Flushed tables
+/// land in L0 where they may overlap; from L1 on every level is a run of non-overlapping
+/// tables of about `sstable_target_size_bytes`, each level `fanout_size` times bigger than
+/// the one above it. A read has to look at every L0 table but at most one table per level
+/// after that, at the cost of rewriting data more often than size-tiered compaction.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LeveledCompactionStrategy {
+    /// L0 is compacted into L1 once it has this many SSTables.
+    pub l0_compaction_threshold: usize,
+    /// L1 is compacted into L2 once it's bigger than this.
+    pub max_bytes_for_level_base: u64,
+    /// Every level after L1 may hold this many times more data than the one above it.
+    pub fanout_size: u64,
+}
+
+impl Default for LeveledCompactionStrategy {
+    fn default() -> Self {
+        LeveledCompactionStrategy {
+            l0_compaction_threshold: 4,
+            // 10 SSTables of the default target size, like Cassandra's L1
+            max_bytes_for_level_base: 10 * 160 * 1024 * 1024,
+            fanout_size: 10,
+        }
+    }
+}
+
+impl LeveledCompactionStrategy {
+    pub fn validate(&self) -> Result<()> {
+        if self.l0_compaction_threshold == 0 {
+            return Err(Error::Config(
+                "compaction l0_compaction_threshold must be greater than 0".to_string(),
+            ));
+        }
+        if self.max_bytes_for_level_base == 0 {
+            return Err(Error::Config(
+                "compaction max_bytes_for_level_base must be greater than 0".to_string(),
+            ));
+        }
+        if self.fanout_size < 2 {
+            return Err(Error::Config(
+                "compaction fanout_size must be at least 2".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// How many bytes the level may hold before some of it is compacted into the next one.
+    pub fn max_bytes_for_level(&self, level: u32) -> u64 {
+        (1..level).fold(self.max_bytes_for_level_base, |max_bytes, _| {
+            max_bytes.saturating_mul(self.fanout_size)
+        })
+    }
+}
+
+impl CompactionStrategy for LeveledCompactionStrategy {
+    fn next_compaction(&self, sstables: &[Arc<SSTable>]) -> Option<CompactionTask> {
+        let in_level = |level: u32| {
+            sstables
+                .iter()
+                .enumerate()
+                .filter(move |(_, sstable)| sstable.level() == level)
+                .map(|(i, _)| i)
+        };
+        let overlapping = |level: u32, min: &str, max: &str| {
+            in_level(level)
+                .filter(|i| overlaps(sstables[*i].key_range(), Some((min, max))))
+                .collect::<Vec<_>>()
+        };
+
+        // L0 tables overlap each other, so all of them go into L1 at once along with every
+        // L1 table inside their combined key range
+        let l0 = in_level(0).collect::<Vec<_>>();
+        if l0.len() >= self.l0_compaction_threshold {
+            let mut inputs = l0.clone();
+            if let Some((min, max)) = combined_key_range(l0.iter().map(|i| sstables[*i].as_ref())) {
+                inputs.extend(overlapping(1, min, max));
+            }
+            inputs.sort_unstable();
+            return Some(CompactionTask {
+                inputs,
+                split_output: true,
+                output_level: 1,
+            });
+        }
+
+        // then push one table down from the first level that has outgrown its size, picking
+        // the one that overlaps the least data in the next level
+        let max_level = sstables.iter().map(|sstable| sstable.level()).max()?;
+        for level in 1..=max_level {
+            let level_size = in_level(level)
+                .map(|i| sstables[i].size_bytes())
+                .sum::<u64>();
+            if level_size <= self.max_bytes_for_level(level) {
+                continue;
+            }
+
+            let inputs_for = |i: usize| {
+                let mut inputs = vec![i];
+                if let Some((min, max)) = sstables[i].key_range() {
+                    inputs.extend(overlapping(level + 1, min, max));
+                }
+                inputs.sort_unstable();
+                inputs
+            };
+            let inputs = in_level(level).map(inputs_for).min_by_key(|inputs| {
+                inputs
+                    .iter()
+                    .map(|i| sstables[*i].size_bytes())
+                    .sum::<u64>()
+            })?;
+            return Some(CompactionTask {
+                inputs,
+                split_output: true,
+                output_level: level + 1,
+            });
+        }
+
+        None
+    }
+}
+
+/// The unit of `TimeWindowCompactionStrategy::compaction_window_size`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TimeWindowUnit {
+    Minutes,
+    Hours,
+    Days,
+}
+
+impl TimeWindowUnit {
+    pub fn seconds(&self) -> u64 {
+        match self {
+            TimeWindowUnit::Minutes => 60,
+            TimeWindowUnit::Hours => 60 * 60,
+            TimeWindowUnit::Days => 24 * 60 * 60,
+        }
+    }
+}
+
+/// Groups SSTables by the time window their newest write timestamp falls in, like
+/// Cassandra's `TimeWindowCompactionStrategy`, for append-only data that's written once and
+/// expires. Tables are only ever compacted with others from the same window: size-tiered in
+/// the newest window while it's still being written to, and into a single table per window
+/// once it's over. Tables whose every entry has expired are dropped without compacting them.
+///
+/// ```toml
+/// [compaction]
+/// strategy = "time_window"
+/// compaction_window_unit = "hours"
+/// compaction_window_size = 6
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimeWindowCompactionStrategy {
+    pub compaction_window_unit: TimeWindowUnit,
+    /// How many units each window spans.
+    pub compaction_window_size: u64,
+    /// The newest window is compacted once a size tier in it has this many SSTables.
+    pub min_threshold: usize,
+    /// At most this many SSTables are compacted at once.
+    pub max_threshold: usize,
+}
+
+impl Default for TimeWindowCompactionStrategy {
+    // Cassandra's defaults
+    fn default() -> Self {
+        TimeWindowCompactionStrategy {
+            compaction_window_unit: TimeWindowUnit::Days,
+            compaction_window_size: 1,
+            min_threshold: 4,
+            max_threshold: 32,
+        }
+    }
+}
+
+impl TimeWindowCompactionStrategy {
+    pub fn validate(&self) -> Result<()> {
+        if self.compaction_window_size == 0 {
+            return Err(Error::Config(
+                "compaction compaction_window_size must be greater than 0".to_string(),
+            ));
+        }
+        self.size_tiered().validate()
+    }
+
+    /// The start of the window the time falls in, in seconds since the Unix epoch.
+    pub fn window_start(&self, time: u64) -> u64 {
+        let window_seconds = self.compaction_window_unit.seconds() * self.compaction_window_size;
+        time - time % window_seconds
+    }
+
+    // How the newest window is compacted
+    fn size_tiered(&self) -> SizeTieredCompactionStrategy {
+        SizeTieredCompactionStrategy {
+            min_threshold: self.min_threshold,
+            max_threshold: self.max_threshold,
+            ..SizeTieredCompactionStrategy::default()
+        }
+    }
+}
+
+impl CompactionStrategy for TimeWindowCompactionStrategy {
+    fn next_compaction(&self, sstables: &[Arc<SSTable>]) -> Option<CompactionTask> {
+        // the window is the one of the newest cell timestamp, so a write with an older
+        // timestamp, or one that was flushed late, goes with the rest of its window even if
+        // the tables in between are from another one
+        let mut windows = BTreeMap::<u64, Vec<usize>>::new();
+        for (i, sstable) in sstables.iter().enumerate() {
+            let window = self.window_start(sstable.stats().max_timestamp / 1_000_000);
+            windows.entry(window).or_default().push(i);
+        }
+
+        let (_, newest) = windows.pop_last()?;
+        let newest_sstables = newest
+            .iter()
+            .map(|i| sstables[*i].clone())
+            .collect::<Vec<_>>();
+        if let Some(task) = self.size_tiered().next_compaction(&newest_sstables) {
+            return Some(CompactionTask {
+                inputs: task.inputs.iter().map(|i| newest[*i]).collect(),
+                ..task
+            });
+        }
+
+        // older windows aren't written to anymore, so each one ends up as a single table
+        windows
+            .into_values()
+            .rev()
+            .find(|window| window.len() >= 2)
+            .map(|window| CompactionTask {
+                inputs: window.into_iter().take(self.max_threshold).collect(),
+                split_output: false,
+                output_level: 0,
+            })
+    }
+
+    fn drops_expired_sstables(&self) -> bool {
+        true
+    }
+}
+
+/// Whether two key ranges share any keys. Empty tables overlap nothing.
+pub(crate) fn overlaps(a: Option<(&str, &str)>, b: Option<(&str, &str)>) -> bool {
+    match (a, b) {
+        (Some((a_min, a_max)), Some((b_min, b_max))) => a_min <= b_max && b_min <= a_max,
+        _ => false,
+    }
+}
+
+// The smallest and largest key of all the tables
+fn combined_key_range<'a>(
+    sstables: impl Iterator<Item = &'a SSTable>,
+) -> Option<(&'a str, &'a str)> {
+    sstables
+        .filter_map(|sstable| sstable.key_range())
+        .reduce(|(min, max), (other_min, other_max)| (min.min(other_min), max.max(other_max)))
+}
+
+/// Writes the merged output of a compaction as it is produced instead of collecting it
+/// all first. Entries are buffered up to the memory budget and then appended to the
+/// current output SSTable, which is finished and a new one started once it reaches the
+/// target size.
+pub struct CompactionWriter {
+    data_dir: String,
+    index_every_n_entries: usize,
+    bloom_filter_fp_chance: f64,
+    compression: Compression,
+    block_size_bytes: usize,
+    memory_budget_bytes: usize,
+    target_sstable_size_bytes: u64,
+    output_level: u32,
+    max_write_time: u64,
+    // how many keys the Bloom filter of each output table is sized for
+    expected_entries: usize,
+    buffer: Vec<(String, Cell)>,
+    buffered_bytes: usize,
+    current: Option<(SSTable, BloomFilter)>,
+    entries_in_current: usize,
+    finished: Vec<SSTable>,
+}
+
+impl CompactionWriter {
+    /// `expected_entries` is an upper bound on how many entries the compaction writes,
+    /// e.g. the sum of the input table sizes, and `max_write_time` the newest write time of
+    /// the inputs. Unless the task splits its output everything goes into a single table.
+    pub fn new(
+        config: &DatabaseConfig,
+        expected_entries: usize,
+        max_write_time: u64,
+        task: &CompactionTask,
+    ) -> CompactionWriter {
+        CompactionWriter {
+            data_dir: config.data_dir.clone(),
+            index_every_n_entries: config.index_every_n_entries,
+            bloom_filter_fp_chance: config.bloom_filter_fp_chance,
+            compression: config.sstable_compression,
+            block_size_bytes: config.sstable_block_size_bytes,
+            memory_budget_bytes: config.compaction_memory_budget_bytes,
+            target_sstable_size_bytes: match task.split_output {
+                true => config.sstable_target_size_bytes,
+                false => u64::MAX,
+            },
+            output_level: task.output_level,
+            max_write_time,
+            expected_entries,
+            buffer: Vec::new(),
+            buffered_bytes: 0,
+            current: None,
+            entries_in_current: 0,
+            finished: Vec::new(),
+        }
+    }
+
+    /// Adds the next entry of the output. Entries must come in key order.
+    pub async fn add(&mut self, key: String, cell: Cell) -> Result<()> {
+        // record header + key + timestamp and value
+        self.buffered_bytes += 9 + key.len() + cell.size_bytes();
+        self.buffer.push((key, cell));
+
+        let current_size = match &self.current {
+            Some((sstable, _)) => sstable.size_bytes(),
+            None => 0,
+        };
+        if self.buffered_bytes >= self.memory_budget_bytes
+            || current_size + self.buffered_bytes as u64 >= self.target_sstable_size_bytes
+        {
+            self.write_buffer().await?;
+        }
+        Ok(())
+    }
+
+    /// Writes out whatever is still buffered and returns the output SSTables, oldest keys first.
+    pub async fn finish(mut self) -> Result<Vec<SSTable>> {
+        self.write_buffer().await?;
+        self.finish_current().await?;
+        Ok(self.finished)
+    }
+
+    async fn write_buffer(&mut self) -> Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+
+        if self.current.is_none() {
+            let path = Database::new_sstable_path(&self.data_dir);
+            let mut sstable = SSTable::with_compression(&path, self.compression).await?;
+            sstable.index_every_n_entries = self.index_every_n_entries;
+            sstable.bloom_filter_fp_chance = self.bloom_filter_fp_chance;
+            sstable.block_size_bytes = self.block_size_bytes;
+            sstable.set_level(self.output_level);
+            sstable.set_max_write_time(self.max_write_time);
+            let bloom_filter = BloomFilter::new(self.expected_entries, self.bloom_filter_fp_chance);
+            self.current = Some((sstable, bloom_filter));
+            self.entries_in_current = 0;
+        }
+        let (sstable, bloom_filter) = self.current.as_mut().unwrap();
+
+        let entries = self
+            .buffer
+            .iter()
+            .map(|(key, cell)| (key, cell))
+            .collect::<Vec<_>>();
+        let offsets = sstable.batch_write(&entries).await?;
+
+        // keep sampling every n-th entry of the whole table, not of each chunk
+        for (i, (key, _)) in entries.iter().enumerate() {
+            if (self.entries_in_current + i).is_multiple_of(self.index_every_n_entries) {
+                sstable.write_to_index(key.to_string(), offsets[i] as u64);
+            }
+            bloom_filter.insert(key);
+        }
+        self.entries_in_current += entries.len();
+        self.buffer.clear();
+        self.buffered_bytes = 0;
+
+        if sstable.size_bytes() >= self.target_sstable_size_bytes {
+            self.finish_current().await?;
+        }
+        Ok(())
+    }
+
+    async fn finish_current(&mut self) -> Result<()> {
+        if let Some((mut sstable, bloom_filter)) = self.current.take() {
+            sstable.set_bloom_filter(bloom_filter);
+            sstable.finish().await?;
+            self.finished.push(sstable);
+        }
+        Ok(())
+    }
+}
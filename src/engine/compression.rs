@@ -0,0 +1,54 @@
+use serde::{Deserialize, Serialize};
+
+/// How SSTable data blocks are compressed. Every SSTable records the one it was written
+/// with, so changing the setting only affects tables written from then on.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Compression {
+    None,
+    #[default]
+    Lz4,
+}
+
+impl Compression {
+    /// The byte the compression is recorded as in the SSTable header.
+    pub fn id(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Lz4 => 1,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Compression> {
+        match id {
+            0 => Some(Compression::None),
+            1 => Some(Compression::Lz4),
+            _ => None,
+        }
+    }
+
+    pub fn compress(self, bytes: &[u8]) -> Vec<u8> {
+        match self {
+            Compression::None => bytes.to_vec(),
+            Compression::Lz4 => lz4_flex::block::compress(bytes),
+        }
+    }
+
+    /// Decompresses a block that was `length` bytes before compression, or returns why
+    /// the bytes don't decompress to one.
+    pub fn decompress(self, bytes: &[u8], length: usize) -> Result<Vec<u8>, String> {
+        let decompressed = match self {
+            Compression::None => bytes.to_vec(),
+            Compression::Lz4 => lz4_flex::block::decompress(bytes, length)
+                .map_err(|e| format!("lz4 decompression failed: {}", e))?,
+        };
+        if decompressed.len() != length {
+            return Err(format!(
+                "block decompressed to {} bytes instead of {}",
+                decompressed.len(),
+                length
+            ));
+        }
+        Ok(decompressed)
+    }
+}
@@ -0,0 +1,150 @@
+use log::info;
+use std::io::Write;
+use std::path::Path;
+
+use crate::error::{Error, Result};
+
+// Manifest layout: magic, SSTable count, (name length, name) per SSTable oldest first,
+// then a crc32 of everything before it
+const MANIFEST_MAGIC: &[u8; 4] = b"KMAN";
+pub const MANIFEST_FILE_NAME: &str = "MANIFEST";
+
+/// The list of live SSTables in a data dir, oldest first. Every flush, compaction and drop
+/// writes out the new list before it touches the SSTables in memory or deletes any files,
+/// so after a crash `Database::load` knows exactly which files are live: anything it
+/// doesn't list is an unfinished output or a leftover input and gets cleaned up.
+#[derive(Debug)]
+pub struct Manifest {
+    dir: String,
+    path: String,
+}
+
+impl Manifest {
+    pub fn new(data_dir: &str) -> Manifest {
+        Manifest {
+            dir: data_dir.to_string(),
+            path: format!("{}/{}", data_dir, MANIFEST_FILE_NAME),
+        }
+    }
+
+    pub fn path(&self) -> String {
+        self.path.clone()
+    }
+
+    /// Data dirs from before the manifest existed don't have one.
+    pub fn exists(&self) -> bool {
+        Path::new(&self.path).exists()
+    }
+
+    /// Reads the file names of the live SSTables, oldest first.
+    pub fn read(&self) -> Result<Vec<String>> {
+        let bytes = std::fs::read(&self.path)?;
+        decode_manifest(&bytes).map_err(|reason| Error::corruption(&self.path, 0, reason))
+    }
+
+    /// Replaces the manifest with one listing these SSTable file names, oldest first. It's
+    /// written to a temp file that is renamed into place, so a crash leaves either the old
+    /// list or the new one.
+    pub fn write(&self, sstables: &[String]) -> Result<()> {
+        let mut buf = MANIFEST_MAGIC.to_vec();
+        buf.extend_from_slice(&(sstables.len() as u32).to_le_bytes());
+        for name in sstables {
+            buf.extend_from_slice(&(name.len() as u32).to_le_bytes());
+            buf.extend_from_slice(name.as_bytes());
+        }
+        let checksum = crc32fast::hash(&buf);
+        buf.extend_from_slice(&checksum.to_le_bytes());
+
+        let tmp_path = format!("{}.tmp", self.path);
+        let mut file = std::fs::File::create(&tmp_path)?;
+        file.write_all(&buf)?;
+        file.sync_all()?;
+        std::fs::rename(&tmp_path, &self.path)?;
+        // the rename itself only survives a crash once the directory is synced
+        std::fs::File::open(&self.dir)?.sync_all()?;
+        Ok(())
+    }
+
+    /// Deletes SSTable files that aren't in `live`, plus any temp files a crash left behind.
+    pub fn remove_orphans(&self, live: &[String]) -> Result<()> {
+        for entry in std::fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let file_name = entry.file_name();
+            let file_name = file_name.to_str().unwrap_or("");
+            let is_temp_file = file_name.ends_with(".tmp");
+            let orphan = if file_name.starts_with("sstable") {
+                // temp files are named after their table, sstable_..._uuid.tmp
+                let table_name = file_name.split('.').next().unwrap();
+                is_temp_file || !live.iter().any(|name| name == table_name)
+            } else {
+                is_temp_file && file_name.starts_with(MANIFEST_FILE_NAME)
+            };
+            if orphan {
+                info!("Removing orphaned file {}", file_name);
+                std::fs::remove_file(entry.path())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn decode_manifest(bytes: &[u8]) -> std::result::Result<Vec<String>, String> {
+    if bytes.len() < 12 || &bytes[0..4] != MANIFEST_MAGIC {
+        return Err("bad manifest header".to_string());
+    }
+    let (body, checksum) = bytes.split_at(bytes.len() - 4);
+    if crc32fast::hash(body) != u32::from_le_bytes(checksum.try_into().unwrap()) {
+        return Err("manifest checksum mismatch".to_string());
+    }
+
+    let count = u32::from_le_bytes(body[4..8].try_into().unwrap()) as usize;
+    let mut sstables = Vec::with_capacity(count);
+    let mut pos = 8;
+    for _ in 0..count {
+        if pos + 4 > body.len() {
+            return Err("truncated manifest entry".to_string());
+        }
+        let name_length = u32::from_le_bytes(body[pos..pos + 4].try_into().unwrap()) as usize;
+        pos += 4;
+        if pos + name_length > body.len() {
+            return Err("truncated manifest entry".to_string());
+        }
+        let name = std::str::from_utf8(&body[pos..pos + name_length])
+            .map_err(|_| "SSTable name is not valid UTF-8".to_string())?;
+        sstables.push(name.to_string());
+        pos += name_length;
+    }
+    if pos != body.len() {
+        return Err("trailing bytes after the last entry".to_string());
+    }
+
+    Ok(sstables)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manifest_round_trips_and_detects_corruption() {
+        let dir = std::env::temp_dir().join(format!("kassantra_manifest_{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let manifest = Manifest::new(dir.to_str().unwrap());
+        assert!(!manifest.exists());
+
+        let sstables = vec!["sstable_1_a".to_string(), "sstable_2_b".to_string()];
+        manifest.write(&sstables).unwrap();
+        assert!(manifest.exists());
+        assert_eq!(manifest.read().unwrap(), sstables);
+
+        manifest.write(&[]).unwrap();
+        assert_eq!(manifest.read().unwrap(), Vec::<String>::new());
+
+        let mut bytes = std::fs::read(manifest.path()).unwrap();
+        bytes[5] ^= 1;
+        std::fs::write(manifest.path(), bytes).unwrap();
+        assert!(matches!(manifest.read(), Err(Error::Corruption { .. })));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
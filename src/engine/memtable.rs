@@ -1,24 +1,46 @@
-use super::operation::Operation;
-use super::wal::Wal;
+use super::operation::{Cell, Operation};
+use super::wal::{Wal, WalSegment};
+use crate::error::Result;
 use std::collections::BTreeMap;
-use std::io::Result;
+use std::ops::RangeBounds;
+use std::sync::Arc;
+use tokio::sync::OwnedSemaphorePermit;
+use uuid::Uuid;
 
 pub struct MemTable {
-    store: BTreeMap<String, Operation>,
+    store: BTreeMap<String, Cell>,
+    // the table whose writes it holds, which its WAL records are tagged with
+    table_id: Uuid,
     flush_threshold_bytes: usize,
     size_bytes: i64,
 }
 
+impl Default for MemTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl MemTable {
     pub fn new() -> MemTable {
+        MemTable::with_flush_threshold(1024)
+    }
+
+    pub fn with_flush_threshold(flush_threshold_bytes: usize) -> MemTable {
+        MemTable::for_table(Uuid::nil(), flush_threshold_bytes)
+    }
+
+    /// A MemTable for the table with the given id, for when several tables share a WAL.
+    pub fn for_table(table_id: Uuid, flush_threshold_bytes: usize) -> MemTable {
         MemTable {
             store: BTreeMap::new(),
-            flush_threshold_bytes: 1024,
+            table_id,
+            flush_threshold_bytes,
             size_bytes: 0,
         }
     }
 
-    pub fn get(&self, key: &str) -> Option<&Operation> {
+    pub fn get(&self, key: &str) -> Option<&Cell> {
         self.store.get(key)
     }
 
@@ -26,37 +48,56 @@ impl MemTable {
         self.size_bytes >= self.flush_threshold_bytes as i64
     }
 
-    pub fn delete(&mut self, key: &String, wal: &mut Wal) {
+    pub fn delete(&mut self, key: &str, timestamp: u64, wal: &mut Wal) -> Result<()> {
         // Log the delete operation first
-        let log_entry = format!("DELETE\t{}", key);
-        wal.append(&log_entry).expect("Failed to write to WAL");
-        let (existing_key_bytes, existing_value_bytes) = match self.store.get(key) {
-            Some(Operation::Insert(value)) => (key.len(), value.len()),
-            Some(Operation::Delete) => (key.len(), 0),
-            _ => (0, 0),
-        };
-
-        let byte_diff = key.len() as i64 - (existing_key_bytes + existing_value_bytes) as i64;
-        self.store.insert(key.clone(), Operation::Delete);
-        self.size_bytes += byte_diff;
+        let cell = Cell::new(Operation::tombstone(), timestamp);
+        wal.append(self.table_id, key, &cell)?;
+        self.apply(key.to_string(), cell);
+        Ok(())
     }
 
     /// Write data to the MemTable and log it to the Write-Ahead Log.
-    pub fn set(&mut self, key: String, value: String, wal: &mut Wal) {
-        // Log the write operation first
-        let log_entry = format!("INSERT\t{}\t{}", key, value);
-        wal.append(&log_entry).expect("Failed to write to WAL");
-
-        let (existing_key_bytes, existing_value_bytes) = match self.store.get(&key) {
-            Some(Operation::Insert(existing_value)) => (key.len(), existing_value.len()),
-            Some(Operation::Delete) => (key.len(), 0),
-            None => (0, 0),
-        };
+    pub fn set(&mut self, key: String, value: String, timestamp: u64, wal: &mut Wal) -> Result<()> {
+        self.write(key, Cell::new(Operation::Insert(value), timestamp), wal)
+    }
+
+    /// Like `set`, but the value expires after `ttl` seconds.
+    pub fn set_with_ttl(
+        &mut self,
+        key: String,
+        value: String,
+        ttl: u32,
+        timestamp: u64,
+        wal: &mut Wal,
+    ) -> Result<()> {
+        self.write(
+            key,
+            Cell::new(Operation::expiring(value, ttl), timestamp),
+            wal,
+        )
+    }
+
+    /// Logs a cell to the WAL and applies it.
+    pub fn write(&mut self, key: String, cell: Cell, wal: &mut Wal) -> Result<()> {
+        // Log the write operation first, a write that didn't make it to the WAL must not be applied
+        wal.append(self.table_id, &key, &cell)?;
 
         // Now insert the data into the MemTable
-        let byte_diff = key.len() as i64 + value.len() as i64
-            - (existing_key_bytes + existing_value_bytes) as i64;
-        self.store.insert(key, Operation::Insert(value));
+        self.apply(key, cell);
+        Ok(())
+    }
+
+    // Insert a cell into the store unless it has a newer one for the key, keeping track of
+    // its size
+    fn apply(&mut self, key: String, cell: Cell) {
+        let existing_bytes = match self.store.get(&key) {
+            Some(existing) if *existing > cell => return,
+            Some(existing) => key.len() + existing.size_bytes(),
+            None => 0,
+        };
+
+        let byte_diff = (key.len() + cell.size_bytes()) as i64 - existing_bytes as i64;
+        self.store.insert(key, cell);
         self.size_bytes += byte_diff;
     }
 
@@ -64,37 +105,58 @@ impl MemTable {
         self.store.is_empty()
     }
 
-    pub fn replay_wal(&mut self, wal: &mut Wal) {
-        // get an iterator to WAL lines so we dont have to read the whole file into memory
-        let wal_iterator = wal.get_line_iterator();
-
-        for line in wal_iterator {
-            let line = line.unwrap();
-            let parts = line.split("\t").collect::<Vec<&str>>();
-            let operation = parts.get(0);
-            let key = parts.get(1);
-            let value = parts.get(2);
-            match (operation, key, value) {
-                (Some(&"INSERT"), Some(key), Some(value)) => {
-                    self.store
-                        .insert(key.to_string(), Operation::Insert(value.to_string()));
-                }
-                (Some(&"DELETE"), Some(key), None) => {
-                    self.store.insert(key.to_string(), Operation::Delete);
-                }
-                _ => panic!("Unknown operation"),
-            }
+    /// Applies the WAL's records of this MemTable's table, skipping other tables' records.
+    pub fn replay_wal(&mut self, wal: &mut Wal) -> Result<()> {
+        // records are streamed from the WAL so we dont have to read the whole file into memory
+        wal.replay(|table_id, key, cell| self.replay_record(table_id, key, cell))
+    }
+
+    pub fn replay_wal_segment(&mut self, segment: &mut WalSegment) -> Result<()> {
+        segment.replay(|table_id, key, cell| self.replay_record(table_id, key, cell))
+    }
+
+    fn replay_record(&mut self, table_id: Uuid, key: String, cell: Cell) {
+        if table_id == self.table_id {
+            self.apply(key, cell);
         }
     }
 
-    pub fn clear(&mut self, wal: &mut Wal) -> Result<()> {
+    pub fn clear(&mut self) {
         self.store.clear();
         self.size_bytes = 0;
-        wal.clear()
+    }
+
+    /// Iterates over the cells whose keys fall in `range`, in key order.
+    pub fn range<R: RangeBounds<String>>(
+        &self,
+        range: R,
+    ) -> std::collections::btree_map::Range<'_, String, Cell> {
+        self.store.range(range)
     }
 
     // return an immutable iterator over the memtable
-    pub fn iter(&self) -> std::collections::btree_map::Iter<String, Operation> {
+    pub fn iter(&self) -> std::collections::btree_map::Iter<'_, String, Cell> {
         self.store.iter()
     }
 }
+
+/// A full MemTable that was swapped out for an empty one and is waiting to be flushed.
+/// Reads still see it until its SSTable takes its place.
+pub struct ImmutableMemTable {
+    pub memtable: Arc<MemTable>,
+    /// The WAL segment started when the MemTable was swapped out. The segments before it
+    /// aren't needed once the MemTable is flushed.
+    pub wal_segment: u64,
+    // frees up a spot for the next immutable MemTable once this one is flushed
+    _permit: OwnedSemaphorePermit,
+}
+
+impl ImmutableMemTable {
+    pub fn new(memtable: MemTable, wal_segment: u64, permit: OwnedSemaphorePermit) -> Self {
+        ImmutableMemTable {
+            memtable: Arc::new(memtable),
+            wal_segment,
+            _permit: permit,
+        }
+    }
+}
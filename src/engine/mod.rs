@@ -1,4 +1,9 @@
+pub mod bloom;
+pub mod compaction;
+pub mod compression;
+pub mod manifest;
 pub mod memtable;
+pub mod operation;
+pub mod schema;
 pub mod sstable;
 pub mod wal;
-pub mod operation;
\ No newline at end of file
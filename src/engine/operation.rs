@@ -1,16 +1,28 @@
 use std::fmt::Display;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
 
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum Operation {
     Insert(String),
-    Delete,
+    /// A value that expires `ttl` seconds after it was written, at `expires_at` seconds
+    /// since the epoch. After that it reads like a tombstone.
+    Expiring {
+        value: String,
+        ttl: u32,
+        expires_at: u64,
+    },
+    /// A tombstone, with the time of the delete in seconds since the epoch so compaction
+    /// can tell when it's safe to drop.
+    Delete(u64),
 }
 
 impl Display for Operation {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Operation::Insert(value) => write!(f, "INSERT\t{}", value),
-            Operation::Delete => write!(f, "DELETE"),
+            Operation::Expiring { value, ttl, .. } => write!(f, "INSERT\t{}\tTTL {}", value, ttl),
+            Operation::Delete(_) => write!(f, "DELETE"),
         }
     }
 }
@@ -20,7 +32,104 @@ impl Operation {
     pub fn size_bytes(&self) -> usize {
         match self {
             Operation::Insert(value) => value.len(),
-            Operation::Delete => 0,
+            Operation::Expiring { value, .. } => value.len() + 12,
+            Operation::Delete(_) => 8,
         }
     }
-}
\ No newline at end of file
+
+    /// A tombstone for a delete happening now.
+    pub fn tombstone() -> Operation {
+        Operation::Delete(now_seconds())
+    }
+
+    /// A value written now that expires after `ttl` seconds. A TTL of 0 means it never
+    /// does, like in Cassandra.
+    pub fn expiring(value: String, ttl: u32) -> Operation {
+        match ttl {
+            0 => Operation::Insert(value),
+            ttl => Operation::Expiring {
+                value,
+                ttl,
+                expires_at: now_seconds().saturating_add(ttl as u64),
+            },
+        }
+    }
+
+    /// The value as of `now` (seconds since the epoch), or `None` if it's been deleted or
+    /// has expired.
+    pub fn live_value(&self, now: u64) -> Option<&String> {
+        match self {
+            Operation::Insert(value) => Some(value),
+            Operation::Expiring {
+                value, expires_at, ..
+            } if now < *expires_at => Some(value),
+            Operation::Expiring { .. } | Operation::Delete(_) => None,
+        }
+    }
+
+    /// Turns a value that has expired by `now` into a tombstone. Like in Cassandra the
+    /// tombstone counts as deleted when the value was written, since the value already
+    /// made it everywhere it was going to.
+    pub fn expire(self, now: u64) -> Operation {
+        match self {
+            Operation::Expiring {
+                ttl, expires_at, ..
+            } if now >= expires_at => Operation::Delete(expires_at.saturating_sub(ttl as u64)),
+            operation => operation,
+        }
+    }
+}
+
+fn now_seconds() -> u64 {
+    SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// An operation on a key along with its write timestamp, in microseconds since the epoch.
+/// When a key was written more than once, the cell with the highest timestamp wins, no
+/// matter which MemTable or SSTable it's in. Cells compare by timestamp first and then by
+/// operation, so like in Cassandra a tie goes to the tombstone and then to the bigger value.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Cell {
+    pub timestamp: u64,
+    pub operation: Operation,
+}
+
+impl Cell {
+    pub fn new(operation: Operation, timestamp: u64) -> Cell {
+        Cell {
+            timestamp,
+            operation,
+        }
+    }
+
+    pub fn size_bytes(&self) -> usize {
+        8 + self.operation.size_bytes()
+    }
+}
+
+static LAST_TIMESTAMP: AtomicU64 = AtomicU64::new(0);
+
+/// A write timestamp for now, in microseconds since the epoch. Never hands out the same
+/// one twice, so of two writes to the same key made here the later one always wins.
+pub fn next_timestamp() -> u64 {
+    next_timestamp_after(0)
+}
+
+/// Like `next_timestamp`, but above `floor` even when the clock is behind it, as are the
+/// timestamps handed out after it.
+pub(crate) fn next_timestamp_after(floor: u64) -> u64 {
+    let now = SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_micros() as u64;
+    let next = |last: u64| now.max(last + 1).max(floor + 1);
+    let last = LAST_TIMESTAMP
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |last| {
+            Some(next(last))
+        })
+        .unwrap();
+    next(last)
+}
@@ -0,0 +1,284 @@
+use std::io::Write;
+use std::path::Path;
+
+use uuid::Uuid;
+
+use crate::error::{Error, Result};
+use crate::types::ColumnType;
+
+// Schema layout: magic, table count, then per table (keyspace length, keyspace, name
+// length, name, id, whether it has columns). A table with columns follows that with the
+// column count, (name length, name, type) per column, the count and column indexes of the
+// partition key, and the count and (column index, order) of the clustering columns. A
+// crc32 of everything before it comes last.
+const SCHEMA_MAGIC: &[u8; 4] = b"KSC3";
+pub const SCHEMA_FILE_NAME: &str = "SCHEMA";
+
+/// A table as the schema file records it. The id is what the table's records in the shared
+/// WAL are tagged with, so a table that is dropped and created again gets a new one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TableDefinition {
+    pub keyspace: String,
+    pub name: String,
+    pub id: Uuid,
+    /// The table's columns, or `None` for a table of plain key-value pairs.
+    pub schema: Option<TableSchema>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColumnDefinition {
+    pub name: String,
+    pub column_type: ColumnType,
+}
+
+/// Which way a clustering column sorts the rows of a partition.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClusteringOrder {
+    Asc,
+    Desc,
+}
+
+impl ClusteringOrder {
+    pub fn id(self) -> u8 {
+        match self {
+            ClusteringOrder::Asc => 0,
+            ClusteringOrder::Desc => 1,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<ClusteringOrder> {
+        match id {
+            0 => Some(ClusteringOrder::Asc),
+            1 => Some(ClusteringOrder::Desc),
+            _ => None,
+        }
+    }
+
+    pub fn reverse(self) -> ClusteringOrder {
+        match self {
+            ClusteringOrder::Asc => ClusteringOrder::Desc,
+            ClusteringOrder::Desc => ClusteringOrder::Asc,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClusteringColumn {
+    pub name: String,
+    pub order: ClusteringOrder,
+}
+
+/// The columns of a table, in the order they were declared, and its primary key: the
+/// partition key columns, which decide what partition a row is in, followed by the
+/// clustering columns, which sort the rows of a partition. Every other column can be left
+/// out of a row.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TableSchema {
+    pub columns: Vec<ColumnDefinition>,
+    pub partition_key: Vec<String>,
+    pub clustering_key: Vec<ClusteringColumn>,
+}
+
+impl TableSchema {
+    pub fn column(&self, name: &str) -> Option<&ColumnDefinition> {
+        self.columns.iter().find(|column| column.name == name)
+    }
+
+    /// The names of the primary key columns, the partition key first.
+    pub fn primary_key(&self) -> impl Iterator<Item = &str> {
+        self.partition_key.iter().map(String::as_str).chain(
+            self.clustering_key
+                .iter()
+                .map(|column| column.name.as_str()),
+        )
+    }
+
+    pub fn is_primary_key(&self, name: &str) -> bool {
+        self.primary_key().any(|column| column == name)
+    }
+
+    fn column_index(&self, name: &str) -> u32 {
+        self.columns
+            .iter()
+            .position(|column| column.name == name)
+            .unwrap() as u32
+    }
+}
+
+/// The tables in a data dir. Creating or dropping a table writes out the new list before
+/// anything else happens to the table's files, like `Manifest` does for SSTables.
+#[derive(Debug)]
+pub struct SchemaFile {
+    dir: String,
+    path: String,
+}
+
+impl SchemaFile {
+    pub fn new(data_dir: &str) -> SchemaFile {
+        SchemaFile {
+            dir: data_dir.to_string(),
+            path: format!("{}/{}", data_dir, SCHEMA_FILE_NAME),
+        }
+    }
+
+    pub fn path(&self) -> String {
+        self.path.clone()
+    }
+
+    /// Data dirs from before tables existed don't have one.
+    pub fn exists(&self) -> bool {
+        Path::new(&self.path).exists()
+    }
+
+    pub fn read(&self) -> Result<Vec<TableDefinition>> {
+        let bytes = std::fs::read(&self.path)?;
+        decode_schema(&bytes).map_err(|reason| Error::corruption(&self.path, 0, reason))
+    }
+
+    /// Replaces the schema with one listing these tables. Written to a temp file that is
+    /// renamed into place, so a crash leaves either the old list or the new one.
+    pub fn write(&self, tables: &[TableDefinition]) -> Result<()> {
+        let mut buf = SCHEMA_MAGIC.to_vec();
+        buf.extend_from_slice(&(tables.len() as u32).to_le_bytes());
+        for table in tables {
+            for name in [&table.keyspace, &table.name] {
+                buf.extend_from_slice(&(name.len() as u32).to_le_bytes());
+                buf.extend_from_slice(name.as_bytes());
+            }
+            buf.extend_from_slice(table.id.as_bytes());
+            let Some(schema) = &table.schema else {
+                buf.push(0);
+                continue;
+            };
+            buf.push(1);
+            buf.extend_from_slice(&(schema.columns.len() as u32).to_le_bytes());
+            for column in schema.columns.iter() {
+                buf.extend_from_slice(&(column.name.len() as u32).to_le_bytes());
+                buf.extend_from_slice(column.name.as_bytes());
+                buf.push(column.column_type.id());
+            }
+            buf.extend_from_slice(&(schema.partition_key.len() as u32).to_le_bytes());
+            for name in schema.partition_key.iter() {
+                buf.extend_from_slice(&schema.column_index(name).to_le_bytes());
+            }
+            buf.extend_from_slice(&(schema.clustering_key.len() as u32).to_le_bytes());
+            for column in schema.clustering_key.iter() {
+                buf.extend_from_slice(&schema.column_index(&column.name).to_le_bytes());
+                buf.push(column.order.id());
+            }
+        }
+        let checksum = crc32fast::hash(&buf);
+        buf.extend_from_slice(&checksum.to_le_bytes());
+
+        let tmp_path = format!("{}.tmp", self.path);
+        let mut file = std::fs::File::create(&tmp_path)?;
+        file.write_all(&buf)?;
+        file.sync_all()?;
+        std::fs::rename(&tmp_path, &self.path)?;
+        // the rename itself only survives a crash once the directory is synced
+        std::fs::File::open(&self.dir)?.sync_all()?;
+        Ok(())
+    }
+}
+
+fn decode_schema(bytes: &[u8]) -> std::result::Result<Vec<TableDefinition>, String> {
+    if bytes.len() < 12 || &bytes[0..4] != SCHEMA_MAGIC {
+        return Err("bad schema header".to_string());
+    }
+    let (body, checksum) = bytes.split_at(bytes.len() - 4);
+    if crc32fast::hash(body) != u32::from_le_bytes(checksum.try_into().unwrap()) {
+        return Err("schema checksum mismatch".to_string());
+    }
+
+    let mut reader = Reader { body, pos: 4 };
+    let count = reader.u32()?;
+    let mut tables = Vec::with_capacity(count);
+    for _ in 0..count {
+        let keyspace = reader.name()?;
+        let name = reader.name()?;
+        let id = Uuid::from_slice(reader.bytes(16)?).unwrap();
+        let schema = match reader.byte()? == 1 {
+            true => Some(decode_table_schema(&mut reader)?),
+            false => None,
+        };
+        tables.push(TableDefinition {
+            keyspace,
+            name,
+            id,
+            schema,
+        });
+    }
+    if reader.pos != body.len() {
+        return Err("trailing bytes after the last entry".to_string());
+    }
+
+    Ok(tables)
+}
+
+fn decode_table_schema(reader: &mut Reader) -> std::result::Result<TableSchema, String> {
+    let count = reader.u32()?;
+    let mut columns = Vec::with_capacity(count);
+    for _ in 0..count {
+        let name = reader.name()?;
+        let type_id = reader.byte()?;
+        let column_type = ColumnType::from_id(type_id)
+            .ok_or_else(|| format!("unknown column type {}", type_id))?;
+        columns.push(ColumnDefinition { name, column_type });
+    }
+    let column_name = |reader: &mut Reader| {
+        columns
+            .get(reader.u32()?)
+            .map(|column| column.name.clone())
+            .ok_or_else(|| "key column is not one of the columns".to_string())
+    };
+    let partition_key_count = reader.u32()?;
+    let partition_key = (0..partition_key_count)
+        .map(|_| column_name(reader))
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    let clustering_key_count = reader.u32()?;
+    let clustering_key = (0..clustering_key_count)
+        .map(|_| {
+            let name = column_name(reader)?;
+            let order = reader.byte()?;
+            let order = ClusteringOrder::from_id(order)
+                .ok_or_else(|| format!("unknown clustering order {}", order))?;
+            Ok(ClusteringColumn { name, order })
+        })
+        .collect::<std::result::Result<Vec<_>, String>>()?;
+    Ok(TableSchema {
+        columns,
+        partition_key,
+        clustering_key,
+    })
+}
+
+// Reads the fields of a schema entry one after another
+struct Reader<'a> {
+    body: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, length: usize) -> std::result::Result<&'a [u8], String> {
+        if self.pos + length > self.body.len() {
+            return Err("truncated schema entry".to_string());
+        }
+        self.pos += length;
+        Ok(&self.body[self.pos - length..self.pos])
+    }
+
+    fn byte(&mut self) -> std::result::Result<u8, String> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u32(&mut self) -> std::result::Result<usize, String> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()) as usize)
+    }
+
+    fn name(&mut self) -> std::result::Result<String, String> {
+        let length = self.u32()?;
+        let name = std::str::from_utf8(self.bytes(length)?)
+            .map_err(|_| "name is not valid UTF-8".to_string())?;
+        Ok(name.to_string())
+    }
+}
@@ -1,208 +1,862 @@
+use log::warn;
 use std::{
     collections::BTreeMap,
-    io::{ErrorKind, Result, SeekFrom},
+    io::{ErrorKind, SeekFrom},
+    sync::Arc,
+    time::Duration,
 };
 use tokio::{
     fs::{File, OpenOptions},
     io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+    sync::Mutex,
 };
 
-use super::operation::Operation;
+use super::bloom::BloomFilter;
+use super::compression::Compression;
+use super::operation::{Cell, Operation};
+use crate::error::{Error, Result};
+
+// SSTable files start with a magic number, the format version and the id of the compression
+// the data blocks use. v0 files, from before there was a header, start straight with the
+// first record.
+const SSTABLE_MAGIC: &[u8; 4] = b"KSST";
+const SSTABLE_HEADER_LENGTH: usize = 9;
+pub const SSTABLE_FORMAT_VERSION: u32 = 1;
+
+// v1 files end with the index, Bloom filter and stats sections and a fixed-size footer:
+// [index offset u64][bloom filter offset u64][stats offset u64][format version u32][crc32
+// of the sections and the footer up to here u32][magic]. A table without a Bloom filter has
+// an empty Bloom filter section.
+const FOOTER_LENGTH: usize = 36;
+
+// v1 records are grouped into data blocks: [stored length][uncompressed length][CRC32C of
+// the stored bytes][stored bytes]. A block is stored compressed unless that doesn't make it
+// any smaller, and records never span two blocks.
+const BLOCK_HEADER_LENGTH: usize = 12;
+// Same as Cassandra's default chunk_length_in_kb
+pub const DEFAULT_BLOCK_SIZE_BYTES: usize = 16 * 1024;
+
+// v1 records are [kind][write timestamp u64][key length][value length][key][value]. Deletes
+// store their deletion time as a u64 value, expiring inserts prefix theirs with [ttl
+// u32][expiration time u64].
+// v0 records have no kind byte or timestamp and mark deletes with the value "TOMBSTONE".
+// They all get the time the table was written as their timestamp.
+const RECORD_INSERT: u8 = 0;
+const RECORD_DELETE: u8 = 1;
+const RECORD_EXPIRING_INSERT: u8 = 2;
+const V0_TOMBSTONE: &str = "TOMBSTONE";
+
+// Index section layout: magic, entry count, then (key length, key, offset) per entry, then
+// the block count and (file offset, stored length, uncompressed length) per block.
+const INDEX_MAGIC: &[u8; 4] = b"KIDX";
+
+// Stats section layout: magic, entry count, tombstone count, creation time, max write time,
+// max deletion time, level, then whether the table has keys and if so the (length, key) of
+// its smallest and largest key, then the max cell timestamp.
+const STATS_MAGIC: &[u8; 4] = b"KSTA";
+
+/// What an SSTable holds, written into its stats section so it's known without reading
+/// the table. Times are in seconds since the Unix epoch.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SSTableStats {
+    pub entry_count: u64,
+    pub tombstone_count: u64,
+    /// The smallest and largest key, or `None` if the table is empty.
+    pub key_range: Option<(String, String)>,
+    pub created_at: u64,
+    /// When the newest entry was written.
+    pub max_write_time: u64,
+    /// When the last live entry stops being live: the newest deletion or expiration time
+    /// if every entry is a tombstone or expires, `u64::MAX` otherwise.
+    pub max_deletion_time: u64,
+    /// The compaction level. Flushed tables start at level 0.
+    pub level: u32,
+    /// The newest cell timestamp, in microseconds since the Unix epoch. A read that already
+    /// found a newer cell can skip the table.
+    pub max_timestamp: u64,
+}
+
+impl SSTableStats {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = STATS_MAGIC.to_vec();
+        buf.extend_from_slice(&self.entry_count.to_le_bytes());
+        buf.extend_from_slice(&self.tombstone_count.to_le_bytes());
+        buf.extend_from_slice(&self.created_at.to_le_bytes());
+        buf.extend_from_slice(&self.max_write_time.to_le_bytes());
+        buf.extend_from_slice(&self.max_deletion_time.to_le_bytes());
+        buf.extend_from_slice(&self.level.to_le_bytes());
+        encode_key_range(&mut buf, &self.key_range);
+        buf.extend_from_slice(&self.max_timestamp.to_le_bytes());
+        buf
+    }
+
+    /// Decodes stats written by `to_bytes`, or returns why the bytes aren't any.
+    pub fn from_bytes(bytes: &[u8]) -> std::result::Result<SSTableStats, String> {
+        if bytes.len() < 48 || &bytes[0..4] != STATS_MAGIC {
+            return Err("corrupt stats: bad header".to_string());
+        }
+        let u64_at = |pos: usize| u64::from_le_bytes(bytes[pos..pos + 8].try_into().unwrap());
+        let (key_range, key_range_length) = decode_key_range(&bytes[48..])
+            .map_err(|reason| format!("corrupt stats: {}", reason))?;
+        let max_timestamp = match &bytes[48 + key_range_length..] {
+            bytes if bytes.len() == 8 => u64::from_le_bytes(bytes.try_into().unwrap()),
+            _ => return Err("corrupt stats: bad length".to_string()),
+        };
+        Ok(SSTableStats {
+            entry_count: u64_at(4),
+            tombstone_count: u64_at(12),
+            created_at: u64_at(20),
+            max_write_time: u64_at(28),
+            max_deletion_time: u64_at(36),
+            level: u32::from_le_bytes(bytes[44..48].try_into().unwrap()),
+            key_range,
+            max_timestamp,
+        })
+    }
+}
+
+// Where a data block of a v1 table is. Offsets into a v1 table (in the sparse index, and
+// what `read_item_at` takes and returns) count the bytes of the uncompressed records, so
+// they don't change with the compression. `start` is the offset of the block's first record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct BlockHandle {
+    start: usize,
+    file_offset: u64,
+    stored_length: u32,
+    length: u32,
+}
 
 // derive Debug
 #[derive(Debug)]
 pub struct SSTable {
-    file: File,
+    // reads seek the shared handle, so they take turns. Only a table that hasn't been
+    // handed out yet is written to, through `&mut self`.
+    file: Mutex<File>,
     path: String,
     index: BTreeMap<String, u64>, // key -> offset
+    bloom_filter: Option<BloomFilter>,
+    format_version: u32,
+    compression: Compression,
+    data_start: usize,
+    end_offset: usize,
+    file_length: usize,
+    // the header and the data blocks, without the sections after them
+    data_length: usize,
+    blocks: Vec<BlockHandle>,
+    // records written since the last block went out
+    pending_block: Vec<u8>,
+    // reads tend to go through a block record by record, so keep the last one decompressed
+    cached_block: std::sync::Mutex<Option<(usize, Arc<Vec<u8>>)>>,
+    stats: SSTableStats,
+    // the write timestamp every record of a v0 table gets, see `legacy_timestamp`
+    legacy_timestamp: u64,
     pub index_every_n_entries: usize,
+    pub bloom_filter_fp_chance: f64,
+    pub block_size_bytes: usize,
 }
 
 impl SSTable {
     pub async fn new(path: &str) -> Result<Self> {
-        let f = OpenOptions::new()
+        SSTable::with_compression(path, Compression::default()).await
+    }
+
+    /// Creates an empty SSTable whose data blocks get compressed with `compression`.
+    pub async fn with_compression(path: &str, compression: Compression) -> Result<Self> {
+        let mut f = OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
+            .truncate(true)
             .open(path)
             .await?;
+
+        let mut header = SSTABLE_MAGIC.to_vec();
+        header.extend_from_slice(&SSTABLE_FORMAT_VERSION.to_le_bytes());
+        header.push(compression.id());
+        f.write_all(&header).await?;
+
         Ok(SSTable {
-            file: f,
+            file: Mutex::new(f),
             path: path.to_string(),
             index: BTreeMap::new(),
+            bloom_filter: None,
+            format_version: SSTABLE_FORMAT_VERSION,
+            compression,
+            data_start: 0,
+            end_offset: 0,
+            file_length: SSTABLE_HEADER_LENGTH,
+            data_length: SSTABLE_HEADER_LENGTH,
+            blocks: Vec::new(),
+            pending_block: Vec::new(),
+            cached_block: std::sync::Mutex::new(None),
+            stats: SSTableStats {
+                created_at: now(),
+                ..SSTableStats::default()
+            },
+            legacy_timestamp: 0,
             index_every_n_entries: 10,
+            bloom_filter_fp_chance: 0.01,
+            block_size_bytes: DEFAULT_BLOCK_SIZE_BYTES,
         })
     }
 
     pub async fn from_file(path: &str) -> Result<Self> {
-        let file = OpenOptions::new().read(true).write(true).open(path).await?;
+        let mut file = OpenOptions::new().read(true).write(true).open(path).await?;
+        let file_length = file.metadata().await?.len() as usize;
+
+        // files without the header are v0 files
+        let mut header = [0u8; SSTABLE_HEADER_LENGTH];
+        let has_header = file_length >= SSTABLE_HEADER_LENGTH
+            && file.read_exact(&mut header).await.is_ok()
+            && &header[0..4] == SSTABLE_MAGIC;
+        let (format_version, compression) = if has_header {
+            let version = u32::from_le_bytes(header[4..8].try_into().unwrap());
+            if version != SSTABLE_FORMAT_VERSION {
+                return Err(Error::corruption(
+                    path,
+                    4,
+                    format!("unsupported format version {}", version),
+                ));
+            }
+            let compression = Compression::from_id(header[8]).ok_or_else(|| {
+                Error::corruption(path, 8, format!("unknown compression id {}", header[8]))
+            })?;
+            (version, compression)
+        } else {
+            (0, Compression::None)
+        };
+
         let mut table = SSTable {
-            file,
+            file: Mutex::new(file),
             path: path.to_string(),
             index: BTreeMap::new(),
+            bloom_filter: None,
+            format_version,
+            compression,
+            data_start: 0,
+            // v1 offsets start from the first block and the blocks aren't known until the
+            // index is loaded, v0 tables store their records as they are
+            end_offset: if has_header { 0 } else { file_length },
+            file_length,
+            data_length: file_length,
+            blocks: Vec::new(),
+            pending_block: Vec::new(),
+            cached_block: std::sync::Mutex::new(None),
+            stats: SSTableStats::default(),
+            legacy_timestamp: 0,
             index_every_n_entries: 10,
+            bloom_filter_fp_chance: 0.01,
+            block_size_bytes: DEFAULT_BLOCK_SIZE_BYTES,
         };
 
-        table.load_index()?;
+        // v0 tables are nothing but their records, everything else comes from reading them
+        if format_version == 0 {
+            table.legacy_timestamp = table.modified_time()?.as_micros() as u64;
+            table.create_index().await?;
+            table.create_bloom_filter().await?;
+            table.create_stats().await?;
+            return Ok(table);
+        }
 
+        match table.load_sections().await {
+            Ok(()) => (),
+            Err(e @ Error::Corruption { .. }) => {
+                warn!(
+                    "Could not load sections of {} ({}), rebuilding them",
+                    path, e
+                );
+                table.rebuild_sections().await?;
+            }
+            Err(e) => return Err(e),
+        }
         Ok(table)
     }
 
+    // Reads the index, Bloom filter and stats sections of a v1 table, going by its footer
+    async fn load_sections(&mut self) -> Result<()> {
+        let path = self.path.clone();
+        let corrupt =
+            |offset: usize, reason: String| Error::corruption(&path, offset as u64, reason);
+        if self.file_length < SSTABLE_HEADER_LENGTH + FOOTER_LENGTH {
+            return Err(corrupt(0, "file is too short to have a footer".to_string()));
+        }
+        let footer_offset = self.file_length - FOOTER_LENGTH;
+        let mut footer = [0u8; FOOTER_LENGTH];
+        let file = self.file.get_mut();
+        file.seek(SeekFrom::Start(footer_offset as u64)).await?;
+        file.read_exact(&mut footer).await?;
+        if &footer[32..36] != SSTABLE_MAGIC {
+            return Err(corrupt(footer_offset, "bad footer magic".to_string()));
+        }
+        let offset_at = |pos: usize| u64::from_le_bytes(footer[pos..pos + 8].try_into().unwrap());
+        let (index_offset, bloom_filter_offset, stats_offset) = (
+            offset_at(0) as usize,
+            offset_at(8) as usize,
+            offset_at(16) as usize,
+        );
+        let version = u32::from_le_bytes(footer[24..28].try_into().unwrap());
+        if version != self.format_version {
+            return Err(corrupt(
+                footer_offset,
+                format!(
+                    "footer says version {}, header {}",
+                    version, self.format_version
+                ),
+            ));
+        }
+        if !(SSTABLE_HEADER_LENGTH <= index_offset
+            && index_offset <= bloom_filter_offset
+            && bloom_filter_offset <= stats_offset
+            && stats_offset <= footer_offset)
+        {
+            return Err(corrupt(
+                footer_offset,
+                "section offsets out of order".to_string(),
+            ));
+        }
+
+        let mut sections = vec![0; footer_offset - index_offset];
+        file.seek(SeekFrom::Start(index_offset as u64)).await?;
+        file.read_exact(&mut sections).await?;
+        let mut checksum = crc32fast::Hasher::new();
+        checksum.update(&sections);
+        checksum.update(&footer[..28]);
+        if checksum.finalize() != u32::from_le_bytes(footer[28..32].try_into().unwrap()) {
+            return Err(corrupt(
+                index_offset,
+                "section checksum mismatch".to_string(),
+            ));
+        }
+
+        let index_section = &sections[..bloom_filter_offset - index_offset];
+        let bloom_filter_section =
+            &sections[bloom_filter_offset - index_offset..stats_offset - index_offset];
+        let stats_section = &sections[stats_offset - index_offset..];
+        let (index, blocks) = decode_index(index_section, index_offset as u64)
+            .map_err(|reason| corrupt(index_offset, reason))?;
+        let bloom_filter = match bloom_filter_section.is_empty() {
+            true => None,
+            false => Some(
+                BloomFilter::from_bytes(bloom_filter_section)
+                    .map_err(|reason| corrupt(bloom_filter_offset, reason))?,
+            ),
+        };
+        let stats = SSTableStats::from_bytes(stats_section)
+            .map_err(|reason| corrupt(stats_offset, reason))?;
+
+        self.set_blocks(blocks);
+        self.data_length = index_offset;
+        self.index = index;
+        self.bloom_filter = bloom_filter;
+        self.stats = stats;
+        Ok(())
+    }
+
+    // Rebuilds the index, Bloom filter and stats of a v1 table whose sections are damaged
+    // by reading its data blocks, and writes them out in place of the damaged ones. The
+    // level and creation time are gone with them, so the table starts over at level 0 as
+    // if it was written when the file was last modified.
+    async fn rebuild_sections(&mut self) -> Result<()> {
+        self.load_blocks().await?;
+        let data_end = self.data_length;
+        // a footer that still has its magic knows where the data ends, blocks that stop
+        // short of that are damaged rather than followed by the sections
+        let mut footer = [0u8; FOOTER_LENGTH];
+        if self.file_length >= data_end + FOOTER_LENGTH {
+            let file = self.file.get_mut();
+            file.seek(SeekFrom::Start((self.file_length - FOOTER_LENGTH) as u64))
+                .await?;
+            file.read_exact(&mut footer).await?;
+            let index_offset = u64::from_le_bytes(footer[0..8].try_into().unwrap());
+            if &footer[32..36] == SSTABLE_MAGIC && index_offset > data_end as u64 {
+                return Err(Error::corruption(
+                    &self.path,
+                    data_end as u64,
+                    "data block doesn't check out",
+                ));
+            }
+        }
+
+        self.create_index().await?;
+        self.create_bloom_filter().await?;
+        self.create_stats().await?;
+        self.file.get_mut().set_len(data_end as u64).await?;
+        self.file_length = data_end;
+        self.finish().await
+    }
+
+    /// Writes out the last, partly filled block, then the index, Bloom filter and stats
+    /// sections and the footer, and fsyncs the file. Nothing can be added after this.
+    pub async fn finish(&mut self) -> Result<()> {
+        self.write_block().await?;
+
+        let index_offset = self.file_length as u64;
+        let mut buf = self.encode_index();
+        let bloom_filter_offset = index_offset + buf.len() as u64;
+        if let Some(bloom_filter) = &self.bloom_filter {
+            buf.extend_from_slice(&bloom_filter.to_bytes());
+        }
+        let stats_offset = index_offset + buf.len() as u64;
+        buf.extend_from_slice(&self.stats.to_bytes());
+
+        buf.extend_from_slice(&index_offset.to_le_bytes());
+        buf.extend_from_slice(&bloom_filter_offset.to_le_bytes());
+        buf.extend_from_slice(&stats_offset.to_le_bytes());
+        buf.extend_from_slice(&self.format_version.to_le_bytes());
+        let checksum = crc32fast::hash(&buf);
+        buf.extend_from_slice(&checksum.to_le_bytes());
+        buf.extend_from_slice(SSTABLE_MAGIC);
+
+        let file = self.file.get_mut();
+        file.seek(SeekFrom::Start(self.file_length as u64)).await?;
+        file.write_all(&buf).await?;
+        self.file_length += buf.len();
+        Ok(file.sync_all().await?)
+    }
+
     pub fn get_path(&self) -> String {
         self.path.clone()
     }
 
-    pub fn load_index(&mut self) -> Result<()> {
+    /// The name the manifest knows this table by, i.e. the path without the data dir.
+    pub fn file_name(&self) -> String {
+        std::path::Path::new(&self.path)
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or(&self.path)
+            .to_string()
+    }
+
+    pub fn format_version(&self) -> u32 {
+        self.format_version
+    }
+
+    /// How the data blocks are compressed. v0 tables aren't.
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+
+    /// Offset of the first record, i.e. where reads over the whole table start.
+    pub fn data_start(&self) -> usize {
+        self.data_start
+    }
+
+    /// Size of the data in bytes, header included but not the index, Bloom filter and
+    /// stats sections. Records that haven't gone out in a block yet count at their
+    /// uncompressed size.
+    pub fn size_bytes(&self) -> u64 {
+        (self.data_length + self.pending_block.len()) as u64
+    }
+
+    /// Entry and tombstone counts, key range, times and level of the table.
+    pub fn stats(&self) -> &SSTableStats {
+        &self.stats
+    }
+
+    fn encode_index(&self) -> Vec<u8> {
+        let mut buf = vec![];
+        buf.extend_from_slice(INDEX_MAGIC);
+        buf.extend_from_slice(&(self.index.len() as u32).to_le_bytes());
+        for (key, offset) in self.index.iter() {
+            buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+            buf.extend_from_slice(key.as_bytes());
+            buf.extend_from_slice(&offset.to_le_bytes());
+        }
+        buf.extend_from_slice(&(self.blocks.len() as u32).to_le_bytes());
+        for block in self.blocks.iter() {
+            buf.extend_from_slice(&block.file_offset.to_le_bytes());
+            buf.extend_from_slice(&block.stored_length.to_le_bytes());
+            buf.extend_from_slice(&block.length.to_le_bytes());
+        }
+        buf
+    }
+
+    fn set_blocks(&mut self, blocks: Vec<BlockHandle>) {
+        self.end_offset = blocks
+            .last()
+            .map_or(0, |block| block.start + block.length as usize);
+        self.blocks = blocks;
+    }
+
+    // Find the data blocks of a v1 table by following the block headers through the file.
+    // They end before the first block that runs past the end of the file or fails its
+    // checksum, which is where the sections start.
+    async fn load_blocks(&mut self) -> Result<()> {
+        let mut blocks = vec![];
+        let mut start = 0;
+        let mut file_offset = SSTABLE_HEADER_LENGTH as u64;
+        let file = self.file.get_mut();
+        loop {
+            let mut header = [0u8; BLOCK_HEADER_LENGTH];
+            file.seek(SeekFrom::Start(file_offset)).await?;
+            match file.read_exact(&mut header).await {
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+                result => result?,
+            };
+            let stored_length = u32::from_le_bytes(header[0..4].try_into().unwrap());
+            let length = u32::from_le_bytes(header[4..8].try_into().unwrap());
+            let block_end = file_offset + (BLOCK_HEADER_LENGTH as u64) + stored_length as u64;
+            if block_end > self.file_length as u64 {
+                break;
+            }
+            let mut stored = vec![0; stored_length as usize];
+            file.read_exact(&mut stored).await?;
+            if crc32c::crc32c(&stored) != u32::from_le_bytes(header[8..12].try_into().unwrap()) {
+                break;
+            }
+            blocks.push(BlockHandle {
+                start,
+                file_offset,
+                stored_length,
+                length,
+            });
+            start += length as usize;
+            file_offset = block_end;
+        }
+        self.data_length = file_offset as usize;
+        self.set_blocks(blocks);
         Ok(())
     }
 
-    pub fn write_index(&mut self) -> Result<()> {
+    // Reads, checks and decompresses a data block, or takes it from the cache if it was the
+    // last one read
+    async fn read_block(&self, block_index: usize) -> Result<Arc<Vec<u8>>> {
+        if let Some((cached_index, block)) = &*self.cached_block.lock().unwrap() {
+            if *cached_index == block_index {
+                return Ok(block.clone());
+            }
+        }
+
+        let handle = self.blocks[block_index];
+        let corrupt = |reason: String| Error::corruption(&self.path, handle.file_offset, reason);
+        let mut bytes = vec![0; BLOCK_HEADER_LENGTH + handle.stored_length as usize];
+        let mut file = self.file.lock().await;
+        file.seek(SeekFrom::Start(handle.file_offset)).await?;
+        match file.read_exact(&mut bytes).await {
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                return Err(corrupt("block runs past the end of the file".to_string()))
+            }
+            result => result?,
+        };
+        drop(file);
+
+        let stored_length = u32::from_le_bytes(bytes[0..4].try_into().unwrap());
+        let length = u32::from_le_bytes(bytes[4..8].try_into().unwrap());
+        if stored_length != handle.stored_length || length != handle.length {
+            return Err(corrupt("block header doesn't match the index".to_string()));
+        }
+        let stored = &bytes[BLOCK_HEADER_LENGTH..];
+        if crc32c::crc32c(stored) != u32::from_le_bytes(bytes[8..12].try_into().unwrap()) {
+            return Err(corrupt("block checksum mismatch".to_string()));
+        }
+        let block = if stored_length == length {
+            stored.to_vec()
+        } else {
+            self.compression
+                .decompress(stored, length as usize)
+                .map_err(corrupt)?
+        };
+
+        let block = Arc::new(block);
+        *self.cached_block.lock().unwrap() = Some((block_index, block.clone()));
+        Ok(block)
+    }
+
+    pub fn set_bloom_filter(&mut self, bloom_filter: BloomFilter) {
+        self.bloom_filter = Some(bloom_filter);
+    }
+
+    // Create the Bloom filter by scanning every key in the SSTable
+    pub async fn create_bloom_filter(&mut self) -> Result<()> {
+        let operations = self.read_all().await?;
+        let mut bloom_filter = BloomFilter::new(operations.len(), self.bloom_filter_fp_chance);
+        for (key, _) in operations.iter() {
+            bloom_filter.insert(key);
+        }
+        self.bloom_filter = Some(bloom_filter);
+        Ok(())
+    }
+
+    /// Returns false if the Bloom filter rules the key out. Tables without a filter
+    /// always have to be read.
+    pub fn may_contain(&self, key: &str) -> bool {
+        match &self.bloom_filter {
+            Some(bloom_filter) => bloom_filter.may_contain(key),
+            None => true,
+        }
+    }
+
+    /// The compaction level the table belongs to. Flushed tables start at level 0.
+    pub fn level(&self) -> u32 {
+        self.stats.level
+    }
+
+    pub fn set_level(&mut self, level: u32) {
+        self.stats.level = level;
+    }
+
+    /// The smallest and largest key in the table, or `None` if it's empty.
+    pub fn key_range(&self) -> Option<(&str, &str)> {
+        self.stats
+            .key_range
+            .as_ref()
+            .map(|(min, max)| (min.as_str(), max.as_str()))
+    }
+
+    /// When the newest entry in the table was written, in seconds since the Unix epoch.
+    pub fn max_write_time(&self) -> u64 {
+        self.stats.max_write_time
+    }
+
+    pub fn set_max_write_time(&mut self, max_write_time: u64) {
+        self.stats.max_write_time = max_write_time;
+    }
+
+    /// When the last live entry in the table stops being live, in seconds since the Unix
+    /// epoch: the newest deletion or expiration time if every entry is a tombstone or
+    /// expires, `u64::MAX` otherwise.
+    pub fn max_deletion_time(&self) -> u64 {
+        self.stats.max_deletion_time
+    }
+
+    /// Returns false if the key is outside the table's key range.
+    pub fn covers_key(&self, key: &str) -> bool {
+        match self.key_range() {
+            Some((min, max)) => min <= key && key <= max,
+            None => false,
+        }
+    }
+
+    // Redo the stats by reading the whole table. Tables with damaged sections and v0 tables
+    // don't have a record of when they were written, so the file's modification time has
+    // to do, and they start over at level 0.
+    async fn create_stats(&mut self) -> Result<()> {
+        let written_at = self.modified_time()?.as_secs();
+        self.stats = SSTableStats {
+            created_at: written_at,
+            max_write_time: written_at,
+            ..SSTableStats::default()
+        };
+        for (key, cell) in self.read_all().await? {
+            self.track_entry(&key, &cell);
+        }
         Ok(())
     }
 
+    fn modified_time(&self) -> Result<Duration> {
+        Ok(std::fs::metadata(&self.path)?
+            .modified()?
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default())
+    }
+
+    /// Removes the data file from disk.
+    pub fn remove_files(&self) -> Result<()> {
+        Ok(std::fs::remove_file(&self.path)?)
+    }
+
+    /// Moves the data file into `dir`, keeping its name.
+    pub fn move_files(&self, dir: &str) -> Result<()> {
+        std::fs::create_dir_all(dir)?;
+        let new_path = std::path::Path::new(dir).join(self.file_name());
+        Ok(std::fs::rename(&self.path, new_path)?)
+    }
+
     pub fn write_to_index(&mut self, key: String, offset: u64) {
         self.index.insert(key, offset);
     }
 
-    // Create the index after writing entries to the SSTable
+    // Create the index by scanning the SSTable, sampling every n-th entry
     pub async fn create_index(&mut self) -> Result<()> {
-        let mut offset = 0u64;
-        let mut buffer = [0; 4]; // To read the u32 lengths of key and value
-
-        // Make sure to start from the beginning of the file
-        self.file.seek(SeekFrom::Start(0)).await?;
-
         self.index.clear(); // Clear any existing index entries
 
-        loop {
-            // Read key length
-            match self.file.read_exact(&mut buffer).await {
-                Ok(_) => {
-                    let key_length = u32::from_le_bytes(buffer);
-
-                    // Read value length
-                    self.file.read_exact(&mut buffer).await?;
-                    let value_length = u32::from_le_bytes(buffer);
-
-                    // Read key
-                    let mut key = vec![0; key_length as usize];
-                    self.file.read_exact(&mut key).await?;
-                    let key = String::from_utf8_lossy(&key).into_owned();
-
-                    // Skip value (we don't need it for index creation)
-                    self.file
-                        .seek(SeekFrom::Current(value_length as i64))
-                        .await?;
-
-                    // Insert the key and its corresponding offset into index
-                    self.index.insert(key, offset);
-
-                    // Update offset
-                    offset += 4 + 4 + key_length as u64 + value_length as u64; // Key length bytes + Value length bytes + Key bytes + Value bytes
-                }
-                Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => break,
-                Err(e) => return Err(e),
+        let mut offset = self.data_start;
+        let mut entry = 0usize;
+        while let Some((key, new_offset, _)) = self.read_item_at(offset).await? {
+            // Insert the key and its corresponding offset into index
+            if entry.is_multiple_of(self.index_every_n_entries) {
+                self.index.insert(key, offset as u64);
             }
+            entry += 1;
+            offset = new_offset;
         }
 
         Ok(())
     }
 
-    pub async fn read_item_at(
-        &mut self,
-        byte_offset: usize,
-    ) -> Result<Option<(String, usize, Operation)>> {
-        let mut buffer = [0; 4]; // To read the u32 lengths of key and value
-                                 // seek
-        self.file.seek(SeekFrom::Start(byte_offset as u64)).await?;
-        match self.file.read_exact(&mut buffer).await {
-            Ok(_) => {
-                let key_length = u32::from_le_bytes(buffer);
-
-                // Read value length
-                self.file.read_exact(&mut buffer).await?;
-                let value_length = u32::from_le_bytes(buffer);
-
-                // Read key
-                let mut key = vec![0; key_length as usize];
-                self.file.read_exact(&mut key).await?;
-                let key = String::from_utf8_lossy(&key).into_owned();
-
-                // Read value
-                let mut value = vec![0; value_length as usize];
-                self.file.read_exact(&mut value).await?;
-                let value = String::from_utf8_lossy(&value).into_owned();
-
-                // Update offset
-                let new_offset = byte_offset + 4 + 4 + key_length as usize + value_length as usize; // Key length bytes + Value length bytes + Key bytes + Value bytes
-
-                match value.as_str() {
-                    "TOMBSTONE" => Ok(Some((key, new_offset, Operation::Delete))),
-                    _ => Ok(Some((key, new_offset, Operation::Insert(value)))),
-                }
-            }
-            Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => Ok(None),
-            Err(e) => Err(e),
+    pub async fn read_item_at(&self, byte_offset: usize) -> Result<Option<(String, usize, Cell)>> {
+        if byte_offset >= self.end_offset {
+            return Ok(None);
         }
-    }
+        if self.format_version > 0 {
+            return self.read_block_item_at(byte_offset).await;
+        }
+        let truncated = |path: &str| {
+            Error::corruption(
+                path,
+                byte_offset as u64,
+                "record runs past the end of the file",
+            )
+        };
 
-    pub async fn write(&mut self, key: &str, operation: &Operation) -> Result<usize> {
-        let key_length = key.len() as u32;
-        let mut bytes_written = 0;
+        let mut header = [0u8; 8];
+        let mut file = self.file.lock().await;
+        file.seek(SeekFrom::Start(byte_offset as u64)).await?;
+        match file.read_exact(&mut header).await {
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Err(truncated(&self.path)),
+            result => result?,
+        };
+
+        let key_length = u32::from_le_bytes(header[0..4].try_into().unwrap()) as usize;
+        let value_length = u32::from_le_bytes(header[4..8].try_into().unwrap()) as usize;
+
+        // Update offset
+        let new_offset = byte_offset + header.len() + key_length + value_length;
+        if new_offset > self.end_offset {
+            return Err(truncated(&self.path));
+        }
 
-        let (value, value_length) = match operation {
-            Operation::Insert(val) => (val.as_str(), val.len() as u32),
-            Operation::Delete => ("TOMBSTONE", "TOMBSTONE".len() as u32),
+        // Read key and value
+        let mut key = vec![0; key_length];
+        file.read_exact(&mut key).await?;
+        let key = String::from_utf8(key).map_err(|_| {
+            Error::corruption(&self.path, byte_offset as u64, "key is not valid utf-8")
+        })?;
+        let mut value = vec![0; value_length];
+        file.read_exact(&mut value).await?;
+        drop(file);
+
+        // v0 tombstones have no deletion time, count them as long gone
+        let operation = match String::from_utf8(value) {
+            Ok(value) if value == V0_TOMBSTONE => Operation::Delete(0),
+            Ok(value) => Operation::Insert(value),
+            Err(_) => {
+                return Err(Error::corruption(
+                    &self.path,
+                    byte_offset as u64,
+                    "value is not valid utf-8",
+                ))
+            }
         };
 
-        self.file.write_all(&key_length.to_le_bytes()).await?;
-        self.file.write_all(&value_length.to_le_bytes()).await?;
-        self.file.write_all(key.as_bytes()).await?;
-        self.file.write_all(value.as_bytes()).await?;
+        Ok(Some((
+            key,
+            new_offset,
+            Cell::new(operation, self.legacy_timestamp),
+        )))
+    }
 
-        bytes_written += 4 + 4 + key_length as usize + value_length as usize;
+    // read_item_at for v1 tables, which decodes the record out of its data block
+    async fn read_block_item_at(
+        &self,
+        byte_offset: usize,
+    ) -> Result<Option<(String, usize, Cell)>> {
+        let block_index = self
+            .blocks
+            .partition_point(|block| block.start <= byte_offset)
+            .saturating_sub(1);
+        let handle = match self.blocks.get(block_index) {
+            // records that haven't been written out in a block yet can't be read
+            Some(handle) if byte_offset < handle.start + handle.length as usize => *handle,
+            _ => return Ok(None),
+        };
+        let block = self.read_block(block_index).await?;
+        let (key, cell, length) = decode_record(&block[byte_offset - handle.start..])
+            .map_err(|reason| Error::corruption(&self.path, handle.file_offset, reason))?;
 
-        Ok(bytes_written)
+        Ok(Some((key, byte_offset + length, cell)))
     }
 
-    pub async fn batch_write(
-        &mut self,
-        operations: &Vec<(&String, &Operation)>,
-    ) -> Result<Vec<usize>> {
-        let mut current_offset = 0;
-        let mut offsets = vec![];
-        let mut write_buf = vec![];
-        for (key, operation) in operations {
-            offsets.push(current_offset);
-            let key_length = key.len() as u32;
-            let mut bytes_written_for_key = 0;
-
-            let (value, value_length) = match operation {
-                Operation::Insert(val) => (val.as_str(), val.len() as u32),
-                Operation::Delete => ("TOMBSTONE", "TOMBSTONE".len() as u32),
-            };
+    /// Appends a record, returning its length.
+    pub async fn write(&mut self, key: &str, cell: &Cell) -> Result<usize> {
+        let offset = self.append_record(key, cell).await?;
+        Ok(self.end_offset - offset)
+    }
 
-            write_buf.extend_from_slice(&key_length.to_le_bytes());
-            write_buf.extend_from_slice(&value_length.to_le_bytes());
-            write_buf.extend_from_slice(key.as_bytes());
-            write_buf.extend_from_slice(value.as_bytes());
+    /// Appends the records in order, returning the offset each one starts at.
+    pub async fn batch_write(&mut self, cells: &Vec<(&String, &Cell)>) -> Result<Vec<usize>> {
+        let mut offsets = vec![];
+        for (key, cell) in cells {
+            offsets.push(self.append_record(key, cell).await?);
+        }
+        Ok(offsets)
+    }
 
-            bytes_written_for_key += 4 + 4 + key_length as usize + value_length as usize;
+    // Adds a record to the pending block and writes the block out once it's full. Returns
+    // the offset the record starts at.
+    async fn append_record(&mut self, key: &str, cell: &Cell) -> Result<usize> {
+        let offset = self.end_offset;
+        self.end_offset += encode_record(&mut self.pending_block, key, cell);
+        self.track_entry(key, cell);
+        if self.pending_block.len() >= self.block_size_bytes {
+            self.write_block().await?;
+        }
+        Ok(offset)
+    }
 
-            current_offset += bytes_written_for_key;
+    // Compresses the pending records into a data block and appends it to the file
+    async fn write_block(&mut self) -> Result<()> {
+        if self.pending_block.is_empty() {
+            return Ok(());
         }
+        let block = std::mem::take(&mut self.pending_block);
+        let compressed = self.compression.compress(&block);
+        let stored = if compressed.len() < block.len() {
+            &compressed
+        } else {
+            &block
+        };
 
-        self.file.write_all(&write_buf).await?;
-        Ok(offsets)
+        let mut buf = Vec::with_capacity(BLOCK_HEADER_LENGTH + stored.len());
+        buf.extend_from_slice(&(stored.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&(block.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&crc32c::crc32c(stored).to_le_bytes());
+        buf.extend_from_slice(stored);
+
+        let file = self.file.get_mut();
+        file.seek(SeekFrom::Start(self.file_length as u64)).await?;
+        file.write_all(&buf).await?;
+
+        self.blocks.push(BlockHandle {
+            start: self.end_offset - block.len(),
+            file_offset: self.file_length as u64,
+            stored_length: stored.len() as u32,
+            length: block.len() as u32,
+        });
+        self.file_length += buf.len();
+        self.data_length = self.file_length;
+        Ok(())
     }
 
-    pub async fn sync(&mut self) -> Result<()> {
-        self.file.sync_all().await
+    // Keeps the stats up to date as entries are appended
+    fn track_entry(&mut self, key: &str, cell: &Cell) {
+        let stats = &mut self.stats;
+        stats.entry_count += 1;
+        stats.max_timestamp = stats.max_timestamp.max(cell.timestamp);
+        stats.max_deletion_time = match cell.operation {
+            Operation::Insert(_) => u64::MAX,
+            Operation::Expiring { expires_at, .. } => stats.max_deletion_time.max(expires_at),
+            Operation::Delete(deleted_at) => {
+                stats.tombstone_count += 1;
+                stats.max_deletion_time.max(deleted_at)
+            }
+        };
+        match &mut stats.key_range {
+            Some((min, max)) => {
+                if key < min.as_str() {
+                    *min = key.to_string();
+                }
+                if key > max.as_str() {
+                    *max = key.to_string();
+                }
+            }
+            None => stats.key_range = Some((key.to_string(), key.to_string())),
+        }
     }
 
-    pub async fn read_all(&mut self) -> Result<Vec<(String, Operation)>> {
+    pub async fn read_all(&self) -> Result<Vec<(String, Cell)>> {
         let mut operations = vec![];
-        let mut offset = 0;
+        let mut offset = self.data_start;
         loop {
             let maybe_op = self.read_item_at(offset).await?;
             if maybe_op.is_none() {
@@ -217,10 +871,10 @@ impl SSTable {
     }
 
     pub async fn batch_read(
-        &mut self,
+        &self,
         number_of_items: usize,
         offset: usize,
-    ) -> Result<(Vec<(String, Operation)>, usize)> {
+    ) -> Result<(Vec<(String, Cell)>, usize)> {
         let mut operations = vec![];
         let mut current_offset = offset;
         for _ in 0..number_of_items {
@@ -240,16 +894,27 @@ impl SSTable {
         Ok((operations, current_offset))
     }
 
-    pub async fn find_key(&mut self, target_key: &str) -> Result<Option<Operation>> {
-        let mut buffer = vec![];
+    /// Offset to start reading from to find `key` or the first key after it: the indexed
+    /// key closest below it, or the start of the data if there is none.
+    pub fn seek_offset(&self, key: &str) -> usize {
+        match self.index.range(..=key.to_string()).next_back() {
+            Some((_, offset)) => *offset as usize,
+            None => self.data_start,
+        }
+    }
+
+    pub async fn find_key(&self, target_key: &str) -> Result<Option<Cell>> {
         // binary search self.index (in memory) to find the closest key
         // btreemap keys are sorted, so we can use binary search
         let keys = self.index.keys().collect::<Vec<&String>>();
+        if keys.is_empty() {
+            return Ok(None);
+        }
         let mut start = 0;
         let mut end = keys.len() - 1;
         let mut middle = (start + end) / 2;
         while (end - start) > 1 {
-            if keys[middle] == &target_key {
+            if keys[middle] == target_key {
                 break;
             } else if keys[middle].as_str().cmp(target_key) == std::cmp::Ordering::Greater {
                 end = middle;
@@ -261,56 +926,233 @@ impl SSTable {
         let closest_key = keys[middle];
         let start_offset = self.index[closest_key];
 
-        println!("Closest key: {}, offset: {}", closest_key, start_offset);
-
         // if closest_key is lexically greater than target_key, return None
         if closest_key.as_str().cmp(target_key) == std::cmp::Ordering::Greater {
             return Ok(None);
         }
 
-        self.file.seek(SeekFrom::Start(start_offset)).await?;
-
-        loop {
-            let mut key_length_bytes = [0u8; 4];
-            let mut value_length_bytes = [0u8; 4];
-
-            // Read lengths
-            let read_key_length = self.file.read_exact(&mut key_length_bytes).await;
-            if read_key_length.is_err() {
-                println!("Error reading key length bytes");
-                println!("{:?}", read_key_length);
-                break;
-            }
-            if self.file.read_exact(&mut value_length_bytes).await.is_err() {
-                println!("Error reading value length bytes");
-                break;
+        // scan forward from the closest indexed key; keys are sorted so we can stop
+        // as soon as we're past the target
+        let mut offset = start_offset as usize;
+        while let Some((key, new_offset, cell)) = self.read_item_at(offset).await? {
+            match key.as_str().cmp(target_key) {
+                std::cmp::Ordering::Equal => return Ok(Some(cell)),
+                std::cmp::Ordering::Greater => break,
+                std::cmp::Ordering::Less => offset = new_offset,
             }
+        }
+
+        Ok(None)
+    }
+}
 
-            let key_length = u32::from_le_bytes(key_length_bytes);
-            let value_length = u32::from_le_bytes(value_length_bytes);
+// Append a v1 record to the buffer, returning the number of bytes written
+fn encode_record(buf: &mut Vec<u8>, key: &str, cell: &Cell) -> usize {
+    let deleted_at;
+    let expiring_value;
+    let (kind, value) = match &cell.operation {
+        Operation::Insert(val) => (RECORD_INSERT, val.as_bytes()),
+        Operation::Expiring {
+            value,
+            ttl,
+            expires_at,
+        } => {
+            expiring_value = [
+                &ttl.to_le_bytes()[..],
+                &expires_at.to_le_bytes(),
+                value.as_bytes(),
+            ]
+            .concat();
+            (RECORD_EXPIRING_INSERT, &expiring_value[..])
+        }
+        Operation::Delete(time) => {
+            deleted_at = time.to_le_bytes();
+            (RECORD_DELETE, &deleted_at[..])
+        }
+    };
 
-            // Read key
-            buffer.resize(key_length as usize, 0);
-            self.file.read_exact(&mut buffer).await?;
-            let key = String::from_utf8_lossy(&buffer);
+    buf.push(kind);
+    buf.extend_from_slice(&cell.timestamp.to_le_bytes());
+    buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&(value.len() as u32).to_le_bytes());
+    buf.extend_from_slice(key.as_bytes());
+    buf.extend_from_slice(value);
 
-            // Read value
-            let mut value_buffer = Vec::new();
-            value_buffer.resize(value_length as usize, 0);
-            self.file.read_exact(&mut value_buffer).await?;
-            let value = String::from_utf8_lossy(&value_buffer);
+    1 + 8 + 4 + 4 + key.len() + value.len()
+}
 
-            // println!("key: {}, value: {}, target_key: {}", key, value, target_key);
+// Decode the record at the start of a data block of a v1 table, returning it along with its
+// length
+fn decode_record(bytes: &[u8]) -> std::result::Result<(String, Cell, usize), String> {
+    let header_length = 17;
+    if bytes.len() < header_length {
+        return Err("record runs past the end of the block".to_string());
+    }
+    let timestamp = u64::from_le_bytes(bytes[1..9].try_into().unwrap());
+    let key_length = u32::from_le_bytes(bytes[9..13].try_into().unwrap()) as usize;
+    let value_length = u32::from_le_bytes(bytes[13..17].try_into().unwrap()) as usize;
+    let length = header_length + key_length + value_length;
+    if length > bytes.len() {
+        return Err("record runs past the end of the block".to_string());
+    }
 
-            if key == target_key {
-                return if value == "TOMBSTONE" {
-                    Ok(Some(Operation::Delete))
-                } else {
-                    Ok(Some(Operation::Insert(value.into_owned())))
-                };
-            }
+    let key = std::str::from_utf8(&bytes[header_length..header_length + key_length])
+        .map_err(|_| "key is not valid utf-8".to_string())?
+        .to_string();
+    let operation = decode_operation(bytes[0], &bytes[header_length + key_length..length])?;
+    Ok((key, Cell::new(operation, timestamp), length))
+}
+
+fn decode_operation(kind: u8, value: &[u8]) -> std::result::Result<Operation, String> {
+    let utf8 = |value: &[u8]| match std::str::from_utf8(value) {
+        Ok(value) => Ok(value.to_string()),
+        Err(_) => Err("value is not valid utf-8".to_string()),
+    };
+    match kind {
+        RECORD_INSERT => utf8(value).map(Operation::Insert),
+        RECORD_DELETE if value.len() == 8 => Ok(Operation::Delete(u64::from_le_bytes(
+            value.try_into().unwrap(),
+        ))),
+        RECORD_DELETE => Err("bad deletion time".to_string()),
+        RECORD_EXPIRING_INSERT if value.len() >= 12 => Ok(Operation::Expiring {
+            value: utf8(&value[12..])?,
+            ttl: u32::from_le_bytes(value[0..4].try_into().unwrap()),
+            expires_at: u64::from_le_bytes(value[4..12].try_into().unwrap()),
+        }),
+        RECORD_EXPIRING_INSERT => Err("bad expiration".to_string()),
+        kind => Err(format!("unknown record kind {}", kind)),
+    }
+}
+
+fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
+}
+
+// Decodes an index section. `data_end` is where the last block ends.
+fn decode_index(
+    bytes: &[u8],
+    data_end: u64,
+) -> std::result::Result<(BTreeMap<String, u64>, Vec<BlockHandle>), String> {
+    let corrupt = |msg: &str| format!("corrupt index: {}", msg);
+
+    if bytes.len() < 8 || &bytes[0..4] != INDEX_MAGIC {
+        return Err(corrupt("bad header"));
+    }
+    let count = u32::from_le_bytes(bytes[4..8].try_into().unwrap()) as usize;
+
+    let mut index = BTreeMap::new();
+    let mut pos = 8;
+    for _ in 0..count {
+        if pos + 4 > bytes.len() {
+            return Err(corrupt("truncated entry"));
         }
+        let key_length = u32::from_le_bytes(bytes[pos..pos + 4].try_into().unwrap()) as usize;
+        pos += 4;
+        if pos + key_length + 8 > bytes.len() {
+            return Err(corrupt("truncated entry"));
+        }
+        let key = String::from_utf8(bytes[pos..pos + key_length].to_vec())
+            .map_err(|_| corrupt("key is not valid utf-8"))?;
+        pos += key_length;
+        let offset = u64::from_le_bytes(bytes[pos..pos + 8].try_into().unwrap());
+        pos += 8;
+        index.insert(key, offset);
+    }
 
-        Ok(None)
+    // the blocks have to cover the data file back to back
+    if pos + 4 > bytes.len() {
+        return Err(corrupt("truncated block count"));
+    }
+    let block_count = u32::from_le_bytes(bytes[pos..pos + 4].try_into().unwrap()) as usize;
+    pos += 4;
+    let mut blocks = vec![];
+    let mut start = 0;
+    let mut file_offset = SSTABLE_HEADER_LENGTH as u64;
+    for _ in 0..block_count {
+        if pos + 16 > bytes.len() {
+            return Err(corrupt("truncated block"));
+        }
+        let block = BlockHandle {
+            start,
+            file_offset: u64::from_le_bytes(bytes[pos..pos + 8].try_into().unwrap()),
+            stored_length: u32::from_le_bytes(bytes[pos + 8..pos + 12].try_into().unwrap()),
+            length: u32::from_le_bytes(bytes[pos + 12..pos + 16].try_into().unwrap()),
+        };
+        pos += 16;
+        if block.file_offset != file_offset {
+            return Err(corrupt("blocks don't follow each other"));
+        }
+        start += block.length as usize;
+        file_offset += BLOCK_HEADER_LENGTH as u64 + block.stored_length as u64;
+        blocks.push(block);
+    }
+    if file_offset != data_end {
+        return Err(corrupt("blocks don't match data file"));
+    }
+    let records_end = start as u64;
+
+    if pos != bytes.len() {
+        return Err(corrupt("trailing bytes"));
+    }
+    if index.values().any(|offset| *offset >= records_end) {
+        return Err(corrupt("offset points outside the data file"));
+    }
+    // a non-empty table always has its first entry indexed
+    if index.is_empty() != (records_end == 0) {
+        return Err(corrupt("index doesn't match data file"));
     }
+
+    Ok((index, blocks))
+}
+
+// Appends whether there is a key range and if so the (length, key) of its smallest and
+// largest key
+fn encode_key_range(buf: &mut Vec<u8>, key_range: &Option<(String, String)>) {
+    match key_range {
+        Some((min, max)) => {
+            buf.push(1);
+            for key in [min, max] {
+                buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+                buf.extend_from_slice(key.as_bytes());
+            }
+        }
+        None => buf.push(0),
+    }
+}
+
+// Decodes a key range written by `encode_key_range` at the start of `bytes`, returning it
+// along with its length
+fn decode_key_range(
+    bytes: &[u8],
+) -> std::result::Result<(Option<(String, String)>, usize), String> {
+    let mut pos = 1;
+    let key_range = match bytes.first() {
+        Some(0) => None,
+        Some(1) => {
+            let mut keys = vec![];
+            for _ in 0..2 {
+                if pos + 4 > bytes.len() {
+                    return Err("truncated key".to_string());
+                }
+                let key_length =
+                    u32::from_le_bytes(bytes[pos..pos + 4].try_into().unwrap()) as usize;
+                pos += 4;
+                if pos + key_length > bytes.len() {
+                    return Err("truncated key".to_string());
+                }
+                let key = String::from_utf8(bytes[pos..pos + key_length].to_vec())
+                    .map_err(|_| "key is not valid utf-8".to_string())?;
+                pos += key_length;
+                keys.push(key);
+            }
+            let max = keys.pop().unwrap();
+            let min = keys.pop().unwrap();
+            Some((min, max))
+        }
+        _ => return Err("bad key range flag".to_string()),
+    };
+
+    Ok((key_range, pos))
 }
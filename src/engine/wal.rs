@@ -1,64 +1,704 @@
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fs::{File, OpenOptions};
-use std::io::{BufRead, BufReader, Lines, Read, Result, Seek, SeekFrom, Write};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{watch, Mutex};
+use tokio::task::JoinHandle;
+use uuid::Uuid;
 
-pub struct Wal {
+use super::operation::{next_timestamp_after, Cell, Operation};
+use crate::error::{Error, Result};
+
+// Every WAL record is framed as [payload length u32][crc32 of payload u32][payload], where
+// the payload is [kind u8][write timestamp u64][key length u32][key][value], with the
+// deletion time as a u64 in place of the value for deletes and the value prefixed with
+// [ttl u32][expiration time u64] for expiring inserts. The framing lets replay tell a
+// record that was cut short by a crash apart from a complete one.
+//
+// Records for a table other than the nil one have RECORD_TABLE_ID_FLAG set in their kind
+// and the table id (16 bytes) right after it. Untagged records belong to the nil table,
+// which is the one a standalone Database and a data dir from before tables both use.
+const RECORD_HEADER_LENGTH: usize = 8;
+const RECORD_TABLE_ID_FLAG: u8 = 0x80;
+
+// Replay positions layout: magic, entry count, (table id, segment id) per entry, then a
+// crc32 of everything before it
+const REPLAY_POSITIONS_MAGIC: &[u8; 4] = b"KRPL";
+pub const REPLAY_POSITIONS_FILE_NAME: &str = "REPLAY_POSITIONS";
+const RECORD_INSERT: u8 = 0;
+const RECORD_DELETE: u8 = 1;
+const RECORD_EXPIRING_INSERT: u8 = 2;
+
+// Same as Cassandra's default commitlog_segment_size
+pub const DEFAULT_SEGMENT_SIZE_BYTES: u64 = 32 * 1024 * 1024;
+
+/// When appended WAL records are fsynced, mirroring Cassandra's `commitlog_sync`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "mode", rename_all = "lowercase")]
+pub enum WalSyncMode {
+    /// Every write waits for an fsync covering it before it's acknowledged. Writers that
+    /// arrive while an fsync is in flight share the next one.
+    Batch,
+    /// Like `Batch`, but the fsync waits `window` for more writers to pile in first.
+    Group {
+        #[serde(rename = "window_ms", with = "duration_ms")]
+        window: Duration,
+    },
+    /// Writes are acknowledged right away and a background task fsyncs every `period`.
+    /// A crash can lose up to `period` worth of acknowledged writes.
+    Periodic {
+        #[serde(rename = "period_ms", with = "duration_ms")]
+        period: Duration,
+    },
+}
+
+// Durations in config files are given in milliseconds
+mod duration_ms {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::time::Duration;
+
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(duration.as_millis() as u64)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        Ok(Duration::from_millis(u64::deserialize(deserializer)?))
+    }
+}
+
+impl Default for WalSyncMode {
+    fn default() -> Self {
+        WalSyncMode::Periodic {
+            period: Duration::from_millis(10000),
+        }
+    }
+}
+
+/// A single WAL segment file.
+pub struct WalSegment {
     file: File,
     path: String,
+    length: u64,
+    // written before records were framed, see `replay_legacy`
+    legacy: bool,
 }
 
-impl Wal {
-    pub fn new(path: &str) -> Wal {
+impl WalSegment {
+    /// Opens the segment at `path`, creating it if it doesn't exist.
+    pub fn open(path: &str) -> Result<WalSegment> {
         let file = OpenOptions::new()
             .read(true)
-            .write(true)
+            .append(true)
             .create(true)
-            .open(path)
-            .unwrap();
+            .open(path)?;
+        let length = file.metadata()?.len();
+        // the WAL from before records were framed was a single wal_<uuid> file
+        let legacy = std::path::Path::new(path)
+            .file_name()
+            .and_then(|name| name.to_str()?.strip_prefix("wal_"))
+            .is_some_and(|id| id.parse::<u64>().is_err());
 
-        Wal {
+        Ok(WalSegment {
             file,
             path: path.to_string(),
+            length,
+            legacy,
+        })
+    }
+
+    pub fn path(&self) -> String {
+        self.path.clone()
+    }
+
+    pub fn len(&self) -> u64 {
+        self.length
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    fn append_record(&mut self, record: &[u8]) -> Result<()> {
+        self.file.write_all(record)?;
+        self.length += record.len() as u64;
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        Ok(self.file.sync_data()?)
+    }
+
+    /// Calls `apply` for every record in the segment, oldest first.
+    ///
+    /// A torn record at the end of the segment (cut short or failing its checksum because
+    /// the process died mid-write) ends the replay and is truncated away so new records
+    /// are appended after the last good one. A bad record followed by more data is
+    /// reported as `Error::Corruption`.
+    pub fn replay<F>(&mut self, mut apply: F) -> Result<()>
+    where
+        F: FnMut(Uuid, String, Cell),
+    {
+        if self.legacy {
+            return self.replay_legacy(apply);
         }
+        let file_length = self.file.metadata()?.len();
+        self.file.seek(SeekFrom::Start(0))?;
+        let mut reader = BufReader::new(self.file.try_clone()?);
+
+        let mut offset = 0u64;
+        let mut header = [0u8; RECORD_HEADER_LENGTH];
+        while offset < file_length {
+            if offset + RECORD_HEADER_LENGTH as u64 > file_length {
+                return self.truncate_torn_tail(offset);
+            }
+            reader.read_exact(&mut header)?;
+            let payload_length = u32::from_le_bytes(header[0..4].try_into().unwrap()) as u64;
+            let checksum = u32::from_le_bytes(header[4..8].try_into().unwrap());
+
+            let record_end = offset + RECORD_HEADER_LENGTH as u64 + payload_length;
+            if record_end > file_length {
+                // a corrupted length field points past the end too, the record was only cut
+                // short if nothing whole comes after it
+                if self.has_record_after(offset + 1, file_length)? {
+                    return Err(Error::corruption(
+                        &self.path,
+                        offset,
+                        "record length past end of segment",
+                    ));
+                }
+                return self.truncate_torn_tail(offset);
+            }
+
+            let mut payload = vec![0; payload_length as usize];
+            reader.read_exact(&mut payload)?;
+
+            let decoded = if crc32fast::hash(&payload) != checksum {
+                Err("checksum mismatch".to_string())
+            } else {
+                decode_payload(&payload)
+            };
+
+            match decoded {
+                Ok((table_id, key, cell)) => apply(table_id, key, cell),
+                // the last record in the segment was being written when we went down
+                Err(_) if record_end == file_length => return self.truncate_torn_tail(offset),
+                Err(reason) => return Err(Error::corruption(&self.path, offset, reason)),
+            }
+
+            offset = record_end;
+        }
+
+        Ok(())
     }
 
-    pub fn from_file(path: &str) -> Wal {
-        let file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .append(true)
-            .open(path)
-            .unwrap();
+    // WALs from before records were framed have a line per record, "INSERT\t<key>\t<value>"
+    // or "DELETE\t<key>", without timestamps. They are stamped as they're replayed, after
+    // the file's modification time: the WAL was cleared whenever the MemTable was flushed,
+    // so its records are newer than those of every v0 SSTable, whose records count as
+    // written when the table was. Nothing gets appended to a legacy segment, it goes away
+    // with the rest once the MemTable is flushed.
+    fn replay_legacy<F>(&mut self, mut apply: F) -> Result<()>
+    where
+        F: FnMut(Uuid, String, Cell),
+    {
+        let written_at = self
+            .file
+            .metadata()?
+            .modified()?
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+        self.file.seek(SeekFrom::Start(0))?;
+        let mut reader = BufReader::new(self.file.try_clone()?);
 
-        Wal {
-            file,
-            path: path.to_string(),
+        let mut offset = 0u64;
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            let length = reader.read_until(b'\n', &mut line)?;
+            // a last line without its newline was cut short by a crash
+            if line.pop() != Some(b'\n') {
+                return Ok(());
+            }
+            let corrupt = |reason| Error::corruption(&self.path, offset, reason);
+            let line =
+                std::str::from_utf8(&line).map_err(|_| corrupt("record is not valid utf-8"))?;
+            let mut fields = line.splitn(3, '\t');
+            let (key, operation) = match (fields.next(), fields.next(), fields.next()) {
+                (Some("INSERT"), Some(key), Some(value)) => {
+                    (key, Operation::Insert(value.to_string()))
+                }
+                (Some("DELETE"), Some(key), None) => (key, Operation::Delete(0)),
+                _ => return Err(corrupt("unknown operation")),
+            };
+            apply(
+                Uuid::nil(),
+                key.to_string(),
+                Cell::new(operation, next_timestamp_after(written_at)),
+            );
+            offset += length as u64;
+        }
+    }
+
+    // Whether a record that passes its checksum starts anywhere from `offset` on
+    fn has_record_after(&mut self, offset: u64, file_length: u64) -> Result<bool> {
+        let mut rest = Vec::with_capacity(file_length.saturating_sub(offset) as usize);
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.read_to_end(&mut rest)?;
+        for start in 0..rest.len() {
+            let Some(header) = rest.get(start..start + RECORD_HEADER_LENGTH) else {
+                break;
+            };
+            let payload_length = u32::from_le_bytes(header[0..4].try_into().unwrap()) as usize;
+            let checksum = u32::from_le_bytes(header[4..8].try_into().unwrap());
+            let payload_start = start + RECORD_HEADER_LENGTH;
+            if let Some(payload) = rest.get(payload_start..payload_start + payload_length) {
+                if crc32fast::hash(payload) == checksum && decode_payload(payload).is_ok() {
+                    return Ok(true);
+                }
+            }
         }
+        Ok(false)
     }
 
+    fn truncate_torn_tail(&mut self, offset: u64) -> Result<()> {
+        warn!(
+            "Truncating torn record at offset {} in WAL {}",
+            offset, self.path
+        );
+        self.file.set_len(offset)?;
+        self.file.sync_all()?;
+        self.length = offset;
+        Ok(())
+    }
+}
+
+/// The write-ahead log: a directory of numbered segments (`wal_<id>`), oldest first. Records
+/// are appended to the newest segment; a new one is started when it fills up or when a
+/// MemTable starts flushing, and old segments are removed once their data is in an SSTable.
+///
+/// Several tables can share one WAL, like the commit log of a Cassandra node. Every record
+/// is tagged with its table, and a segment is only removed once none of the tables that
+/// wrote to it need it anymore.
+pub struct Wal {
+    dir: String,
+    segments: Vec<(u64, WalSegment)>, // segment id -> segment, the last one is active
+    // table id -> oldest segment that may have records of the table that aren't in an
+    // SSTable yet. Tables with nothing unflushed aren't in here.
+    dirty_tables: HashMap<Uuid, u64>,
+    // table id -> first segment whose records of the table aren't in an SSTable for sure.
+    // Replay skips the table's records in the segments before it, which other tables may
+    // still be keeping around. Kept on disk in the REPLAY_POSITIONS file.
+    replay_positions: HashMap<Uuid, u64>,
+    last_sequence: u64,
+    synced_sequence: u64,
+    pub segment_size_bytes: u64,
+}
+
+impl Wal {
+    /// Opens the WAL in `dir`, picking up any segments left over from a previous run and
+    /// starting a fresh active segment after them.
+    pub fn open(dir: &str) -> Result<Wal> {
+        let mut segments = vec![];
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            let file_name = entry.file_name();
+            let file_name = file_name.to_str().unwrap_or("");
+            if let Some(suffix) = file_name.strip_prefix("wal_") {
+                // WALs from before segmenting were named wal_<uuid>, they go first
+                let id = suffix.parse::<u64>().unwrap_or(0);
+                segments.push((id, WalSegment::open(entry.path().to_str().unwrap())?));
+            }
+        }
+        segments.sort_by(|(a_id, a), (b_id, b)| a_id.cmp(b_id).then(a.path.cmp(&b.path)));
+
+        let replay_positions_path = format!("{}/{}", dir, REPLAY_POSITIONS_FILE_NAME);
+        let replay_positions = match std::fs::read(&replay_positions_path) {
+            Ok(bytes) => decode_replay_positions(&bytes)
+                .map_err(|reason| Error::corruption(&replay_positions_path, 0, reason))?,
+            // only written once a table that shares the WAL flushes
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut wal = Wal {
+            dir: dir.to_string(),
+            segments,
+            dirty_tables: HashMap::new(),
+            replay_positions,
+            last_sequence: 0,
+            synced_sequence: 0,
+            segment_size_bytes: DEFAULT_SEGMENT_SIZE_BYTES,
+        };
+        wal.rotate()?;
+        Ok(wal)
+    }
+
+    /// Path of the active segment.
     pub fn path(&self) -> String {
-        self.path.clone()
+        self.active_segment().path()
     }
 
-    pub fn append(&mut self, line: &str) -> Result<()> {
-        writeln!(self.file, "{}", line)
+    /// Paths of every live segment, oldest first.
+    pub fn segment_paths(&self) -> Vec<String> {
+        self.segments
+            .iter()
+            .map(|(_, segment)| segment.path())
+            .collect()
     }
 
-    pub fn get_line_iterator(&mut self) -> Lines<BufReader<File>> {
-        self.file.seek(SeekFrom::Start(0)).unwrap();
-        let reader = BufReader::new(self.file.try_clone().unwrap());
-        reader.lines()
+    fn active_segment(&self) -> &WalSegment {
+        &self.segments.last().unwrap().1
     }
 
-    pub fn read(&mut self, offset: u64, size: usize) -> Vec<u8> {
-        let mut buf = vec![0; size];
-        self.file.seek(SeekFrom::Start(offset)).unwrap();
-        self.file.read_exact(&mut buf).unwrap();
-        buf
+    fn active_segment_id(&self) -> u64 {
+        self.segments.last().unwrap().0
     }
 
-    pub fn clear(&mut self) -> Result<()> {
-        self.file.set_len(0)?;
-        self.file.seek(SeekFrom::Start(0))?;
+    /// Logs a write of `cell` to `key` in the table with the given id.
+    pub fn append(&mut self, table_id: Uuid, key: &str, cell: &Cell) -> Result<()> {
+        let deleted_at;
+        let expiring_value;
+        let (kind, value) = match &cell.operation {
+            Operation::Insert(value) => (RECORD_INSERT, value.as_bytes()),
+            Operation::Expiring {
+                value,
+                ttl,
+                expires_at,
+            } => {
+                expiring_value = [
+                    &ttl.to_le_bytes()[..],
+                    &expires_at.to_le_bytes(),
+                    value.as_bytes(),
+                ]
+                .concat();
+                (RECORD_EXPIRING_INSERT, &expiring_value[..])
+            }
+            Operation::Delete(time) => {
+                deleted_at = time.to_le_bytes();
+                (RECORD_DELETE, &deleted_at[..])
+            }
+        };
+
+        let mut payload = Vec::with_capacity(1 + 16 + 8 + 4 + key.len() + value.len());
+        if table_id.is_nil() {
+            payload.push(kind);
+        } else {
+            payload.push(kind | RECORD_TABLE_ID_FLAG);
+            payload.extend_from_slice(table_id.as_bytes());
+        }
+        payload.extend_from_slice(&cell.timestamp.to_le_bytes());
+        payload.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        payload.extend_from_slice(key.as_bytes());
+        payload.extend_from_slice(value);
+
+        // build the whole record up front so it goes out in a single write
+        let mut record = Vec::with_capacity(RECORD_HEADER_LENGTH + payload.len());
+        record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        record.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+        record.extend_from_slice(&payload);
+
+        let active = self.active_segment();
+        if !active.is_empty() && active.len() + record.len() as u64 > self.segment_size_bytes {
+            self.rotate()?;
+        }
+
+        self.segments.last_mut().unwrap().1.append_record(&record)?;
+        self.last_sequence += 1;
+        let active_id = self.active_segment_id();
+        self.dirty_tables.entry(table_id).or_insert(active_id);
         Ok(())
     }
+
+    /// Starts a new active segment and returns its id. Every record appended before the
+    /// call lives in a segment with a smaller id.
+    pub fn rotate(&mut self) -> Result<u64> {
+        // only the active segment is ever left unsynced, so the sync modes can keep
+        // tracking durability with a single sequence number
+        self.sync()?;
+
+        let id = self.segments.last().map(|(id, _)| id + 1).unwrap_or(1);
+        let path = format!("{}/wal_{:020}", self.dir, id);
+        self.segments.push((id, WalSegment::open(&path)?));
+        Ok(id)
+    }
+
+    /// Records that the table's records in segments older than `segment_id` are durably
+    /// stored in SSTables, so replay skips them from now on, and deletes the segments no
+    /// table needs anymore. `has_unflushed` says whether the table has logged anything
+    /// since that it still needs.
+    pub fn mark_flushed(
+        &mut self,
+        table_id: Uuid,
+        segment_id: u64,
+        has_unflushed: bool,
+    ) -> Result<()> {
+        let position = self.replay_positions.entry(table_id).or_insert(0);
+        *position = segment_id.max(*position);
+        self.write_replay_positions()?;
+        match has_unflushed {
+            true => self.dirty_tables.insert(table_id, segment_id),
+            false => self.dirty_tables.remove(&table_id),
+        };
+        self.remove_unneeded_segments()
+    }
+
+    /// Forgets the tables `is_live` says no longer exist, so their records don't keep
+    /// segments around, and deletes the segments no table needs anymore.
+    pub fn retain_tables(&mut self, is_live: impl Fn(&Uuid) -> bool) -> Result<()> {
+        self.dirty_tables.retain(|table_id, _| is_live(table_id));
+        self.replay_positions
+            .retain(|table_id, _| is_live(table_id));
+        self.write_replay_positions()?;
+        self.remove_unneeded_segments()
+    }
+
+    fn remove_unneeded_segments(&mut self) -> Result<()> {
+        let oldest_needed = match self.dirty_tables.values().min() {
+            Some(segment_id) => *segment_id,
+            None => self.active_segment_id(),
+        };
+        self.remove_segments_before(oldest_needed)
+    }
+
+    // Written to a temp file that is renamed into place like the manifest, so a crash
+    // leaves either the old positions or the new ones
+    fn write_replay_positions(&self) -> Result<()> {
+        let mut buf = REPLAY_POSITIONS_MAGIC.to_vec();
+        buf.extend_from_slice(&(self.replay_positions.len() as u32).to_le_bytes());
+        for (table_id, segment_id) in self.replay_positions.iter() {
+            buf.extend_from_slice(table_id.as_bytes());
+            buf.extend_from_slice(&segment_id.to_le_bytes());
+        }
+        let checksum = crc32fast::hash(&buf);
+        buf.extend_from_slice(&checksum.to_le_bytes());
+
+        let path = format!("{}/{}", self.dir, REPLAY_POSITIONS_FILE_NAME);
+        let tmp_path = format!("{}.tmp", path);
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&buf)?;
+        file.sync_all()?;
+        std::fs::rename(&tmp_path, &path)?;
+        File::open(&self.dir)?.sync_all()?;
+        Ok(())
+    }
+
+    /// Deletes every segment older than `segment_id`. Only call this once the data in
+    /// those segments is durably stored in SSTables.
+    pub fn remove_segments_before(&mut self, segment_id: u64) -> Result<()> {
+        while self.segments.len() > 1 && self.segments[0].0 < segment_id {
+            let (_, segment) = self.segments.remove(0);
+            std::fs::remove_file(&segment.path)?;
+        }
+        Ok(())
+    }
+
+    /// Sequence number of the most recently appended record.
+    pub fn last_sequence(&self) -> u64 {
+        self.last_sequence
+    }
+
+    /// Sequence number of the most recent record known to be on disk.
+    pub fn synced_sequence(&self) -> u64 {
+        self.synced_sequence
+    }
+
+    /// Fsyncs everything appended so far, returning the sequence number that is now durable.
+    pub fn sync(&mut self) -> Result<u64> {
+        if self.synced_sequence < self.last_sequence {
+            self.segments.last_mut().unwrap().1.sync()?;
+            self.synced_sequence = self.last_sequence;
+        }
+        Ok(self.synced_sequence)
+    }
+
+    /// Calls `apply` with the table id, key and cell of every record in every live segment,
+    /// oldest first, skipping the ones a table marked as flushed. The segments stay around
+    /// until every table they have records of is flushed or forgotten with `retain_tables`.
+    pub fn replay<F>(&mut self, mut apply: F) -> Result<()>
+    where
+        F: FnMut(Uuid, String, Cell),
+    {
+        for (segment_id, segment) in self.segments.iter_mut() {
+            let dirty_tables = &mut self.dirty_tables;
+            let replay_positions = &self.replay_positions;
+            segment.replay(|table_id, key, cell| {
+                if *segment_id < replay_positions.get(&table_id).copied().unwrap_or(0) {
+                    return;
+                }
+                dirty_tables.entry(table_id).or_insert(*segment_id);
+                apply(table_id, key, cell)
+            })?;
+        }
+        Ok(())
+    }
+}
+
+/// Makes writes durable according to the configured `WalSyncMode`.
+pub struct WalSyncer {
+    mode: WalSyncMode,
+    // highest WAL sequence number known to be on disk
+    synced: watch::Sender<u64>,
+    // set while some writer is running a group fsync on behalf of the others
+    sync_in_progress: AtomicBool,
+    periodic_task: Option<JoinHandle<()>>,
+}
+
+impl WalSyncer {
+    pub fn new(mode: WalSyncMode, wal: &Arc<Mutex<Wal>>) -> WalSyncer {
+        let periodic_task = match mode {
+            WalSyncMode::Periodic { period } => match tokio::runtime::Handle::try_current() {
+                Ok(handle) => Some(handle.spawn(periodic_sync(wal.clone(), period))),
+                Err(_) => {
+                    warn!("No tokio runtime, periodic WAL sync disabled");
+                    None
+                }
+            },
+            _ => None,
+        };
+
+        WalSyncer {
+            mode,
+            synced: watch::channel(0).0,
+            sync_in_progress: AtomicBool::new(false),
+            periodic_task,
+        }
+    }
+
+    pub fn mode(&self) -> WalSyncMode {
+        self.mode
+    }
+
+    /// Waits until the WAL record with the given sequence number is durable. Returns right
+    /// away in periodic mode. Must be called without holding the WAL lock.
+    pub async fn wait_for(&self, wal: &Arc<Mutex<Wal>>, sequence: u64) -> Result<()> {
+        let window = match self.mode {
+            WalSyncMode::Batch => Duration::ZERO,
+            WalSyncMode::Group { window } => window,
+            WalSyncMode::Periodic { .. } => return Ok(()),
+        };
+
+        let mut synced = self.synced.subscribe();
+        loop {
+            if *synced.borrow_and_update() >= sequence {
+                return Ok(());
+            }
+
+            if self.sync_in_progress.swap(true, Ordering::AcqRel) {
+                // someone else is syncing, wait for them to publish how far they got
+                let _ = synced.changed().await;
+                continue;
+            }
+
+            // we're the leader: give other writers the window to append, then fsync once for all of them
+            if !window.is_zero() {
+                tokio::time::sleep(window).await;
+            }
+            let result = wal.lock().await.sync();
+            self.sync_in_progress.store(false, Ordering::Release);
+            match result {
+                Ok(durable) => {
+                    self.synced
+                        .send_modify(|synced| *synced = durable.max(*synced));
+                }
+                Err(e) => {
+                    // wake the followers so one of them retries the sync
+                    self.synced.send_modify(|_| ());
+                    return Err(e);
+                }
+            }
+        }
+    }
+}
+
+impl Drop for WalSyncer {
+    fn drop(&mut self) {
+        if let Some(task) = &self.periodic_task {
+            task.abort();
+        }
+    }
+}
+
+async fn periodic_sync(wal: Arc<Mutex<Wal>>, period: Duration) {
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        if let Err(e) = wal.lock().await.sync() {
+            error!("Failed to sync WAL: {}", e);
+        }
+    }
+}
+
+fn decode_replay_positions(bytes: &[u8]) -> std::result::Result<HashMap<Uuid, u64>, String> {
+    if bytes.len() < 12 || &bytes[0..4] != REPLAY_POSITIONS_MAGIC {
+        return Err("bad replay positions header".to_string());
+    }
+    let (body, checksum) = bytes.split_at(bytes.len() - 4);
+    if crc32fast::hash(body) != u32::from_le_bytes(checksum.try_into().unwrap()) {
+        return Err("replay positions checksum mismatch".to_string());
+    }
+    let count = u32::from_le_bytes(body[4..8].try_into().unwrap()) as usize;
+    if body.len() != 8 + count * 24 {
+        return Err("wrong length for the number of entries".to_string());
+    }
+    Ok(body[8..]
+        .chunks_exact(24)
+        .map(|entry| {
+            let table_id = Uuid::from_slice(&entry[0..16]).unwrap();
+            let segment_id = u64::from_le_bytes(entry[16..24].try_into().unwrap());
+            (table_id, segment_id)
+        })
+        .collect())
+}
+
+fn decode_payload(payload: &[u8]) -> std::result::Result<(Uuid, String, Cell), String> {
+    let Some(&kind) = payload.first() else {
+        return Err("record too short".to_string());
+    };
+    if kind & RECORD_TABLE_ID_FLAG != 0 {
+        if payload.len() < 17 {
+            return Err("record too short".to_string());
+        }
+        let table_id = Uuid::from_slice(&payload[1..17]).unwrap();
+        // the rest is laid out like an untagged record
+        let untagged = [&[kind & !RECORD_TABLE_ID_FLAG][..], &payload[17..]].concat();
+        let (_, key, cell) = decode_payload(&untagged)?;
+        return Ok((table_id, key, cell));
+    }
+    // the kind, the timestamp and the key length come first
+    let key_start = 13;
+    if payload.len() < key_start {
+        return Err("record too short".to_string());
+    }
+    let timestamp = u64::from_le_bytes(payload[1..9].try_into().unwrap());
+    let key_length =
+        u32::from_le_bytes(payload[key_start - 4..key_start].try_into().unwrap()) as usize;
+    if key_start + key_length > payload.len() {
+        return Err("key length past end of record".to_string());
+    }
+    let key = String::from_utf8(payload[key_start..key_start + key_length].to_vec())
+        .map_err(|_| "key is not valid utf-8".to_string())?;
+    let value = &payload[key_start + key_length..];
+
+    let utf8 =
+        |value: &[u8]| String::from_utf8(value.to_vec()).map_err(|_| "value is not valid utf-8");
+    let operation = match kind {
+        RECORD_INSERT => Operation::Insert(utf8(value)?),
+        RECORD_EXPIRING_INSERT if value.len() >= 12 => Operation::Expiring {
+            value: utf8(&value[12..])?,
+            ttl: u32::from_le_bytes(value[0..4].try_into().unwrap()),
+            expires_at: u64::from_le_bytes(value[4..12].try_into().unwrap()),
+        },
+        RECORD_EXPIRING_INSERT => return Err("bad expiration".to_string()),
+        RECORD_DELETE => match value.len() {
+            8 => Operation::Delete(u64::from_le_bytes(value.try_into().unwrap())),
+            _ => return Err("bad deletion time".to_string()),
+        },
+        kind => return Err(format!("unknown record kind {}", kind)),
+    };
+    Ok((Uuid::nil(), key, Cell::new(operation, timestamp)))
 }
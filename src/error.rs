@@ -0,0 +1,69 @@
+use std::fmt::Display;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Everything that can go wrong in the engine.
+#[derive(Debug)]
+pub enum Error {
+    Io(std::io::Error),
+    /// Data on disk doesn't decode: a bad record in an SSTable or WAL segment, a mangled
+    /// sidecar file, etc. `offset` is where in `path` the bad data starts.
+    Corruption {
+        path: String,
+        offset: u64,
+        reason: String,
+    },
+    /// The configuration is invalid or couldn't be parsed.
+    Config(String),
+    /// A query couldn't be parsed.
+    Parse(String),
+    /// A query parsed but can't be run, e.g. it names a table that doesn't exist.
+    InvalidRequest(String),
+    /// The background task that flushes and compacts has stopped, e.g. because it panicked.
+    BackgroundTaskStopped,
+    /// The Database was closed, e.g. because its table was dropped.
+    Closed,
+}
+
+impl Error {
+    pub fn corruption(path: &str, offset: u64, reason: impl Into<String>) -> Error {
+        Error::Corruption {
+            path: path.to_string(),
+            offset,
+            reason: reason.into(),
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::Corruption {
+                path,
+                offset,
+                reason,
+            } => write!(f, "{} is corrupted at offset {}: {}", path, offset, reason),
+            Error::Config(msg) => write!(f, "invalid config: {}", msg),
+            Error::Parse(msg) => write!(f, "parse error: {}", msg),
+            Error::InvalidRequest(msg) => write!(f, "invalid request: {}", msg),
+            Error::BackgroundTaskStopped => write!(f, "the background task has stopped"),
+            Error::Closed => write!(f, "the database is closed"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
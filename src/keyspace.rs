@@ -0,0 +1,348 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use tokio::sync::{Mutex, RwLock};
+use uuid::Uuid;
+
+use crate::config::DatabaseConfig;
+use crate::engine::manifest::MANIFEST_FILE_NAME;
+use crate::engine::schema::{SchemaFile, TableDefinition, TableSchema};
+use crate::engine::wal::{Wal, WalSyncer};
+use crate::error::{Error, Result};
+use crate::table::Table;
+use crate::{open_wal, Database};
+
+/// The keyspace table names without one refer to.
+pub const DEFAULT_KEYSPACE: &str = "kassantra";
+/// The table a new data dir starts out with. A data dir from before there were tables
+/// becomes this table.
+pub const DEFAULT_TABLE: &str = "the_table";
+
+// Same limit as Cassandra's, names end up in paths
+const MAX_NAME_LENGTH: usize = 48;
+
+/// A named group of tables. Keyspaces come and go with their tables: creating a table in
+/// a keyspace that doesn't exist creates it, and dropping its last table drops it.
+pub struct Keyspace {
+    pub name: String,
+    tables: HashMap<String, Arc<Table>>,
+}
+
+impl Keyspace {
+    fn new(name: &str) -> Keyspace {
+        Keyspace {
+            name: name.to_string(),
+            tables: HashMap::new(),
+        }
+    }
+
+    pub fn table(&self, name: &str) -> Option<Arc<Table>> {
+        self.tables.get(name).cloned()
+    }
+
+    pub fn tables(&self) -> impl Iterator<Item = &Arc<Table>> {
+        self.tables.values()
+    }
+}
+
+/// Every keyspace in a data dir, and the WAL their tables share as a commit log like the
+/// one a Cassandra node has. The tables are listed in the data dir's schema file.
+pub struct Node {
+    config: DatabaseConfig,
+    wal: Arc<Mutex<Wal>>,
+    wal_syncer: Arc<WalSyncer>,
+    schema: SchemaFile,
+    // creating and dropping tables takes the write lock for the whole operation
+    keyspaces: RwLock<HashMap<String, Keyspace>>,
+}
+
+impl Node {
+    /// Opens every table in the configured data dir, replaying the shared WAL into their
+    /// MemTables. A new data dir starts out with just `kassantra.the_table`, and one from
+    /// before there were tables has its SSTables moved into that table.
+    ///
+    /// Like `Database::load`, has to be called from within a Tokio runtime.
+    pub async fn load(config: impl Into<DatabaseConfig>) -> Result<Node> {
+        let config = config.into();
+        config.validate()?;
+        std::fs::create_dir_all(&config.data_dir)?;
+
+        let schema = SchemaFile::new(&config.data_dir);
+        if !schema.exists() {
+            upgrade_data_dir(&config.data_dir)?;
+            schema.write(&[TableDefinition {
+                keyspace: DEFAULT_KEYSPACE.to_string(),
+                name: DEFAULT_TABLE.to_string(),
+                // the WAL records from before there were tables aren't tagged, which
+                // makes them the nil table's
+                id: Uuid::nil(),
+                schema: None,
+            }])?;
+        }
+
+        let (wal, wal_syncer) = open_wal(&config)?;
+        let node = Node {
+            config,
+            wal,
+            wal_syncer,
+            schema,
+            keyspaces: RwLock::new(HashMap::new()),
+        };
+
+        let definitions = node.schema.read()?;
+        let mut keyspaces = HashMap::new();
+        for definition in definitions.iter() {
+            let table = node.open_table(definition).await?;
+            keyspaces
+                .entry(definition.keyspace.clone())
+                .or_insert_with(|| Keyspace::new(&definition.keyspace))
+                .tables
+                .insert(definition.name.clone(), Arc::new(table));
+        }
+        *node.keyspaces.write().await = keyspaces;
+
+        // records of tables that were dropped don't keep their segments around
+        let live_ids = definitions
+            .iter()
+            .map(|definition| definition.id)
+            .collect::<HashSet<_>>();
+        node.wal
+            .lock()
+            .await
+            .retain_tables(|id| live_ids.contains(id))?;
+
+        Ok(node)
+    }
+
+    pub fn config(&self) -> &DatabaseConfig {
+        &self.config
+    }
+
+    /// The names of the keyspaces, sorted.
+    pub async fn keyspace_names(&self) -> Vec<String> {
+        let mut names = self
+            .keyspaces
+            .read()
+            .await
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    /// Looks up a table, failing with `Error::InvalidRequest` if there's no such table.
+    pub async fn table(&self, keyspace: &str, name: &str) -> Result<Arc<Table>> {
+        self.keyspaces
+            .read()
+            .await
+            .get(keyspace)
+            .and_then(|keyspace| keyspace.table(name))
+            .ok_or_else(|| unconfigured_table(keyspace, name))
+    }
+
+    /// Creates a table with the given columns, or of plain key-value pairs without a
+    /// schema, and its keyspace if that doesn't exist yet. If the table already exists
+    /// it's returned as is when `if_not_exists` is set, otherwise it's an error.
+    pub async fn create_table(
+        &self,
+        keyspace: &str,
+        name: &str,
+        schema: Option<TableSchema>,
+        if_not_exists: bool,
+    ) -> Result<Arc<Table>> {
+        validate_name(keyspace)?;
+        validate_name(name)?;
+        if let Some(schema) = &schema {
+            validate_schema(schema)?;
+        }
+        let mut keyspaces = self.keyspaces.write().await;
+        if let Some(table) = keyspaces.get(keyspace).and_then(|ks| ks.table(name)) {
+            if if_not_exists {
+                return Ok(table);
+            }
+            return Err(Error::InvalidRequest(format!(
+                "table {}.{} already exists",
+                keyspace, name
+            )));
+        }
+
+        let definition = TableDefinition {
+            keyspace: keyspace.to_string(),
+            name: name.to_string(),
+            id: Uuid::new_v4(),
+            schema,
+        };
+        // a drop that crashed halfway may have left files behind
+        let data_dir = self.table_config(&definition).data_dir;
+        if std::path::Path::new(&data_dir).exists() {
+            std::fs::remove_dir_all(&data_dir)?;
+        }
+        let mut definitions = table_definitions(&keyspaces);
+        definitions.push(definition.clone());
+        self.schema.write(&definitions)?;
+
+        let table = Arc::new(self.open_table(&definition).await?);
+        keyspaces
+            .entry(keyspace.to_string())
+            .or_insert_with(|| Keyspace::new(keyspace))
+            .tables
+            .insert(name.to_string(), table.clone());
+        Ok(table)
+    }
+
+    /// Drops a table and deletes all its data, and its keyspace with it if it was the last
+    /// table there. A table that doesn't exist is an error unless `if_exists` is set.
+    pub async fn drop_table(&self, keyspace: &str, name: &str, if_exists: bool) -> Result<()> {
+        let mut keyspaces = self.keyspaces.write().await;
+        let Some(keyspace_tables) = keyspaces.get_mut(keyspace) else {
+            return match if_exists {
+                true => Ok(()),
+                false => Err(unconfigured_table(keyspace, name)),
+            };
+        };
+        let Some(table) = keyspace_tables.tables.remove(name) else {
+            return match if_exists {
+                true => Ok(()),
+                false => Err(unconfigured_table(keyspace, name)),
+            };
+        };
+        let drop_keyspace = keyspace_tables.tables.is_empty();
+        if drop_keyspace {
+            keyspaces.remove(keyspace);
+        }
+        // once it's out of the schema the table is gone, whatever happens to its files
+        self.schema.write(&table_definitions(&keyspaces))?;
+
+        // others may still hold the table: wait for their reads and writes, and the
+        // flushes and compactions those queued, so nothing writes into the dir as it's
+        // being removed, and have anything they try after that fail
+        table.database.close().await?;
+        if drop_keyspace {
+            std::fs::remove_dir_all(format!("{}/{}", self.config.data_dir, keyspace))?;
+        } else {
+            std::fs::remove_dir_all(&table.database.config.data_dir)?;
+        }
+        // its records in the WAL are never going to be replayed
+        self.wal.lock().await.retain_tables(|id| *id != table.id)?;
+        Ok(())
+    }
+
+    /// Removes all the data in a table, keeping the table.
+    pub async fn truncate(&self, keyspace: &str, name: &str) -> Result<()> {
+        self.table(keyspace, name).await?.database.truncate().await
+    }
+
+    // Each table gets the node's settings, with its own dir under the data dir
+    fn table_config(&self, definition: &TableDefinition) -> DatabaseConfig {
+        DatabaseConfig {
+            data_dir: format!(
+                "{}/{}/{}",
+                self.config.data_dir, definition.keyspace, definition.name
+            ),
+            ..self.config.clone()
+        }
+    }
+
+    async fn open_table(&self, definition: &TableDefinition) -> Result<Table> {
+        let database = Database::open_table(
+            self.table_config(definition),
+            definition.id,
+            self.wal.clone(),
+            self.wal_syncer.clone(),
+        )
+        .await?;
+        Ok(Table {
+            keyspace: definition.keyspace.clone(),
+            name: definition.name.clone(),
+            id: definition.id,
+            schema: definition.schema.clone(),
+            database,
+        })
+    }
+}
+
+// Every table there is, sorted so the schema file comes out the same every time
+fn table_definitions(keyspaces: &HashMap<String, Keyspace>) -> Vec<TableDefinition> {
+    let mut definitions = keyspaces
+        .values()
+        .flat_map(|keyspace| keyspace.tables())
+        .map(|table| TableDefinition {
+            keyspace: table.keyspace.clone(),
+            name: table.name.clone(),
+            id: table.id,
+            schema: table.schema.clone(),
+        })
+        .collect::<Vec<_>>();
+    definitions.sort_by(|a, b| (&a.keyspace, &a.name).cmp(&(&b.keyspace, &b.name)));
+    definitions
+}
+
+// Moves the SSTables and manifest of a data dir from before there were tables into the
+// default table's dir. The WAL stays where it is, it's the shared one now.
+fn upgrade_data_dir(data_dir: &str) -> Result<()> {
+    let table_dir = format!("{}/{}/{}", data_dir, DEFAULT_KEYSPACE, DEFAULT_TABLE);
+    std::fs::create_dir_all(&table_dir)?;
+    for entry in std::fs::read_dir(data_dir)? {
+        let entry = entry?;
+        let file_name = entry.file_name();
+        let file_name = file_name.to_str().unwrap_or("");
+        if file_name.starts_with("sstable") || file_name.starts_with(MANIFEST_FILE_NAME) {
+            std::fs::rename(entry.path(), format!("{}/{}", table_dir, file_name))?;
+        }
+    }
+    Ok(())
+}
+
+fn validate_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_NAME_LENGTH
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    match valid {
+        true => Ok(()),
+        false => Err(Error::InvalidRequest(format!(
+            "{:?} is not a valid name, use up to {} letters, digits and underscores",
+            name, MAX_NAME_LENGTH
+        ))),
+    }
+}
+
+fn validate_schema(schema: &TableSchema) -> Result<()> {
+    for (i, column) in schema.columns.iter().enumerate() {
+        validate_name(&column.name)?;
+        if schema.columns[..i]
+            .iter()
+            .any(|other| other.name == column.name)
+        {
+            return Err(Error::InvalidRequest(format!(
+                "column {} is declared twice",
+                column.name
+            )));
+        }
+    }
+    if schema.partition_key.is_empty() {
+        return Err(Error::InvalidRequest(
+            "the primary key needs a partition key column".to_string(),
+        ));
+    }
+    let primary_key = schema.primary_key().collect::<Vec<_>>();
+    for (i, name) in primary_key.iter().enumerate() {
+        if schema.column(name).is_none() {
+            return Err(Error::InvalidRequest(format!(
+                "primary key column {} is not one of the columns",
+                name
+            )));
+        }
+        if primary_key[..i].contains(name) {
+            return Err(Error::InvalidRequest(format!(
+                "column {} is in the primary key twice",
+                name
+            )));
+        }
+    }
+    Ok(())
+}
+
+fn unconfigured_table(keyspace: &str, name: &str) -> Error {
+    Error::InvalidRequest(format!("unconfigured table {}.{}", keyspace, name))
+}
@@ -1,117 +1,416 @@
+pub mod config;
 pub mod engine;
+pub mod error;
+pub mod keyspace;
 pub mod network;
 pub mod ql;
-
-use engine::memtable::MemTable;
-use engine::operation::Operation;
+pub mod query;
+pub mod table;
+pub mod types;
+
+pub use config::{CorruptionPolicy, DatabaseConfig};
+pub use error::{Error, Result};
+pub use keyspace::{Keyspace, Node};
+pub use table::Table;
+
+use async_stream::try_stream;
+use engine::bloom::{BloomFilter, BloomFilterMetrics};
+use engine::compaction::{overlaps, CompactionStrategy, CompactionTask, CompactionWriter};
+use engine::manifest::Manifest;
+use engine::memtable::{ImmutableMemTable, MemTable};
+use engine::operation::{next_timestamp, Cell, Operation};
 use engine::sstable::SSTable;
-use engine::wal::Wal;
+use engine::wal::{Wal, WalSegment, WalSyncMode, WalSyncer};
+use log::{debug, error, info, warn};
 use priority_queue::PriorityQueue;
-use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
-use std::fs::read_dir;
-use std::io::Result;
+use std::collections::{HashSet, VecDeque};
+use std::ops::{Bound, RangeBounds};
 use std::sync::Arc;
 use std::time::SystemTime;
-use tokio::sync::Mutex;
+use tokio::sync::{mpsc, oneshot, Mutex, RwLock, RwLockReadGuard, Semaphore};
+use tokio_stream::Stream;
 use uuid::Uuid;
 
 pub struct Database {
     pub wal: Arc<Mutex<Wal>>,
+    pub wal_syncer: Arc<WalSyncer>,
     pub memtable: Arc<Mutex<MemTable>>,
-    pub sstables: Arc<Mutex<Vec<SSTable>>>,
-    pub sstable_compaction_threshold: usize,
-    pub data_dir: String,
+    /// Full MemTables waiting to be flushed, oldest first.
+    pub immutable_memtables: Arc<Mutex<VecDeque<ImmutableMemTable>>>,
+    pub sstables: Arc<Mutex<Vec<Arc<SSTable>>>>,
+    pub bloom_filter_metrics: BloomFilterMetrics,
+    pub config: DatabaseConfig,
+    // what the WAL records are tagged with, nil unless the WAL is shared with other tables
+    table_id: Uuid,
+    // flushes and compactions run on a background task, which takes jobs from this queue
+    background_jobs: mpsc::Sender<BackgroundJob>,
+    // one permit per immutable MemTable allowed to wait for its flush
+    immutable_memtable_slots: Arc<Semaphore>,
+    // true once `close` was called. Operations hold the read lock while they run, so
+    // taking the write lock waits for the ones in flight.
+    closed: RwLock<bool>,
 }
 
 impl Database {
-    /// Creates a new Database with an empty MemTable and no SSTables.
-    pub fn new(data_dir: &str) -> Self {
+    /// Creates a new Database with an empty MemTable and no SSTables. Takes either a
+    /// `DatabaseConfig` or just a data dir to use the default settings. The data dir has
+    /// to be empty or not exist yet, an existing database is opened with `load`.
+    ///
+    /// Flushes and compactions run on a background task, so this has to be called from
+    /// within a Tokio runtime.
+    pub fn new(config: impl Into<DatabaseConfig>) -> Result<Self> {
+        let config = config.into();
+        config.validate()?;
         // create data dir if doesnt exist
-        std::fs::create_dir_all(data_dir).unwrap_or(());
-        let wal_path = format!("{}/wal_{}", data_dir, Uuid::new_v4());
-        Self {
-            wal: Arc::new(Mutex::new(Wal::new(wal_path.as_str()))),
-            memtable: Arc::new(Mutex::new(MemTable::new())),
-            sstables: Arc::new(Mutex::new(Vec::new())),
-            sstable_compaction_threshold: 10,
-            data_dir: data_dir.to_string(),
+        std::fs::create_dir_all(&config.data_dir)?;
+        // an empty manifest would make every SSTable already in the dir an orphan, and the
+        // WAL segments would be appended to without being replayed
+        if std::fs::read_dir(&config.data_dir)?.next().is_some() {
+            return Err(Error::Config(format!(
+                "data dir {} isn't empty, use Database::load to open an existing database",
+                config.data_dir
+            )));
         }
+        let (wal, wal_syncer) = open_wal(&config)?;
+        let memtable = MemTable::with_flush_threshold(config.memtable_flush_threshold_bytes);
+        Manifest::new(&config.data_dir).write(&[])?;
+        Ok(Self::start(
+            config,
+            Uuid::nil(),
+            wal,
+            wal_syncer,
+            memtable,
+            Vec::new(),
+        ))
     }
 
-    pub async fn load(data_dir: &str) -> Result<Self> {
-        // sstable names are sstable_timestamp_uuid so we can sort them by timestamp
-        let dir = tokio::fs::read_dir(data_dir).await;
-        if dir.is_err() {
-            return Ok(Self::new(data_dir));
+    /// Opens the table with the given id in the configured data dir, creating the dir if
+    /// it doesn't exist. The WAL is shared with other tables and must have been opened
+    /// already, its records of the table are replayed into the MemTable.
+    pub(crate) async fn open_table(
+        config: DatabaseConfig,
+        table_id: Uuid,
+        wal: Arc<Mutex<Wal>>,
+        wal_syncer: Arc<WalSyncer>,
+    ) -> Result<Self> {
+        config.validate()?;
+        std::fs::create_dir_all(&config.data_dir)?;
+        let sstables = Self::load_sstables(&config).await?;
+        let mut memtable = MemTable::for_table(table_id, config.memtable_flush_threshold_bytes);
+        memtable.replay_wal(&mut *wal.lock().await)?;
+        Ok(Self::start(
+            config, table_id, wal, wal_syncer, memtable, sstables,
+        ))
+    }
+
+    // Puts the Database together and starts its background task
+    fn start(
+        config: DatabaseConfig,
+        table_id: Uuid,
+        wal: Arc<Mutex<Wal>>,
+        wal_syncer: Arc<WalSyncer>,
+        memtable: MemTable,
+        sstables: Vec<SSTable>,
+    ) -> Self {
+        let memtable = Arc::new(Mutex::new(memtable));
+        let immutable_memtables = Arc::new(Mutex::new(VecDeque::new()));
+        let mut sstables = sstables.into_iter().map(Arc::new).collect::<Vec<_>>();
+        sort_by_level(&mut sstables);
+        let sstables = Arc::new(Mutex::new(sstables));
+
+        let (background_jobs, jobs) = mpsc::channel(config.background_queue_size);
+        let worker = BackgroundWorker {
+            table_id,
+            wal: wal.clone(),
+            memtable: memtable.clone(),
+            immutable_memtables: immutable_memtables.clone(),
+            sstables: sstables.clone(),
+            compaction_strategy: config.compaction.build(),
+            manifest: Manifest::new(&config.data_dir),
+            config: config.clone(),
+        };
+        tokio::spawn(worker.run(jobs));
+
+        Self {
+            wal_syncer,
+            wal,
+            memtable,
+            immutable_memtables,
+            sstables,
+            bloom_filter_metrics: BloomFilterMetrics::default(),
+            immutable_memtable_slots: Arc::new(Semaphore::new(config.max_immutable_memtables)),
+            config,
+            table_id,
+            background_jobs,
+            closed: RwLock::new(false),
         }
-        let mut dir = dir.unwrap();
-        let mut sstable_paths = Vec::new();
-        let mut wal_path = None;
-        while let Some(entry) = dir.next_entry().await? {
-            if entry.file_name().to_str().unwrap().starts_with("sstable") {
-                sstable_paths.push(entry.path());
-            }
-            if entry.file_name().to_str().unwrap().starts_with("wal") {
-                wal_path = Some(entry.path());
-            }
+    }
+
+    /// Opens the Database in the configured data dir, loading its SSTables and replaying its WAL.
+    pub async fn load(config: impl Into<DatabaseConfig>) -> Result<Self> {
+        let config = config.into();
+        config.validate()?;
+        if tokio::fs::metadata(&config.data_dir).await.is_err() {
+            return Self::new(config);
         }
+        let sstables = Self::load_sstables(&config).await?;
 
-        sstable_paths.sort();
+        // replay every live WAL segment in order to rebuild the MemTable
+        let (wal, wal_syncer) = open_wal(&config)?;
+        info!(
+            "Loading WAL segments: {:?}",
+            wal.lock().await.segment_paths()
+        );
+        let mut memtable = MemTable::with_flush_threshold(config.memtable_flush_threshold_bytes);
+        memtable.replay_wal(&mut *wal.lock().await)?;
+
+        Ok(Self::start(
+            config,
+            Uuid::nil(),
+            wal,
+            wal_syncer,
+            memtable,
+            sstables,
+        ))
+    }
 
-        println!("Loading SSTables: {:?}", sstable_paths);
-        println!("Loading WAL: {:?}", wal_path);
+    // Loads the live SSTables in the configured data dir
+    async fn load_sstables(config: &DatabaseConfig) -> Result<Vec<SSTable>> {
+        let data_dir = config.data_dir.as_str();
+        // sstable names are sstable_timestamp_uuid so we can sort them by timestamp
+        let mut dir = tokio::fs::read_dir(data_dir).await?;
+
+        // only the SSTables in the manifest are live, anything else was left behind by a
+        // flush or compaction that didn't finish
+        let manifest = Manifest::new(data_dir);
+        let has_manifest = manifest.exists();
+        let sstable_names = if has_manifest {
+            manifest.read()?
+        } else {
+            // data dirs from before the manifest: every SSTable file is live
+            let mut sstable_names = Vec::new();
+            while let Some(entry) = dir.next_entry().await? {
+                // skip leftover temp files (sstable_..._uuid.tmp)
+                let file_name = entry.file_name();
+                let file_name = file_name.to_str().unwrap();
+                if file_name.starts_with("sstable") && !file_name.contains('.') {
+                    sstable_names.push(file_name.to_string());
+                }
+            }
+            sstable_names.sort();
+            sstable_names
+        };
+        manifest.remove_orphans(&sstable_names)?;
+
+        info!("Loading SSTables: {:?}", sstable_names);
 
         let mut sstables = Vec::new();
-        for path in sstable_paths {
-            let sstable = SSTable::from_file(path.to_str().unwrap()).await?;
+        let mut live_names = Vec::new();
+        for name in sstable_names.iter() {
+            let path = format!("{}/{}", data_dir, name);
+            let mut sstable = match SSTable::from_file(&path).await {
+                Ok(sstable) => sstable,
+                Err(e @ Error::Corruption { .. })
+                    if config.corruption_policy != CorruptionPolicy::Fail =>
+                {
+                    // a table that doesn't open can't be kept around to skip on every read,
+                    // and left out of the manifest it would be removed as an orphan, so Skip
+                    // sets it aside like Quarantine does. It's moved before the manifest
+                    // stops listing it so a crash in between doesn't lose it.
+                    warn!("Quarantining SSTable {} that doesn't open: {}", path, e);
+                    let quarantine_dir = format!("{}/quarantine", data_dir);
+                    std::fs::create_dir_all(&quarantine_dir)?;
+                    std::fs::rename(&path, format!("{}/{}", quarantine_dir, name))?;
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            sstable.index_every_n_entries = config.index_every_n_entries;
+            sstable.bloom_filter_fp_chance = config.bloom_filter_fp_chance;
             sstables.push(sstable);
+            live_names.push(name.clone());
+        }
+        if !has_manifest || live_names.len() != sstable_names.len() {
+            manifest.write(&live_names)?;
         }
+        Ok(sstables)
+    }
 
-        let database = Self {
-            wal: Arc::new(Mutex::new(match wal_path {
-                Some(path) => Wal::from_file(path.to_str().unwrap()),
-                None => Wal::new(format!("{}/wal_{}", data_dir, Uuid::new_v4()).as_str()),
-            })),
-            memtable: Arc::new(Mutex::new(MemTable::new())),
-            sstables: Arc::new(Mutex::new(sstables)),
-            sstable_compaction_threshold: 10,
-            data_dir: data_dir.to_string(),
-        };
+    /// Changes when writes are acknowledged relative to the WAL being fsynced.
+    pub fn set_wal_sync_mode(&mut self, mode: WalSyncMode) {
+        self.config.wal_sync_mode = mode;
+        self.wal_syncer = Arc::new(WalSyncer::new(mode, &self.wal));
+    }
+
+    /// Replaces the compaction strategy picked by the config, e.g. with a custom one. Jobs
+    /// queued before the call still use the old strategy.
+    pub async fn set_compaction_strategy(
+        &self,
+        strategy: impl CompactionStrategy + 'static,
+    ) -> Result<()> {
+        let _in_flight = self.start_operation().await?;
+        self.background_jobs
+            .send(BackgroundJob::SetCompactionStrategy(Box::new(strategy)))
+            .await
+            .map_err(|_| Error::BackgroundTaskStopped)
+    }
+
+    /// Waits until every flush and compaction queued so far has finished.
+    pub async fn wait_for_background_jobs(&self) -> Result<()> {
+        let _in_flight = self.start_operation().await?;
+        self.run_in_background(BackgroundJob::Wait).await
+    }
+
+    /// Closes the Database: waits for the operations already running and then for the
+    /// flushes and compactions they queued. Every operation after that fails with
+    /// `Error::Closed`, so nothing touches the data dir anymore.
+    pub async fn close(&self) -> Result<()> {
+        let mut closed = self.closed.write().await;
+        if *closed {
+            return Ok(());
+        }
+        *closed = true;
+        drop(closed);
+        self.run_in_background(BackgroundJob::Wait).await
+    }
 
-        database
-            .replay_from_wal(database.wal_path().await.as_str())
-            .await;
+    // Lets an operation run unless the Database is closed. It has to hold on to the guard
+    // until it's done, and mustn't call another operation that takes one meanwhile.
+    async fn start_operation(&self) -> Result<RwLockReadGuard<'_, bool>> {
+        let closed = self.closed.read().await;
+        if *closed {
+            return Err(Error::Closed);
+        }
+        Ok(closed)
+    }
 
-        Ok(database)
+    // Queues a job for the background task and waits for its result
+    async fn run_in_background(
+        &self,
+        job: impl FnOnce(oneshot::Sender<Result<()>>) -> BackgroundJob,
+    ) -> Result<()> {
+        let (reply, result) = oneshot::channel();
+        self.background_jobs
+            .send(job(reply))
+            .await
+            .map_err(|_| Error::BackgroundTaskStopped)?;
+        result.await.map_err(|_| Error::BackgroundTaskStopped)?
     }
 
     pub async fn wal_path(&self) -> String {
-        let foo = self.wal.lock().await;
-        foo.path()
+        let wal = self.wal.lock().await;
+        wal.path()
     }
 
-    /// Inserts a key-value pair into the MemTable.
-    pub async fn set(&self, key: String, value: String) {
-        // println!("set: Obtaining lock for memtable");
+    /// Inserts a key-value pair into the MemTable, timestamped with the current time.
+    pub async fn set(&self, key: String, value: String) -> Result<()> {
+        self.set_with_timestamp(key, value, next_timestamp()).await
+    }
+
+    /// Inserts a key-value pair with the given write timestamp, in microseconds since the
+    /// epoch. It only shows up in reads if no write to the key has a newer timestamp.
+    pub async fn set_with_timestamp(
+        &self,
+        key: String,
+        value: String,
+        timestamp: u64,
+    ) -> Result<()> {
+        let _in_flight = self.start_operation().await?;
         let mut memtable = self.memtable.lock().await;
-        // println!("set: Obtained lock for memtable");
-        // println!("set: Obtaining lock for wal");
         let mut wal = self.wal.lock().await;
-        // println!("set: Obtained lock for wal");
-        memtable.set(key, value, &mut wal);
-        if memtable.is_full() {
-            drop(memtable);
-            drop(wal);
-            self.flush_memtable_to_sstable().await.unwrap();
-            // println!("set: Obtaining lock for sstables");
-            let sstables = self.sstables.lock().await;
-            // println!("set: Obtained lock for sstables");
-            if sstables.len() >= self.sstable_compaction_threshold {
-                drop(sstables);
-                self.compact_sstables().await.unwrap();
-            }
+        memtable.set(key, value, timestamp, &mut wal)?;
+        let sequence = wal.last_sequence();
+        let memtable_is_full = memtable.is_full();
+        drop(memtable);
+        drop(wal);
+        self.after_write(sequence, memtable_is_full).await
+    }
+
+    /// Inserts a key-value pair that expires `ttl` seconds from now, after which reads
+    /// treat the key as deleted. A TTL of 0 means it never expires.
+    pub async fn set_with_ttl(&self, key: String, value: String, ttl: u32) -> Result<()> {
+        self.set_with_ttl_and_timestamp(key, value, ttl, next_timestamp())
+            .await
+    }
+
+    /// `set_with_ttl` with the given write timestamp, in microseconds since the epoch.
+    /// The TTL counts from now no matter what the timestamp says.
+    pub async fn set_with_ttl_and_timestamp(
+        &self,
+        key: String,
+        value: String,
+        ttl: u32,
+        timestamp: u64,
+    ) -> Result<()> {
+        let _in_flight = self.start_operation().await?;
+        let mut memtable = self.memtable.lock().await;
+        let mut wal = self.wal.lock().await;
+        memtable.set_with_ttl(key, value, ttl, timestamp, &mut wal)?;
+        let sequence = wal.last_sequence();
+        let memtable_is_full = memtable.is_full();
+        drop(memtable);
+        drop(wal);
+        self.after_write(sequence, memtable_is_full).await
+    }
+
+    /// Writes several cells holding the MemTable lock throughout, so reads see either all
+    /// of them or none. Each is logged on its own though, a crash can leave some of them
+    /// out of the WAL.
+    pub async fn write_cells(&self, cells: Vec<(String, Cell)>) -> Result<()> {
+        let _in_flight = self.start_operation().await?;
+        let mut memtable = self.memtable.lock().await;
+        let mut wal = self.wal.lock().await;
+        for (key, cell) in cells {
+            memtable.write(key, cell, &mut wal)?;
         }
+        let sequence = wal.last_sequence();
+        let memtable_is_full = memtable.is_full();
+        drop(memtable);
+        drop(wal);
+        self.after_write(sequence, memtable_is_full).await
+    }
+
+    // Waits for the write to be as durable as the WAL sync mode demands, then swaps the
+    // MemTable out and has the background task flush and compact if the write filled it up.
+    async fn after_write(&self, wal_sequence: u64, memtable_is_full: bool) -> Result<()> {
+        self.wal_syncer.wait_for(&self.wal, wal_sequence).await?;
+        if memtable_is_full && self.freeze_memtable(true).await? {
+            // waits while the queue is full, which holds writers back until the task catches up
+            self.background_jobs
+                .send(BackgroundJob::FlushAndCompact)
+                .await
+                .map_err(|_| Error::BackgroundTaskStopped)?;
+        }
+        Ok(())
+    }
+
+    // Moves the MemTable to the immutable MemTables and starts a new one along with a new
+    // WAL segment, so writes can go on while it's flushed. Waits if there are already
+    // `max_immutable_memtables` waiting. Returns false if there was nothing to swap out.
+    async fn freeze_memtable(&self, only_if_full: bool) -> Result<bool> {
+        let permit = self
+            .immutable_memtable_slots
+            .clone()
+            .acquire_owned()
+            .await
+            .map_err(|_| Error::BackgroundTaskStopped)?;
+
+        let mut memtable = self.memtable.lock().await;
+        // another writer may have gotten here first
+        if memtable.is_empty() || (only_if_full && !memtable.is_full()) {
+            return Ok(false);
+        }
+        // everything in the MemTable was logged to the segments before the new one
+        let wal_segment = self.wal.lock().await.rotate()?;
+        let full_memtable = std::mem::replace(
+            &mut *memtable,
+            MemTable::for_table(self.table_id, self.config.memtable_flush_threshold_bytes),
+        );
+        self.immutable_memtables
+            .lock()
+            .await
+            .push_back(ImmutableMemTable::new(full_memtable, wal_segment, permit));
+        Ok(true)
     }
 
     fn get_timestamp() -> u64 {
@@ -121,122 +420,571 @@ impl Database {
             .as_secs()
     }
 
-    pub async fn flush_memtable_to_sstable(&self) -> Result<()> {
-        let flush_start = std::time::Instant::now();
-        println!("flush_memtable_to_sstable: Flushing MemTable to SSTable");
-        // Create new SSTable
-        let uuid = Uuid::new_v4();
-        // println!("flush_memtable_to_sstable: obtain lock for sstables");
-        let mut sstables = self.sstables.lock().await;
-        // println!("flush_memtable_to_sstable: lock obtained for sstables");
-        let sstable_path = format!(
+    // sstable names are sstable_timestamp_uuid so we can sort them by timestamp
+    pub(crate) fn new_sstable_path(data_dir: &str) -> String {
+        format!(
             "{}/sstable_{}_{}",
-            self.data_dir,
+            data_dir,
             Database::get_timestamp(),
-            uuid
-        );
-        let mut sstable = SSTable::new(sstable_path.as_str()).await?;
+            Uuid::new_v4()
+        )
+    }
 
-        let every_n_entries = sstable.index_every_n_entries;
+    /// Flushes the MemTable, and any immutable ones still waiting, to new SSTables on the
+    /// background task and waits for it.
+    pub async fn flush_memtable_to_sstable(&self) -> Result<()> {
+        let _in_flight = self.start_operation().await?;
+        self.freeze_memtable(false).await?;
+        self.run_in_background(BackgroundJob::Flush).await
+    }
 
-        // println!("flush_memtable_to_sstable: obtain lock for memtable");
+    /// Replays a single WAL segment file into the MemTable.
+    pub async fn replay_from_wal(&self, path: &str) -> Result<()> {
+        let _in_flight = self.start_operation().await?;
+        let mut segment = WalSegment::open(path)?;
         let mut memtable = self.memtable.lock().await;
-        // println!("flush_memtable_to_sstable: lock obtained for memtable");
+        memtable.replay_wal_segment(&mut segment)
+    }
 
-        // MemTable data is already sorted if you are using a data structure like BTreeMap
-        let vec_of_operations = memtable.iter().collect::<Vec<(&String, &Operation)>>();
-        let offsets = sstable.batch_write(&vec_of_operations).await?;
+    pub async fn memtable_is_empty(&self) -> bool {
+        self.memtable.lock().await.is_empty()
+    }
 
-        // for every n entries, add element to index
-        for (i, (key, _)) in vec_of_operations.iter().enumerate() {
-            if i % every_n_entries == 0 {
-                sstable.write_to_index(key.to_string(), offsets[i] as u64);
+    pub async fn delete(&self, key: &str) -> Result<()> {
+        self.delete_with_timestamp(key, next_timestamp()).await
+    }
+
+    /// Deletes the key as of the given write timestamp, in microseconds since the epoch.
+    /// Writes to the key with a newer timestamp aren't affected.
+    pub async fn delete_with_timestamp(&self, key: &str, timestamp: u64) -> Result<()> {
+        let _in_flight = self.start_operation().await?;
+        let mut memtable = self.memtable.lock().await;
+        let mut wal = self.wal.lock().await;
+        memtable.delete(key, timestamp, &mut wal)?;
+        let sequence = wal.last_sequence();
+        let memtable_is_full = memtable.is_full();
+        drop(memtable);
+        drop(wal);
+        self.after_write(sequence, memtable_is_full).await
+    }
+
+    pub async fn delete_sstables(&self) -> Result<()> {
+        let _in_flight = self.start_operation().await?;
+        self.run_in_background(BackgroundJob::DeleteSSTables).await
+    }
+
+    /// Removes all the data. The MemTables are flushed first so none of it is left in the
+    /// WAL either, then every SSTable is deleted.
+    pub async fn truncate(&self) -> Result<()> {
+        let _in_flight = self.start_operation().await?;
+        self.freeze_memtable(false).await?;
+        self.run_in_background(BackgroundJob::Flush).await?;
+        self.run_in_background(BackgroundJob::DeleteSSTables).await
+    }
+
+    // Merge all SSTables into new ones to reduce the number of SSTables
+    // and improve read performance + reduce disk space usage (a major compaction).
+    // The merged output is split into SSTables of about `sstable_target_size_bytes`
+    // and goes in the deepest level any of the SSTables were in.
+    pub async fn compact_sstables(&self) -> Result<()> {
+        let _in_flight = self.start_operation().await?;
+        self.run_in_background(BackgroundJob::Compact).await
+    }
+
+    // Decides what happens to a read that got `error` from `sstable`: returns it if the
+    // corruption policy says the read fails, or lets the read go on without the table
+    async fn on_corruption(&self, sstable: &Arc<SSTable>, error: Error) -> Result<()> {
+        if !matches!(error, Error::Corruption { .. }) {
+            return Err(error);
+        }
+        match self.config.corruption_policy {
+            CorruptionPolicy::Fail => Err(error),
+            CorruptionPolicy::Skip => {
+                warn!("Skipping SSTable {}: {}", sstable.get_path(), error);
+                Ok(())
+            }
+            CorruptionPolicy::Quarantine => {
+                warn!("Quarantining SSTable {}: {}", sstable.get_path(), error);
+                self.background_jobs
+                    .send(BackgroundJob::Quarantine(sstable.clone()))
+                    .await
+                    .map_err(|_| Error::BackgroundTaskStopped)
             }
         }
+    }
+
+    // The immutable MemTables and the SSTables as they are right now, both oldest first.
+    // A flush moves a MemTable from one to the other in a single step, so taking both
+    // under the immutable MemTables lock never misses it or sees it half done.
+    async fn snapshot(&self) -> (Vec<Arc<MemTable>>, Vec<Arc<SSTable>>) {
+        let immutable_memtables = self.immutable_memtables.lock().await;
+        let sstables = self.sstables.lock().await.clone();
+        let memtables = immutable_memtables
+            .iter()
+            .map(|immutable| immutable.memtable.clone())
+            .collect();
+        (memtables, sstables)
+    }
+
+    /// Attempts to read a value for a given key from the database.
+    ///
+    /// 1. First checks the MemTable.
+    /// 2. Then the MemTables waiting to be flushed.
+    /// 3. Then each SSTable, unless what was found already is newer than anything in it.
+    ///
+    /// Of all the writes to the key the one with the highest timestamp wins, wherever it is.
+    /// Returns `Some(value)` if that's a value, `None` if it's a delete, has expired or there
+    /// are none.
+    pub async fn get(&self, key: &str) -> Result<Option<String>> {
+        let _in_flight = self.start_operation().await?;
+        // First, look for the key in the MemTable
+        let memtable = self.memtable.lock().await;
+        let mut newest = memtable.get(key).cloned();
 
-        // sstable.sync().await?;
+        // Read from a snapshot of the immutable MemTables and SSTables so flushes and
+        // compactions can swap tables in meanwhile
+        let (immutable_memtables, sstables) = self.snapshot().await;
+        drop(memtable);
 
-        // Optionally, write the index to a separate index file
-        sstable.write_index()?;
+        // Then in the MemTables waiting to be flushed (newest to oldest)
+        for memtable in immutable_memtables.iter().rev() {
+            if let Some(cell) = memtable.get(key) {
+                newest = newest.max(Some(cell.clone()));
+            }
+        }
 
-        // Add the SSTable to the list of SSTables managed by this Database instance
-        sstables.push(sstable);
-        drop(sstables);
+        // Then scan through each SSTable (newest to oldest)
+        for sstable in sstables.iter().rev() {
+            // a cell newer than anything in the table beats whatever the table has for the key
+            if matches!(&newest, Some(cell) if cell.timestamp > sstable.stats().max_timestamp) {
+                continue;
+            }
+            // tables in L1 and up don't overlap, so only one per level can hold the key
+            if sstable.level() > 0 && !sstable.covers_key(key) {
+                continue;
+            }
+            // the bloom filter lets us skip tables that can't contain the key without any disk I/O
+            if !sstable.may_contain(key) {
+                self.bloom_filter_metrics.record_miss();
+                continue;
+            }
+            self.bloom_filter_metrics.record_hit();
+            let found = match sstable.find_key(key).await {
+                Ok(found) => found,
+                Err(e) => {
+                    self.on_corruption(sstable, e).await?;
+                    continue;
+                }
+            };
+            match found {
+                Some(cell) => newest = newest.max(Some(cell)),
+                None => self.bloom_filter_metrics.record_false_positive(),
+            }
+        }
 
-        // Clear the MemTable
-        // println!("flush_memtable_to_sstable: obtain lock for wal");
-        let mut wal = self.wal.lock().await;
-        // println!("flush_memtable_to_sstable: lock obtained for wal");
-        memtable.clear(&mut wal)?;
+        // deleted, expired, or not found in either the MemTables or SSTables
+        let now = Database::get_timestamp();
+        Ok(newest.and_then(|cell| cell.operation.live_value(now).cloned()))
+    }
 
-        let flush_end = std::time::Instant::now();
+    /// Streams the live key-value pairs with keys in `range`, in key order, stopping after
+    /// `limit` pairs if one is given.
+    ///
+    /// The MemTable and SSTables are merged like in `compact_sstables`: for every key only
+    /// the write with the highest timestamp counts, and keys where that's a delete or has
+    /// expired are skipped.
+    /// The stream reads from the SSTables there were when it started, so it doesn't hold
+    /// up flushes or compactions and doesn't see their effects either. Closing the
+    /// Database doesn't wait for it, a stream that has started keeps going from what it
+    /// started with.
+    pub fn scan<K: AsRef<str>>(
+        &self,
+        range: impl RangeBounds<K>,
+        limit: Option<usize>,
+    ) -> impl Stream<Item = Result<(String, String)>> + '_ {
+        let start = owned_bound(range.start_bound());
+        let end = owned_bound(range.end_bound());
+        let limit = limit.unwrap_or(usize::MAX);
+        let now = Database::get_timestamp();
+
+        try_stream! {
+            let in_flight = self.start_operation().await?;
+            if limit == 0 {
+                return;
+            }
 
-        println!(
-            "flush_memtable_to_sstable: Flush took {}ms",
-            (flush_end - flush_start).as_millis()
-        );
+            // copy the MemTables' part of the range and snapshot the SSTables so neither
+            // writers nor flushes and compactions are blocked while we stream
+            let in_range = |memtable: &MemTable| {
+                memtable
+                    .range((start.clone(), Bound::Unbounded))
+                    .take_while(|(key, _)| is_before_end(key, &end))
+                    .map(|(key, cell)| (key.clone(), cell.clone()))
+                    .collect::<VecDeque<_>>()
+            };
+            let (memtable_entries, sstables) = {
+                let memtable = self.memtable.lock().await;
+                let (immutable_memtables, sstables) = self.snapshot().await;
+                // oldest first, the active MemTable last
+                let mut memtable_entries = immutable_memtables
+                    .iter()
+                    .map(|memtable| in_range(memtable))
+                    .collect::<Vec<_>>();
+                memtable_entries.push(in_range(&memtable));
+                (memtable_entries, sstables)
+            };
+            // the snapshot's SSTables keep their files open even once they're removed, so
+            // a consumer that never finishes the stream doesn't keep `close` waiting
+            drop(in_flight);
+
+            let start_key = match &start {
+                Bound::Included(key) | Bound::Excluded(key) => key.as_str(),
+                Bound::Unbounded => "",
+            };
+            // one source per SSTable plus the MemTables last, so a higher index is always newer
+            let mut sources = sstables
+                .iter()
+                .map(|sstable| ScanSource {
+                    buffered: VecDeque::new(),
+                    next_offset: Some(sstable.seek_offset(start_key)),
+                })
+                .collect::<Vec<_>>();
+            sources.extend(memtable_entries.into_iter().map(|entries| ScanSource {
+                buffered: entries,
+                next_offset: None,
+            }));
+
+            let mut keys_priority_queue = PriorityQueue::new();
+            let mut sources_to_advance = (0..sources.len()).collect::<Vec<_>>();
+            let mut returned = 0;
+            loop {
+                // keep the next entry of every source we took from in the queue
+                for i in sources_to_advance.drain(..) {
+                    let entry = match sources[i]
+                        .next_entry(sstables.get(i).map(|sstable| sstable.as_ref()), &start, &end)
+                        .await
+                    {
+                        Ok(entry) => entry,
+                        // only SSTables can fail, the MemTables are already in memory
+                        Err(e) => {
+                            self.on_corruption(&sstables[i], e).await?;
+                            None
+                        }
+                    };
+                    if let Some((key, cell)) = entry {
+                        let item = CompactionPriorityQueueItem {
+                            key,
+                            sstable_index: i,
+                            cell,
+                        };
+                        keys_priority_queue.push(item.clone(), item);
+                    }
+                }
 
-        Ok(())
+                let (_, item) = match keys_priority_queue.pop() {
+                    Some(next) => next,
+                    None => break,
+                };
+                sources_to_advance.push(item.sstable_index);
+                // older versions of the same key come out right after the newest one
+                while let Some((_, next_item)) = keys_priority_queue.peek() {
+                    if next_item.key != item.key {
+                        break;
+                    }
+                    let (_, older) = keys_priority_queue.pop().unwrap();
+                    sources_to_advance.push(older.sstable_index);
+                }
+
+                if let Some(value) = item.cell.operation.live_value(now) {
+                    yield (item.key, value.clone());
+                    returned += 1;
+                    if returned >= limit {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
+
+// What the background task can be asked to do. Jobs with a reply channel get their result
+// sent back once they're done.
+enum BackgroundJob {
+    // A write filled up the MemTable and swapped it out: flush it and compact
+    FlushAndCompact,
+    Flush(oneshot::Sender<Result<()>>),
+    Compact(oneshot::Sender<Result<()>>),
+    DeleteSSTables(oneshot::Sender<Result<()>>),
+    SetCompactionStrategy(Box<dyn CompactionStrategy>),
+    // A read found the SSTable to be corrupted: take it out and move its files aside
+    Quarantine(Arc<SSTable>),
+    // Done once every job queued before it is
+    Wait(oneshot::Sender<Result<()>>),
+}
+
+// Runs flushes and compactions one at a time, off the write path. It's the only thing that
+// changes the SSTable list, so a compaction can work from a snapshot of it.
+struct BackgroundWorker {
+    table_id: Uuid,
+    wal: Arc<Mutex<Wal>>,
+    memtable: Arc<Mutex<MemTable>>,
+    immutable_memtables: Arc<Mutex<VecDeque<ImmutableMemTable>>>,
+    sstables: Arc<Mutex<Vec<Arc<SSTable>>>>,
+    compaction_strategy: Box<dyn CompactionStrategy>,
+    manifest: Manifest,
+    config: DatabaseConfig,
+}
+
+impl BackgroundWorker {
+    // Takes jobs until the Database is dropped
+    async fn run(mut self, mut jobs: mpsc::Receiver<BackgroundJob>) {
+        while let Some(job) = jobs.recv().await {
+            match job {
+                BackgroundJob::FlushAndCompact => {
+                    if let Err(e) = self.flush_and_compact().await {
+                        error!("Flush or compaction failed: {}", e);
+                    }
+                }
+                BackgroundJob::Flush(reply) => {
+                    reply.send(self.flush_immutable_memtables().await).ok();
+                }
+                BackgroundJob::Compact(reply) => {
+                    reply.send(self.compact_sstables().await).ok();
+                }
+                BackgroundJob::DeleteSSTables(reply) => {
+                    reply.send(self.delete_sstables().await).ok();
+                }
+                BackgroundJob::SetCompactionStrategy(strategy) => {
+                    self.compaction_strategy = strategy;
+                }
+                BackgroundJob::Quarantine(sstable) => {
+                    if let Err(e) = self.quarantine(&sstable).await {
+                        error!("Quarantining failed: {}", e);
+                    }
+                }
+                BackgroundJob::Wait(reply) => {
+                    reply.send(Ok(())).ok();
+                }
+            }
+        }
     }
 
-    pub async fn replay_from_wal(&self, path: &str) {
-        let mut wal = Wal::from_file(path);
-        let mut memtable = self.memtable.lock().await;
-        memtable.replay_wal(&mut wal);
+    // Writes the SSTable list that's about to go live to the manifest. Has to happen before
+    // it's swapped in and before any replaced files are deleted.
+    fn record_sstables(&self, sstables: &[Arc<SSTable>]) -> Result<()> {
+        let names = sstables
+            .iter()
+            .map(|sstable| sstable.file_name())
+            .collect::<Vec<_>>();
+        self.manifest.write(&names)
     }
 
-    pub async fn memtable_is_empty(&self) -> bool {
-        self.memtable.lock().await.is_empty()
+    async fn flush_and_compact(&self) -> Result<()> {
+        self.flush_immutable_memtables().await?;
+        if self.compaction_strategy.drops_expired_sstables() {
+            self.drop_expired_sstables().await?;
+        }
+        let sstables = self.sstables.lock().await.clone();
+        if let Some(task) = self.compaction_strategy.next_compaction(&sstables) {
+            self.compact(&sstables, task).await?;
+        }
+        Ok(())
     }
 
-    pub async fn delete(&self, key: &String) {
-        let mut memtable = self.memtable.lock().await;
-        let mut wal = self.wal.lock().await;
-        memtable.delete(key, &mut wal);
-        if memtable.is_full() {
-            drop(memtable);
-            self.flush_memtable_to_sstable().await.unwrap();
-            // println!("delete: Obtaining lock for sstables");
-            let sstables = self.sstables.lock().await;
-            // println!("delete: Obtained lock for sstables");
-            if sstables.len() >= self.sstable_compaction_threshold {
-                drop(sstables);
-                self.compact_sstables().await.unwrap();
+    // Flushes the immutable MemTables, oldest first, until there are none left
+    async fn flush_immutable_memtables(&self) -> Result<()> {
+        loop {
+            let memtable = match self.immutable_memtables.lock().await.front() {
+                Some(immutable) => immutable.memtable.clone(),
+                None => return Ok(()),
+            };
+            self.flush_memtable_to_sstable(&memtable).await?;
+        }
+    }
+
+    // Writes the oldest immutable MemTable to a new SSTable, which then takes its place
+    async fn flush_memtable_to_sstable(&self, memtable: &MemTable) -> Result<()> {
+        let flush_start = std::time::Instant::now();
+        debug!("Flushing MemTable to SSTable");
+        // Create new SSTable
+        let sstable_path = Database::new_sstable_path(&self.config.data_dir);
+        let mut sstable =
+            SSTable::with_compression(sstable_path.as_str(), self.config.sstable_compression)
+                .await?;
+        sstable.index_every_n_entries = self.config.index_every_n_entries;
+        sstable.bloom_filter_fp_chance = self.config.bloom_filter_fp_chance;
+        sstable.block_size_bytes = self.config.sstable_block_size_bytes;
+
+        let every_n_entries = sstable.index_every_n_entries;
+
+        // MemTable data is already sorted if you are using a data structure like BTreeMap
+        let vec_of_operations = memtable.iter().collect::<Vec<(&String, &Cell)>>();
+        let offsets = sstable.batch_write(&vec_of_operations).await?;
+
+        // for every n entries, add element to index, and every key to the bloom filter
+        let mut bloom_filter =
+            BloomFilter::new(vec_of_operations.len(), sstable.bloom_filter_fp_chance);
+        for (i, (key, _)) in vec_of_operations.iter().enumerate() {
+            if i % every_n_entries == 0 {
+                sstable.write_to_index(key.to_string(), offsets[i] as u64);
             }
+            bloom_filter.insert(key);
         }
+        sstable.set_bloom_filter(bloom_filter);
+        sstable.set_max_write_time(Database::get_timestamp());
+
+        // Persist the sparse index, bloom filter and stats at the end of the file so they
+        // survive restarts
+        sstable.finish().await?;
+
+        let sstable = Arc::new(sstable);
+        let mut live_sstables = self.sstables.lock().await.clone();
+        live_sstables.push(sstable.clone());
+        self.record_sstables(&live_sstables)?;
+
+        // Add the SSTable to the list of SSTables managed by this Database instance, in the
+        // same step as the MemTable leaves the queue so reads always see one or the other
+        let active_memtable = self.memtable.lock().await;
+        let mut immutable_memtables = self.immutable_memtables.lock().await;
+        self.sstables.lock().await.push(sstable);
+        let flushed = immutable_memtables.pop_front().unwrap();
+        let unflushed = !immutable_memtables.is_empty() || !active_memtable.is_empty();
+        drop(immutable_memtables);
+
+        // Its WAL segments aren't needed now that the SSTable is on disk, what's left of the
+        // table was logged after it was swapped out. Writers are held off by the MemTable
+        // lock meanwhile, so one can't log a record the WAL is then told isn't needed.
+        self.wal
+            .lock()
+            .await
+            .mark_flushed(self.table_id, flushed.wal_segment, unflushed)?;
+        drop(active_memtable);
+
+        let flush_end = std::time::Instant::now();
+
+        debug!("Flush took {}ms", (flush_end - flush_start).as_millis());
+
+        Ok(())
     }
 
-    pub async fn delete_sstables(&self) -> Result<()> {
+    async fn delete_sstables(&self) -> Result<()> {
+        self.record_sstables(&[])?;
         let mut sstables = self.sstables.lock().await;
-        let paths = sstables
+        for sstable in sstables.drain(..) {
+            sstable.remove_files()?;
+        }
+
+        Ok(())
+    }
+
+    // A major compaction of all the SSTables there are right now
+    async fn compact_sstables(&self) -> Result<()> {
+        let sstables = self.sstables.lock().await.clone();
+        let task = CompactionTask {
+            inputs: (0..sstables.len()).collect(),
+            split_output: true,
+            output_level: sstables
+                .iter()
+                .map(|sstable| sstable.level())
+                .max()
+                .unwrap_or(0),
+        };
+        self.compact(&sstables, task).await
+    }
+
+    // Takes a corrupted SSTable out of the live list and moves its files to the quarantine
+    // directory. Several reads may have asked for the same table, it's only moved once.
+    async fn quarantine(&self, sstable: &Arc<SSTable>) -> Result<()> {
+        let mut live_sstables = self.sstables.lock().await.clone();
+        let Some(position) = live_sstables
             .iter()
-            .map(|sstable| sstable.get_path())
-            .collect::<Vec<String>>();
-        sstables.clear();
-        for path in paths {
-            std::fs::remove_file(path)?;
+            .position(|live| Arc::ptr_eq(live, sstable))
+        else {
+            return Ok(());
+        };
+        live_sstables.remove(position);
+        self.record_sstables(&live_sstables)?;
+        *self.sstables.lock().await = live_sstables;
+        sstable.move_files(&format!("{}/quarantine", self.config.data_dir))
+    }
+
+    // Removes SSTables whose every entry is a tombstone past gc_grace_seconds, as long as
+    // no other SSTable or MemTable may have a cell for one of their keys that they're still
+    // hiding. With write timestamps that cell can be in a newer table as well as an older one.
+    async fn drop_expired_sstables(&self) -> Result<()> {
+        let sstables = self.sstables.lock().await.clone();
+        let now = Database::get_timestamp();
+        let mut expired = vec![false; sstables.len()];
+        for (i, sstable) in sstables.iter().enumerate() {
+            if sstable
+                .max_deletion_time()
+                .saturating_add(self.config.gc_grace_seconds)
+                > now
+            {
+                continue;
+            }
+            let others = sstables
+                .iter()
+                .enumerate()
+                .filter(|(j, other)| {
+                    *j != i && !expired[*j] && overlaps(sstable.key_range(), other.key_range())
+                })
+                .map(|(_, other)| other)
+                .collect::<Vec<_>>();
+            let mut hides_nothing = true;
+            for (key, _) in sstable.read_all().await? {
+                if others.iter().any(|other| other.may_contain(&key))
+                    || self.memtables_contain(&key).await
+                {
+                    hides_nothing = false;
+                    break;
+                }
+            }
+            expired[i] = hides_nothing;
+        }
+        let mut live_sstables = Vec::with_capacity(sstables.len());
+        let mut expired_sstables = Vec::new();
+        for (sstable, expired) in sstables.into_iter().zip(expired) {
+            match expired {
+                true => expired_sstables.push(sstable),
+                false => live_sstables.push(sstable),
+            }
+        }
+        if expired_sstables.is_empty() {
+            return Ok(());
         }
 
+        self.record_sstables(&live_sstables)?;
+        *self.sstables.lock().await = live_sstables;
+        for sstable in expired_sstables {
+            info!("Dropping expired SSTable {}", sstable.get_path());
+            sstable.remove_files()?;
+        }
         Ok(())
     }
 
-    // Merge old SSTables into a new SSTable to reduce the number of SSTables
-    // and improve read performance + reduce disk space usage.
-    pub async fn compact_sstables(&self) -> Result<()> {
+    // Whether the MemTable or one waiting to be flushed has a cell for the key
+    async fn memtables_contain(&self, key: &str) -> bool {
+        if self.memtable.lock().await.get(key).is_some() {
+            return true;
+        }
+        self.immutable_memtables
+            .lock()
+            .await
+            .iter()
+            .any(|immutable| immutable.memtable.get(key).is_some())
+    }
+
+    // Merges the SSTables the task picked out of the `sstables` snapshot into new ones,
+    // which then take their place in the live list. The merged output is written as it's
+    // produced and reads go on meanwhile, the list is only locked to swap the tables.
+    async fn compact(&self, sstables: &[Arc<SSTable>], task: CompactionTask) -> Result<()> {
+        let inputs = task.inputs.clone();
         // time how much compaction takes
         let start = std::time::Instant::now();
-        println!("Compacting SSTables");
+        info!("Compacting SSTables {:?}", inputs);
 
         let mut keys_priority_queue = PriorityQueue::new();
-        let uuid = Uuid::new_v4();
-
-        let sstable_path = format!(
-            "{}/sstable_{}_{}",
-            self.data_dir,
-            Database::get_timestamp(),
-            uuid
-        );
-        let mut new_sstable = SSTable::new(sstable_path.as_str()).await?;
 
         // we can iterate through sstable entries in order because they are sorted by key
         // for this implementation lets iterate through all of them and write them to a new sstable
@@ -245,74 +993,74 @@ impl Database {
         // we need to keep track of the current key we are looking at in each sstable
         // we can use a HashMap to keep track of the current key for each sstable
         // and the current offset in the sstable
-        let mut sstables = self.sstables.lock().await;
-        let mut current_sstables = sstables
+        let mut current_sstables = inputs.iter().copied().collect::<HashSet<_>>();
+        let expected_entries = inputs
+            .iter()
+            .map(|i| sstables[*i].stats().entry_count as usize)
+            .sum();
+        let max_write_time = inputs
             .iter()
-            .enumerate()
-            .map(|(i, _)| i)
-            .collect::<HashSet<_>>();
-        let mut final_ops = Vec::new();
+            .map(|i| sstables[*i].max_write_time())
+            .max()
+            .unwrap_or(0);
+        let now = Database::get_timestamp();
+        let mut writer =
+            CompactionWriter::new(&self.config, expected_entries, max_write_time, &task);
 
         // an sstable has a read_item_at() method that you can pass a byte offset, it returns the next offset to read from
         // async iterators aren't a stable feature so not using them for that reason
 
-        let mut read_indexes = sstables.iter().map(|_| 0).collect::<Vec<usize>>();
+        let mut read_indexes = sstables
+            .iter()
+            .map(|sstable| sstable.data_start())
+            .collect::<Vec<usize>>();
 
         let mut ops_in_queue_per_sstable = sstables.iter().map(|_| 0).collect::<Vec<usize>>();
 
         // while there are still sstables with entries
-        while current_sstables.len() > 0 {
-            if keys_priority_queue.len() == 0 {
+        while !current_sstables.is_empty() {
+            if keys_priority_queue.is_empty() {
                 // initialize the current key and offset for each sstable
-                for (i, table) in sstables.iter_mut().enumerate() {
+                for (i, table) in sstables.iter().enumerate() {
                     if !current_sstables.contains(&i) {
                         continue;
                     }
-                    // println!("Reading this doofus: {:?}", i);
-                    match table.batch_read(10, read_indexes[i]).await {
-                        Err(e) => panic!("Error reading SSTable: {}", e),
-                        Ok((tuples, new_offset)) => {
-                            if tuples.len() == 0 {
-                                current_sstables.remove(&i);
-                                continue;
-                            }
-                            ops_in_queue_per_sstable[i] += tuples.len();
-                            for (key, operation) in tuples {
-                                // println!("Adding to queue: {:?}", key);
-                                let item = CompactionPriorityQueueItem {
-                                    key: key.clone(),
-                                    sstable_index: i,
-                                    operation: operation.clone(),
-                                };
-                                keys_priority_queue.push(item.clone(), item);
-                            }
-                            read_indexes[i] = new_offset;
-                            current_sstables.insert(i);
-                        }
+                    let (tuples, new_offset) = table.batch_read(10, read_indexes[i]).await?;
+                    if tuples.is_empty() {
+                        current_sstables.remove(&i);
+                        continue;
+                    }
+                    ops_in_queue_per_sstable[i] += tuples.len();
+                    for (key, cell) in tuples {
+                        let item = CompactionPriorityQueueItem {
+                            key: key.clone(),
+                            sstable_index: i,
+                            cell: cell.clone(),
+                        };
+                        keys_priority_queue.push(item.clone(), item);
                     }
+                    read_indexes[i] = new_offset;
+                    current_sstables.insert(i);
                 }
             }
 
             // there might be no more entries in any sstable even though they were in current_sstables at the start of the loop
-            if keys_priority_queue.len() == 0 {
+            if keys_priority_queue.is_empty() {
                 break;
             }
             // find the sstable and operation associated with the smallest key
             let (_, item) = keys_priority_queue.pop().unwrap();
-            // println!("Item from queue: {:?}", item);
-            // println!("Items in queue per sstable: {:?}", ops_in_queue_per_sstable);
-            // println!("Current sstables: {:?}", current_sstables);
-            // println!("Smallest key ssstable: {:?}", item.sstable_index);
-            // println!("Queue: {:?}", keys_priority_queue.len());
+            ops_in_queue_per_sstable[item.sstable_index] -= 1;
+            let mut drained_sstables = vec![item.sstable_index];
             loop {
-                // pop same items since they are duplicates and we are ordering by newest sstable first
+                // pop same items since they are duplicates and we are ordering by newest cell first
                 let next = keys_priority_queue.peek();
                 match next {
                     Some((_, next_item)) => {
                         if next_item.key == item.key {
                             let item = keys_priority_queue.pop().unwrap();
-                            // println!("Dropping duplicate: {:?}", item);
                             ops_in_queue_per_sstable[item.0.sstable_index] -= 1;
+                            drained_sstables.push(item.0.sstable_index);
                         } else {
                             break;
                         }
@@ -321,124 +1069,176 @@ impl Database {
                 }
             }
 
-            // get the smallest key's operation
-            let smallest_key_sstable = item.sstable_index;
+            // get the smallest key's newest cell. A value whose TTL has run out is just a
+            // tombstone from here on.
             let smallest_key = item.key;
-            let smallest_key_operation = item.operation;
-            // write the smallest key and operation to final_ops
-            final_ops.push((smallest_key, smallest_key_operation));
+            let smallest_key_cell = Cell {
+                operation: item.cell.operation.expire(now),
+                ..item.cell
+            };
+
+            // a tombstone can go once it's past gc_grace_seconds, unless an sstable that isn't
+            // part of this compaction or a MemTable may still have a value it's hiding. With
+            // write timestamps that value can be in a newer sstable or a MemTable as well as
+            // an older sstable.
+            let purge = match smallest_key_cell.operation {
+                Operation::Delete(deleted_at) => {
+                    deleted_at.saturating_add(self.config.gc_grace_seconds) <= now
+                        && (0..sstables.len())
+                            .all(|i| inputs.contains(&i) || !sstables[i].may_contain(&smallest_key))
+                        && !self.memtables_contain(&smallest_key).await
+                }
+                Operation::Insert(_) | Operation::Expiring { .. } => false,
+            };
 
-            // if the sstable that has the smallest key has no more entries in the pq currently, load more entries
+            // write the smallest key and its cell to the new sstables
+            if !purge {
+                writer.add(smallest_key, smallest_key_cell).await?;
+            }
+
+            // if a sstable we just took from has no more entries in the pq currently, load more entries,
+            // otherwise its next keys could be smaller than what's left in the pq
             // if there aren't any more to load, remove it from current_sstables
-            ops_in_queue_per_sstable[smallest_key_sstable] -= 1;
-            if ops_in_queue_per_sstable[smallest_key_sstable] == 0 {
-                // println!("Reading this dingus: {:?}", smallest_key_sstable);
-                match sstables[smallest_key_sstable]
-                    .batch_read(10, read_indexes[smallest_key_sstable])
-                    .await
-                {
-                    Err(e) => panic!("Error reading SSTable: {}", e),
-                    Ok((tuples, new_offset)) => {
-                        if tuples.len() == 0 {
-                            current_sstables.remove(&smallest_key_sstable);
-                            continue;
-                        }
-                        ops_in_queue_per_sstable[smallest_key_sstable] += tuples.len();
-                        for (key, operation) in tuples {
-                            let item = CompactionPriorityQueueItem {
-                                key: key.clone(),
-                                sstable_index: smallest_key_sstable,
-                                operation: operation.clone(),
-                            };
-                            keys_priority_queue.push(item.clone(), item);
-                        }
-                        read_indexes[smallest_key_sstable] = new_offset;
-                        current_sstables.insert(smallest_key_sstable);
-                    }
+            for i in drained_sstables {
+                if ops_in_queue_per_sstable[i] > 0 {
+                    continue;
+                }
+                let (tuples, new_offset) = sstables[i].batch_read(10, read_indexes[i]).await?;
+                if tuples.is_empty() {
+                    current_sstables.remove(&i);
+                    continue;
+                }
+                ops_in_queue_per_sstable[i] += tuples.len();
+                for (key, cell) in tuples {
+                    let item = CompactionPriorityQueueItem {
+                        key: key.clone(),
+                        sstable_index: i,
+                        cell: cell.clone(),
+                    };
+                    keys_priority_queue.push(item.clone(), item);
                 }
+                read_indexes[i] = new_offset;
+                current_sstables.insert(i);
             }
         }
 
-        let every_n_entries = new_sstable.index_every_n_entries;
+        let new_sstables = writer.finish().await?;
 
-        let vec_of_operations = final_ops
+        // Swap the new SSTables in where the oldest input was and delete the old ones. Only
+        // this task changes the list, but look the inputs up anyway instead of trusting indexes.
+        let inputs = inputs
             .iter()
-            .map(|(key, op)| (key, op))
+            .map(|i| sstables[*i].clone())
             .collect::<Vec<_>>();
-
-        let offsets = new_sstable.batch_write(&vec_of_operations).await?;
-
-        // for every n entries, add element to index
-        for (i, (key, _)) in final_ops.iter().enumerate() {
-            if i % every_n_entries == 0 {
-                new_sstable.write_to_index(key.to_string(), offsets[i] as u64);
-            }
-        }
-
-        // Delete old SSTables
-        let sstable_paths = sstables
+        let mut live_sstables = self.sstables.lock().await.clone();
+        let position = live_sstables
             .iter()
-            .map(|sstable| sstable.get_path())
-            .collect::<Vec<String>>();
-        sstables.clear();
-        for path in sstable_paths {
-            std::fs::remove_file(path).unwrap_or(());
+            .position(|sstable| inputs.iter().any(|input| Arc::ptr_eq(input, sstable)))
+            .unwrap_or(live_sstables.len());
+        live_sstables.retain(|sstable| !inputs.iter().any(|input| Arc::ptr_eq(input, sstable)));
+        live_sstables.splice(position..position, new_sstables.into_iter().map(Arc::new));
+        sort_by_level(&mut live_sstables);
+        // once the manifest has the new list, a crash before the old files are gone
+        // just leaves orphans for the next load to clean up
+        self.record_sstables(&live_sstables)?;
+        *self.sstables.lock().await = live_sstables;
+        // readers that still hold the old tables keep their open file handles
+        for sstable in inputs {
+            sstable.remove_files().unwrap_or(());
         }
-        sstables.push(new_sstable);
 
         let end = std::time::Instant::now();
 
-        println!("Compaction took {}ms", (end - start).as_millis());
+        info!("Compaction took {}ms", (end - start).as_millis());
 
         Ok(())
     }
+}
 
-    /// Attempts to read a value for a given key from the database.
-    ///
-    /// 1. First checks the MemTable.
-    /// 2. If not found in the MemTable, checks each SSTable.
-    ///
-    /// Returns `Some(value)` if found, `None` otherwise.
-    pub async fn get(&self, key: &str) -> Option<String> {
-        // First, look for the key in the MemTable
-        let memtable = self.memtable.lock().await;
-        match memtable.get(key) {
-            Some(Operation::Insert(value)) => {
-                println!("get: Found key in memtable");
-                return Some(value.clone());
-            }
-            Some(Operation::Delete) => {
-                println!("get: Found key in memtable but it was deleted");
-                return None;
-            }
-            None => {
-                println!("get: Key not found in memtable");
-            }
-        }
+// Where a scan is in one SSTable (or the MemTable, which has no offset and is fully buffered)
+struct ScanSource {
+    buffered: VecDeque<(String, Cell)>,
+    next_offset: Option<usize>,
+}
 
-        // println!("get: Obtaining lock for sstables");
-        let mut sstables = self.sstables.lock().await;
-        // println!("get: Obtained lock for sstables");
-        // If the key is not in the MemTable, scan through each SSTable (newest to oldest)
-        for (i, sstable) in sstables.iter_mut().rev().enumerate() {
-            match sstable.find_key(key).await {
-                Ok(Some(Operation::Insert(value))) => {
-                    println!("get: Found key in sstable {}", i);
-                    return Some(value);
+impl ScanSource {
+    // Returns the next entry in the range, reading ahead from the SSTable a batch at a time
+    async fn next_entry(
+        &mut self,
+        sstable: Option<&SSTable>,
+        start: &Bound<String>,
+        end: &Bound<String>,
+    ) -> Result<Option<(String, Cell)>> {
+        loop {
+            if let Some((key, cell)) = self.buffered.pop_front() {
+                if !is_after_start(&key, start) {
+                    continue;
                 }
-                Ok(Some(Operation::Delete)) => {
-                    println!("get: Found tombstone in sstable {}", i);
-                    return None;
+                if !is_before_end(&key, end) {
+                    // keys are sorted, nothing further in this source is in range
+                    self.buffered.clear();
+                    self.next_offset = None;
+                    return Ok(None);
                 }
-                Ok(None) => {
-                    println!("get: Key not found in sstable {}", i);
-                }
-                Err(e) => panic!("Error reading SSTable: {}", e),
+                return Ok(Some((key, cell)));
+            }
+
+            let (offset, sstable) = match (self.next_offset, sstable) {
+                (Some(offset), Some(sstable)) => (offset, sstable),
+                _ => return Ok(None),
+            };
+            let (entries, new_offset) = sstable.batch_read(10, offset).await?;
+            if entries.is_empty() {
+                self.next_offset = None;
+                return Ok(None);
             }
+            self.buffered.extend(entries);
+            self.next_offset = Some(new_offset);
         }
+    }
+}
 
-        // If the key was not found in either the MemTable or SSTables
-        None
+// Opens the WAL in the configured data dir and its syncer
+pub(crate) fn open_wal(config: &DatabaseConfig) -> Result<(Arc<Mutex<Wal>>, Arc<WalSyncer>)> {
+    let mut wal = Wal::open(&config.data_dir)?;
+    wal.segment_size_bytes = config.wal_segment_size_bytes;
+    let wal = Arc::new(Mutex::new(wal));
+    let wal_syncer = Arc::new(WalSyncer::new(config.wal_sync_mode, &wal));
+    Ok((wal, wal_syncer))
+}
+
+// Deeper levels hold older data, so they go first. L0 tables stay in the order they were
+// flushed in, the rest are sorted by key since they don't overlap within a level.
+fn sort_by_level(sstables: &mut [Arc<SSTable>]) {
+    sstables.sort_by(|a, b| {
+        b.level().cmp(&a.level()).then_with(|| match a.level() {
+            0 => std::cmp::Ordering::Equal,
+            _ => a.key_range().cmp(&b.key_range()),
+        })
+    });
+}
+
+fn owned_bound<K: AsRef<str>>(bound: Bound<&K>) -> Bound<String> {
+    match bound {
+        Bound::Included(key) => Bound::Included(key.as_ref().to_string()),
+        Bound::Excluded(key) => Bound::Excluded(key.as_ref().to_string()),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+fn is_after_start(key: &str, start: &Bound<String>) -> bool {
+    match start {
+        Bound::Included(start) => key >= start.as_str(),
+        Bound::Excluded(start) => key > start.as_str(),
+        Bound::Unbounded => true,
+    }
+}
+
+fn is_before_end(key: &str, end: &Bound<String>) -> bool {
+    match end {
+        Bound::Included(end) => key <= end.as_str(),
+        Bound::Excluded(end) => key < end.as_str(),
+        Bound::Unbounded => true,
     }
 }
 
@@ -446,13 +1246,17 @@ impl Database {
 pub struct CompactionPriorityQueueItem {
     key: String,
     sstable_index: usize,
-    operation: Operation,
+    cell: Cell,
 }
 
+// Smallest key first, and for the same key the newest cell first
 impl Ord for CompactionPriorityQueueItem {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
         match self.key.cmp(&other.key) {
-            std::cmp::Ordering::Equal => self.sstable_index.cmp(&other.sstable_index),
+            std::cmp::Ordering::Equal => self
+                .cell
+                .cmp(&other.cell)
+                .then(self.sstable_index.cmp(&other.sstable_index)),
             ordering => ordering.reverse(),
         }
     }
@@ -474,12 +1278,12 @@ mod tests {
         let item1 = CompactionPriorityQueueItem {
             key: "aaa".to_string(),
             sstable_index: 0,
-            operation: Operation::Insert("aaa".to_string()),
+            cell: Cell::new(Operation::Insert("aaa".to_string()), 1),
         };
         let item2 = CompactionPriorityQueueItem {
             key: "bbb".to_string(),
             sstable_index: 0,
-            operation: Operation::Insert("bbb".to_string()),
+            cell: Cell::new(Operation::Insert("bbb".to_string()), 1),
         };
 
         assert!(item1 > item2);
@@ -487,19 +1291,19 @@ mod tests {
         let item1 = CompactionPriorityQueueItem {
             key: "bbb".to_string(),
             sstable_index: 0,
-            operation: Operation::Insert("bbb".to_string()),
+            cell: Cell::new(Operation::Insert("bbb".to_string()), 1),
         };
 
         let item2 = CompactionPriorityQueueItem {
             key: "bbb".to_string(),
             sstable_index: 1,
-            operation: Operation::Insert("bbb".to_string()),
+            cell: Cell::new(Operation::Insert("bbb".to_string()), 1),
         };
 
         let item3 = CompactionPriorityQueueItem {
             key: "aaa".to_string(),
             sstable_index: 0,
-            operation: Operation::Insert("aaa".to_string()),
+            cell: Cell::new(Operation::Insert("aaa".to_string()), 1),
         };
 
         assert!(item1 < item2);
@@ -514,5 +1318,18 @@ mod tests {
         assert_eq!(pq.pop().unwrap(), (2, item3));
         assert_eq!(pq.pop().unwrap(), (1, item2));
         assert_eq!(pq.pop().unwrap(), (0, item1));
+
+        // a newer cell wins over a newer sstable
+        let older = CompactionPriorityQueueItem {
+            key: "bbb".to_string(),
+            sstable_index: 1,
+            cell: Cell::new(Operation::Insert("old".to_string()), 1),
+        };
+        let newer = CompactionPriorityQueueItem {
+            key: "bbb".to_string(),
+            sstable_index: 0,
+            cell: Cell::new(Operation::Insert("new".to_string()), 2),
+        };
+        assert!(newer > older);
     }
 }
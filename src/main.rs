@@ -1,7 +1,7 @@
 use std::{sync::Arc, time::Duration};
 
-use kassantra::ql::parser::Operation;
-use kassantra::Database;
+use kassantra::query::execute_request;
+use kassantra::{DatabaseConfig, Node};
 use rand::Rng;
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
@@ -12,12 +12,28 @@ use tokio::{
 #[tokio::main]
 async fn main() {
     // console_subscriber::init();
+    // what the engine logs goes to stderr, at the level RUST_LOG sets (info by default)
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
     // get the first commandline argument, if it's 'client' execute run_client, otherwise execute run_server
+    // the server takes an optional `--config path` pointing to a TOML or JSON config file
     let args: Vec<String> = std::env::args().collect();
     if args.len() > 1 && args[1] == "client" {
         run_client().await;
     } else {
-        run_server().await;
+        let config = match args.iter().position(|arg| arg == "--config") {
+            Some(i) => match args.get(i + 1) {
+                Some(path) => DatabaseConfig::from_file(path).unwrap_or_else(|e| {
+                    eprintln!("Could not load config {}: {}", path, e);
+                    std::process::exit(1);
+                }),
+                None => {
+                    eprintln!("Usage: kassantra [--config path]");
+                    std::process::exit(1);
+                }
+            },
+            None => DatabaseConfig::default(),
+        };
+        run_server(config).await;
     }
 }
 
@@ -59,7 +75,7 @@ async fn run_client() {
             "INSERT INTO the_table ({}) VALUES (\"{}\");\n",
             random_three_letter_key, random_three_letter_value
         );
-        stream.write(insert_command.as_bytes()).await.unwrap();
+        stream.write_all(insert_command.as_bytes()).await.unwrap();
         let mut buf = [0; 1024];
         let n = stream.read(&mut buf).await.unwrap();
         let buf_to_string = String::from_utf8(buf[0..n].to_vec()).unwrap();
@@ -72,8 +88,8 @@ async fn run_client() {
     }
 }
 
-async fn run_server() {
-    let database = Arc::new(Database::load("data").await.unwrap());
+async fn run_server(config: DatabaseConfig) {
+    let node = Arc::new(Node::load(config).await.unwrap());
     let port_from_env = std::env::var("PORT").unwrap_or("8080".to_string());
     let listener = TcpListener::bind(format!("127.0.0.1:{}", port_from_env))
         .await
@@ -82,49 +98,18 @@ async fn run_server() {
 
     loop {
         let (mut socket, _) = listener.accept().await.unwrap();
-        let database_clone = database.clone(); // this clones the Arc, not the Database
-                                               // println!("Got a connection from {:?}", socket.peer_addr());
+        let node_clone = node.clone(); // this clones the Arc, not the Node
+                                       // println!("Got a connection from {:?}", socket.peer_addr());
         tokio::spawn(async move {
             // read everything that is sent to the socket, but no extra bytes
             let mut buf = [0; 1024];
             let n = socket.read(&mut buf).await.unwrap();
-            let buf_to_string = String::from_utf8(buf[0..n].to_vec());
-
-            if buf_to_string.is_err() {
-                println!("Error: {}", buf_to_string.err().unwrap());
-                return;
-            }
-
-            let buf_to_string = buf_to_string.unwrap();
-
-            // ql::parser::Operation implements the from_str trait
-            let response = match Operation::from_str(buf_to_string.as_ref()) {
-                Ok(operation) => match operation {
-                    Operation::Insert(key, value) => {
-                        // println!("Inserting key: {}, value: {}", key, value);
-                        database_clone.set(key, value).await;
-                        // println!("Response: OK");
-                        Ok("OK".to_string())
-                    }
-                    Operation::Select(key) => match database_clone.get(&key).await {
-                        Some(value) => Ok(value),
-                        None => Ok("Key not found".to_string()),
-                    },
-                    Operation::Delete(key) => {
-                        database_clone.delete(&key).await;
-                        Ok("OK".to_string())
-                    }
-                },
-                Err(e) => Err(format!("Error: {}", e)),
-            };
-            match response {
-                Ok(response) => {
-                    socket.write(response.as_bytes()).await.unwrap();
-                }
-                Err(e) => {
-                    socket.write(e.as_bytes()).await.unwrap();
-                }
-            }
+            let response = execute_request(&node_clone, &buf[0..n]).await;
+            let response = response.unwrap_or_else(|e| {
+                println!("Error: {}", e);
+                format!("Error: {}", e)
+            });
+            socket.write_all(response.as_bytes()).await.unwrap();
         });
     }
 }
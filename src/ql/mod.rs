@@ -1 +1 @@
-pub mod parser;
\ No newline at end of file
+pub mod parser;
@@ -1,23 +1,158 @@
 use nom::{
     self, branch,
-    bytes::complete::{self, tag},
-    character::complete::{line_ending, space0, space1},
+    bytes::complete::{self, tag, tag_no_case, take_while1, take_while_m_n},
+    character::complete::{digit1, line_ending, space0, space1},
+    combinator::{map, map_opt, map_res, opt},
+    multi::separated_list1,
+    number::complete::recognize_float,
+    sequence::{delimited, preceded, terminated, tuple},
 };
+use uuid::Uuid;
 
-// This is going to be a toy version of CQL. All inserts and deletes will go to a table called 'the_table'.
+use crate::engine::schema::{ClusteringColumn, ClusteringOrder, ColumnDefinition, TableSchema};
+use crate::types::{decode_hex, ColumnType, Value};
+
+// This is going to be a toy version of CQL. Table names without a keyspace ('the_table'
+// rather than 'kassantra.the_table') are in the default keyspace.
+
+/// A table name as given in a statement, with the keyspace if there was one.
+#[derive(Debug, PartialEq)]
+pub struct TableName {
+    pub keyspace: Option<String>,
+    pub name: String,
+}
+
+/// A constant in a statement. What it means depends on the column it's for, see
+/// `to_value`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Literal {
+    /// 'single quoted', with '' for a quote, or "double quoted"
+    String(String),
+    /// An integer or a float, as written
+    Number(String),
+    Boolean(bool),
+    Uuid(Uuid),
+    /// 0x followed by hex digits
+    Blob(Vec<u8>),
+    Null,
+}
+
+impl Literal {
+    /// The value the literal stands for in a column of the given type, `None` for null,
+    /// or why it can't go in the column. Timestamps are given in milliseconds since the
+    /// epoch and uuids can be quoted.
+    pub fn to_value(&self, column_type: ColumnType) -> Result<Option<Value>, String> {
+        let value = match (self, column_type) {
+            (Literal::Null, _) => return Ok(None),
+            (Literal::String(value), ColumnType::Text) => Value::Text(value.clone()),
+            (Literal::String(value), ColumnType::Uuid) => column_type.decode(value)?,
+            (
+                Literal::Number(number),
+                ColumnType::Int | ColumnType::BigInt | ColumnType::Double | ColumnType::Timestamp,
+            ) => column_type.decode(number)?,
+            (Literal::Boolean(value), ColumnType::Boolean) => Value::Boolean(*value),
+            (Literal::Uuid(value), ColumnType::Uuid) => Value::Uuid(*value),
+            (Literal::Blob(bytes), ColumnType::Blob) => Value::Blob(bytes.clone()),
+            (literal, column_type) => {
+                return Err(format!("{} is not a valid {}", literal, column_type))
+            }
+        };
+        Ok(Some(value))
+    }
+}
+
+impl std::fmt::Display for Literal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Literal::String(value) => write!(f, "{}", value),
+            Literal::Number(number) => write!(f, "{}", number),
+            Literal::Boolean(value) => write!(f, "{}", value),
+            Literal::Uuid(value) => write!(f, "{}", value),
+            Literal::Blob(bytes) => write!(f, "{}", Value::Blob(bytes.clone())),
+            Literal::Null => write!(f, "null"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operator {
+    Eq,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+/// A WHERE condition like `key >= 'a'`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Relation {
+    pub column: String,
+    pub operator: Operator,
+    pub value: Literal,
+}
 
 #[derive(Debug, PartialEq)]
 pub enum Operation {
-    Insert(String, String),
-    Delete(String),
-    Select(String),
+    /// `timestamp` is the write timestamp given with `USING TIMESTAMP`, in microseconds
+    /// since the epoch. Without one the server uses the current time. `ttl` is the
+    /// `USING TTL` in seconds.
+    Insert {
+        table: TableName,
+        columns: Vec<String>,
+        values: Vec<Literal>,
+        timestamp: Option<u64>,
+        ttl: Option<u32>,
+    },
+    /// Deletes the listed columns of the rows the relations pick, or the whole rows if
+    /// no columns are listed.
+    Delete {
+        table: TableName,
+        columns: Vec<String>,
+        relations: Vec<Relation>,
+        timestamp: Option<u64>,
+    },
+    /// `columns` is `None` for `SELECT *`. `order_by` lists the clustering columns the
+    /// rows are to be sorted by, if the query has an `ORDER BY`.
+    Select {
+        table: TableName,
+        columns: Option<Vec<String>>,
+        relations: Vec<Relation>,
+        order_by: Vec<(String, ClusteringOrder)>,
+        limit: Option<usize>,
+    },
+    /// `schema` is `None` for a table of plain key-value pairs, which is what a table
+    /// declared without columns is.
+    CreateTable {
+        table: TableName,
+        schema: Option<TableSchema>,
+        if_not_exists: bool,
+    },
+    DropTable {
+        table: TableName,
+        if_exists: bool,
+    },
+    Truncate(TableName),
 }
 
-// e.g. 'INSERT INTO the_table (key) VALUES ("foo")' / 'DELETE FROM the_table WHERE key = "foo"' / 'SELECT * FROM the_table WHERE key = "foo"'
+// e.g. 'INSERT INTO users (id, name, age) VALUES ('bob', 'Bob', 42);' / 'SELECT name, age FROM users WHERE id = 'bob';'
+// 'DELETE age FROM users WHERE id = 'bob';' deletes one column, 'DELETE FROM users WHERE id = 'bob';' the whole row
+// Key-value tables like the_table: 'INSERT INTO the_table (key) VALUES ("foo")' / 'DELETE FROM the_table WHERE key = "foo"' /
+// 'SELECT key FROM the_table;'
+// Range scans: 'SELECT * FROM the_table WHERE key >= "a" AND key < "m" LIMIT 10;', both the bounds and the limit are optional
+// Writes can set their timestamp like in CQL: 'INSERT INTO the_table (key) VALUES ("foo") USING TIMESTAMP 123;' /
+// 'DELETE FROM the_table USING TIMESTAMP 123 WHERE key = "foo";'
+// Inserts can also expire: 'INSERT INTO the_table (key) VALUES ("foo") USING TTL 60 AND TIMESTAMP 123;'
+// Tables: 'CREATE TABLE IF NOT EXISTS ks.users (id text PRIMARY KEY, name text, age int);' /
+// 'CREATE TABLE ks.pairs;' for a key-value table / 'DROP TABLE IF EXISTS ks.users;' / 'TRUNCATE ks.users;'
+// Partitions of rows sorted by clustering columns: 'CREATE TABLE ks.readings (sensor text, at timestamp, value double,
+// PRIMARY KEY (sensor, at)) WITH CLUSTERING ORDER BY (at DESC);' /
+// 'SELECT * FROM ks.readings WHERE sensor = 'a' AND at > 1000 ORDER BY at ASC LIMIT 10;'
 
 impl Operation {
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str(input: &str) -> Result<Operation, nom::Err<nom::error::Error<&str>>> {
-        let (_, operation) = branch::alt((insert, delete, select))(input)?;
+        let (_, operation) =
+            branch::alt((insert, delete, select, create_table, drop_table, truncate))(input)?;
         Ok(operation)
     }
 }
@@ -27,81 +162,902 @@ fn insert(input: &str) -> nom::IResult<&str, Operation> {
     let (input, _) = space1(input)?;
     let (input, _) = tag("INTO")(input)?;
     let (input, _) = space1(input)?;
-    let (input, _) = tag("the_table")(input)?;
+    let (input, table) = table_name(input)?;
     let (input, _) = space1(input)?;
-    let (input, _) = tag("(")(input)?;
-    let (input, key) = complete::is_not(")")(input)?;
-    let (input, _) = tag(")")(input)?;
+    let (input, columns) = parenthesized(identifier)(input)?;
     let (input, _) = space1(input)?;
     let (input, _) = tag("VALUES")(input)?;
     let (input, _) = space1(input)?;
-    let (input, _) = tag("(")(input)?;
-    let (input, value) = complete::is_not(")")(input)?;
-    let (input, _) = tag(")")(input)?;
-    let (input, _) = tag(";")(input)?;
-    // consume any whitespace left
-    let (input, _) = space0(input)?;
-    // consume any newlines
-    let (input, _) = line_ending(input)?;
-    match input.len() {
-        0 => Ok((input, Operation::Insert(key.to_string(), value.to_string()))),
-        _ => Err(nom::Err::Error(nom::error::Error::new(
+    let (input, values) = parenthesized(literal)(input)?;
+    let (input, using) = opt(preceded(
+        tuple((space1, tag("USING"), space1)),
+        separated_list1(tuple((space1, tag("AND"), space1)), using_option),
+    ))(input)?;
+    let (input, _) = statement_end(input)?;
+    if columns.len() != values.len() {
+        return Err(nom::Err::Error(nom::error::Error::new(
             input,
-            nom::error::ErrorKind::Eof,
-        ))),
+            nom::error::ErrorKind::Verify,
+        )));
     }
+    let mut timestamp = None;
+    let mut ttl = None;
+    for option in using.unwrap_or_default() {
+        match option {
+            UsingOption::Timestamp(value) if timestamp.is_none() => timestamp = Some(value),
+            UsingOption::Ttl(value) if ttl.is_none() => ttl = Some(value),
+            // the same option given twice
+            _ => {
+                return Err(nom::Err::Error(nom::error::Error::new(
+                    input,
+                    nom::error::ErrorKind::Verify,
+                )))
+            }
+        }
+    }
+    Ok((
+        input,
+        Operation::Insert {
+            table,
+            columns: columns.into_iter().map(str::to_string).collect(),
+            values,
+            timestamp,
+            ttl,
+        },
+    ))
 }
 
 fn delete(input: &str) -> nom::IResult<&str, Operation> {
     let (input, _) = tag("DELETE")(input)?;
     let (input, _) = space1(input)?;
-    let (input, _) = tag("FROM")(input)?;
-    let (input, _) = space1(input)?;
-    let (input, _) = tag("the_table")(input)?;
+    // DELETE FROM t or DELETE a, b FROM t
+    let (input, columns) = branch::alt((
+        map(tuple((tag("FROM"), space1)), |_| Vec::new()),
+        terminated(identifier_list, tuple((space1, tag("FROM"), space1))),
+    ))(input)?;
+    let (input, table) = table_name(input)?;
     let (input, _) = space1(input)?;
+    let (input, timestamp) = opt(terminated(using_timestamp, space1))(input)?;
     let (input, _) = tag("WHERE")(input)?;
     let (input, _) = space1(input)?;
-    let (input, _) = tag("key")(input)?;
+    let (input, relations) = relations(input)?;
+    let (input, _) = statement_end(input)?;
+    Ok((
+        input,
+        Operation::Delete {
+            table,
+            columns,
+            relations,
+            timestamp,
+        },
+    ))
+}
+
+fn select(input: &str) -> nom::IResult<&str, Operation> {
+    // SELECT * FROM the_table WHERE key >= "a" LIMIT 10; or SELECT a, b FROM t WHERE id = 1;
+    // or SELECT * FROM t WHERE pk = 1 AND ck > 2 ORDER BY ck DESC LIMIT 10;
+    let (input, _) = tag("SELECT")(input)?;
+    let (input, _) = space1(input)?;
+    let (input, columns) =
+        branch::alt((map(tag("*"), |_| None), map(identifier_list, Some)))(input)?;
     let (input, _) = space1(input)?;
-    let (input, _) = tag("=")(input)?;
+    let (input, _) = tag("FROM")(input)?;
     let (input, _) = space1(input)?;
-    let (input, _) = tag("\"")(input)?;
-    let (input, key) = complete::is_not("\"")(input)?;
-    let (input, _) = tag("\"")(input)?;
-    let (input, _) = tag(";")(input)?;
-    // consume any whitespace left
-    let (input, _) = space0(input)?;
-    // consume any newlines
-    let (input, _) = line_ending(input)?;
+    let (input, table) = table_name(input)?;
+    let (input, relations) =
+        opt(preceded(tuple((space1, tag("WHERE"), space1)), relations))(input)?;
+    let (input, order_by) = opt(preceded(
+        tuple((space1, tag("ORDER"), space1, tag("BY"), space1)),
+        separated_list1(tuple((space0, tag(","), space0)), ordering),
+    ))(input)?;
+    let (input, limit) = opt(preceded(
+        tuple((space1, tag("LIMIT"), space1)),
+        map_res(digit1, str::parse::<usize>),
+    ))(input)?;
+    let (input, _) = statement_end(input)?;
+    Ok((
+        input,
+        Operation::Select {
+            table,
+            columns,
+            relations: relations.unwrap_or_default(),
+            order_by: order_by.unwrap_or_default(),
+            limit,
+        },
+    ))
+}
 
-    match input.len() {
-        0 => Ok((input, Operation::Delete(key.to_string()))),
-        _ => Err(nom::Err::Error(nom::error::Error::new(
-            input,
-            nom::error::ErrorKind::Eof,
-        ))),
+fn create_table(input: &str) -> nom::IResult<&str, Operation> {
+    // CREATE TABLE IF NOT EXISTS ks.users (id text PRIMARY KEY, name text); or
+    // CREATE TABLE ks.events (a int, b int, at timestamp, x text, PRIMARY KEY ((a, b), at))
+    // WITH CLUSTERING ORDER BY (at DESC);
+    let (input, _) = tag("CREATE")(input)?;
+    let (input, _) = space1(input)?;
+    let (input, _) = tag("TABLE")(input)?;
+    let (input, _) = space1(input)?;
+    let (input, if_not_exists) = opt(tuple((
+        tag("IF"),
+        space1,
+        tag("NOT"),
+        space1,
+        tag("EXISTS"),
+        space1,
+    )))(input)?;
+    let (input, table) = table_name(input)?;
+    let (input, definitions) = opt(preceded(space1, parenthesized(table_element)))(input)?;
+    let (input, clustering_order) = opt(preceded(
+        tuple((
+            space1,
+            tag("WITH"),
+            space1,
+            tag("CLUSTERING"),
+            space1,
+            tag("ORDER"),
+            space1,
+            tag("BY"),
+            space1,
+        )),
+        parenthesized(ordering),
+    ))(input)?;
+    let (input, _) = statement_end(input)?;
+
+    let schema = match definitions {
+        Some(definitions) => {
+            match table_schema(definitions, clustering_order.unwrap_or_default()) {
+                Some(schema) => Some(schema),
+                None => {
+                    return Err(nom::Err::Error(nom::error::Error::new(
+                        input,
+                        nom::error::ErrorKind::Verify,
+                    )))
+                }
+            }
+        }
+        None => None,
+    };
+    Ok((
+        input,
+        Operation::CreateTable {
+            table,
+            schema,
+            if_not_exists: if_not_exists.is_some(),
+        },
+    ))
+}
+
+// What goes between the parentheses of a CREATE TABLE
+enum TableElement {
+    Column {
+        definition: ColumnDefinition,
+        primary_key: bool,
+    },
+    PrimaryKey {
+        partition_key: Vec<String>,
+        clustering_key: Vec<String>,
+    },
+}
+
+// name text, name text PRIMARY KEY, PRIMARY KEY (partition_key, clustering...) or
+// PRIMARY KEY ((partition_key...), clustering...)
+fn table_element(input: &str) -> nom::IResult<&str, TableElement> {
+    let partition_key = branch::alt((
+        map(parenthesized(identifier), |names| {
+            names.into_iter().map(str::to_string).collect::<Vec<_>>()
+        }),
+        map(identifier, |name| vec![name.to_string()]),
+    ));
+    let primary_key = map(
+        preceded(
+            tuple((tag("PRIMARY"), space1, tag("KEY"), space0)),
+            delimited(
+                tuple((tag("("), space0)),
+                tuple((
+                    partition_key,
+                    opt(preceded(tuple((space0, tag(","), space0)), identifier_list)),
+                )),
+                tuple((space0, tag(")"))),
+            ),
+        ),
+        |(partition_key, clustering_key)| TableElement::PrimaryKey {
+            partition_key,
+            clustering_key: clustering_key.unwrap_or_default(),
+        },
+    );
+    let column = map(
+        tuple((
+            identifier,
+            space1,
+            map_opt(identifier, ColumnType::from_name),
+            opt(tuple((space1, tag("PRIMARY"), space1, tag("KEY")))),
+        )),
+        |(name, _, column_type, primary_key)| TableElement::Column {
+            definition: ColumnDefinition {
+                name: name.to_string(),
+                column_type,
+            },
+            primary_key: primary_key.is_some(),
+        },
+    );
+    branch::alt((primary_key, column))(input)
+}
+
+// The schema the CREATE TABLE declares, or None if it doesn't declare exactly one primary
+// key or its clustering order isn't for the first clustering columns, in order
+fn table_schema(
+    elements: Vec<TableElement>,
+    clustering_order: Vec<(String, ClusteringOrder)>,
+) -> Option<TableSchema> {
+    let mut columns = Vec::new();
+    let mut primary_keys = Vec::new();
+    for element in elements {
+        match element {
+            TableElement::Column {
+                definition,
+                primary_key,
+            } => {
+                if primary_key {
+                    primary_keys.push((vec![definition.name.clone()], Vec::new()));
+                }
+                columns.push(definition);
+            }
+            TableElement::PrimaryKey {
+                partition_key,
+                clustering_key,
+            } => primary_keys.push((partition_key, clustering_key)),
+        }
+    }
+    let [(partition_key, clustering_key)] = <[_; 1]>::try_from(primary_keys).ok()?;
+    if clustering_order.len() > clustering_key.len() {
+        return None;
     }
+    let clustering_key = clustering_key
+        .into_iter()
+        .enumerate()
+        .map(|(i, name)| match clustering_order.get(i) {
+            Some((ordered, order)) if *ordered == name => Some(ClusteringColumn {
+                name,
+                order: *order,
+            }),
+            Some(_) => None,
+            None => Some(ClusteringColumn {
+                name,
+                order: ClusteringOrder::Asc,
+            }),
+        })
+        .collect::<Option<Vec<_>>>()?;
+    Some(TableSchema {
+        columns,
+        partition_key,
+        clustering_key,
+    })
 }
 
-fn select(input: &str) -> nom::IResult<&str, Operation> {
-    // SELECT some_key FROM the_table;
-    let (input, _) = tag("SELECT")(input)?;
+// at, at ASC or at DESC
+fn ordering(input: &str) -> nom::IResult<&str, (String, ClusteringOrder)> {
+    let (input, name) = identifier(input)?;
+    let (input, order) = opt(preceded(
+        space1,
+        branch::alt((
+            map(tag("ASC"), |_| ClusteringOrder::Asc),
+            map(tag("DESC"), |_| ClusteringOrder::Desc),
+        )),
+    ))(input)?;
+    Ok((
+        input,
+        (name.to_string(), order.unwrap_or(ClusteringOrder::Asc)),
+    ))
+}
+
+fn drop_table(input: &str) -> nom::IResult<&str, Operation> {
+    // DROP TABLE IF EXISTS ks.users;
+    let (input, _) = tag("DROP")(input)?;
     let (input, _) = space1(input)?;
-    let (input, key) = complete::is_not(" ")(input)?;
+    let (input, _) = tag("TABLE")(input)?;
     let (input, _) = space1(input)?;
-    let (input, _) = tag("FROM")(input)?;
+    let (input, if_exists) = opt(tuple((tag("IF"), space1, tag("EXISTS"), space1)))(input)?;
+    let (input, table) = table_name(input)?;
+    let (input, _) = statement_end(input)?;
+    Ok((
+        input,
+        Operation::DropTable {
+            table,
+            if_exists: if_exists.is_some(),
+        },
+    ))
+}
+
+fn truncate(input: &str) -> nom::IResult<&str, Operation> {
+    // TRUNCATE ks.users; or TRUNCATE TABLE ks.users;
+    let (input, _) = tag("TRUNCATE")(input)?;
     let (input, _) = space1(input)?;
-    let (input, _) = tag("the_table")(input)?;
+    let (input, _) = opt(tuple((tag("TABLE"), space1)))(input)?;
+    let (input, table) = table_name(input)?;
+    let (input, _) = statement_end(input)?;
+    Ok((input, Operation::Truncate(table)))
+}
+
+// The ; and newline every statement ends with, and nothing after them
+fn statement_end(input: &str) -> nom::IResult<&str, ()> {
     let (input, _) = tag(";")(input)?;
     // consume any whitespace left
     let (input, _) = space0(input)?;
     // consume any newlines
     let (input, _) = line_ending(input)?;
     match input.len() {
-        0 => Ok((input, Operation::Select(key.to_string()))),
+        0 => Ok((input, ())),
         _ => Err(nom::Err::Error(nom::error::Error::new(
             input,
             nom::error::ErrorKind::Eof,
         ))),
     }
 }
+
+// users or ks.users
+fn table_name(input: &str) -> nom::IResult<&str, TableName> {
+    let (input, first) = identifier(input)?;
+    let (input, second) = opt(preceded(tag("."), identifier))(input)?;
+    let table = match second {
+        Some(name) => TableName {
+            keyspace: Some(first.to_string()),
+            name: name.to_string(),
+        },
+        None => TableName {
+            keyspace: None,
+            name: first.to_string(),
+        },
+    };
+    Ok((input, table))
+}
+
+fn identifier(input: &str) -> nom::IResult<&str, &str> {
+    take_while1(|c: char| c.is_ascii_alphanumeric() || c == '_')(input)
+}
+
+// a, b, c
+fn identifier_list(input: &str) -> nom::IResult<&str, Vec<String>> {
+    map(
+        separated_list1(tuple((space0, tag(","), space0)), identifier),
+        |names| names.into_iter().map(str::to_string).collect(),
+    )(input)
+}
+
+// (a, b, c) with whatever `item` parses in place of a, b and c
+fn parenthesized<'a, O>(
+    item: impl FnMut(&'a str) -> nom::IResult<&'a str, O>,
+) -> impl FnMut(&'a str) -> nom::IResult<&'a str, Vec<O>> {
+    delimited(
+        tuple((tag("("), space0)),
+        separated_list1(tuple((space0, tag(","), space0)), item),
+        tuple((space0, tag(")"))),
+    )
+}
+
+fn literal(input: &str) -> nom::IResult<&str, Literal> {
+    branch::alt((
+        map(single_quoted, Literal::String),
+        map(
+            delimited(tag("\""), opt(complete::is_not("\"")), tag("\"")),
+            |value: Option<&str>| Literal::String(value.unwrap_or_default().to_string()),
+        ),
+        map_opt(
+            preceded(
+                tag_no_case("0x"),
+                take_while_m_n(0, usize::MAX, |c: char| c.is_ascii_hexdigit()),
+            ),
+            |hex| decode_hex(hex).map(Literal::Blob),
+        ),
+        map_res(
+            take_while_m_n(36, 36, |c: char| c.is_ascii_hexdigit() || c == '-'),
+            |uuid| Uuid::parse_str(uuid).map(Literal::Uuid),
+        ),
+        map(recognize_float, |number: &str| {
+            Literal::Number(number.to_string())
+        }),
+        map(tag_no_case("true"), |_| Literal::Boolean(true)),
+        map(tag_no_case("false"), |_| Literal::Boolean(false)),
+        map(tag_no_case("null"), |_| Literal::Null),
+    ))(input)
+}
+
+// 'it''s' is "it's"
+fn single_quoted(input: &str) -> nom::IResult<&str, String> {
+    let (mut input, _) = tag("'")(input)?;
+    let mut value = String::new();
+    loop {
+        let (rest, part) = opt(complete::is_not("'"))(input)?;
+        value.push_str(part.unwrap_or_default());
+        let (rest, _) = tag("'")(rest)?;
+        match tag::<_, _, nom::error::Error<&str>>("'")(rest) {
+            Ok((rest, _)) => {
+                value.push('\'');
+                input = rest;
+            }
+            Err(_) => return Ok((rest, value)),
+        }
+    }
+}
+
+// USING TIMESTAMP 1700000000000000
+fn using_timestamp(input: &str) -> nom::IResult<&str, u64> {
+    preceded(tuple((tag("USING"), space1)), timestamp)(input)
+}
+
+// TIMESTAMP 1700000000000000
+fn timestamp(input: &str) -> nom::IResult<&str, u64> {
+    preceded(
+        tuple((tag("TIMESTAMP"), space1)),
+        map_res(digit1, str::parse::<u64>),
+    )(input)
+}
+
+enum UsingOption {
+    Timestamp(u64),
+    Ttl(u32),
+}
+
+// TIMESTAMP 1700000000000000 or TTL 60, the options an insert can have after USING
+fn using_option(input: &str) -> nom::IResult<&str, UsingOption> {
+    branch::alt((
+        nom::combinator::map(timestamp, UsingOption::Timestamp),
+        nom::combinator::map(
+            preceded(
+                tuple((tag("TTL"), space1)),
+                map_res(digit1, str::parse::<u32>),
+            ),
+            UsingOption::Ttl,
+        ),
+    ))(input)
+}
+
+// key >= "a" AND key < "m", where a column can have at most one bound on either side, or
+// just an =
+fn relations(input: &str) -> nom::IResult<&str, Vec<Relation>> {
+    let (rest, relations) = separated_list1(tuple((space1, tag("AND"), space1)), relation)(input)?;
+    let side = |operator: Operator| match operator {
+        Operator::Eq => 0,
+        Operator::Gt | Operator::Ge => 1,
+        Operator::Lt | Operator::Le => 2,
+    };
+    for (i, relation) in relations.iter().enumerate() {
+        let clashes = relations[..i].iter().any(|earlier| {
+            earlier.column == relation.column
+                && (side(earlier.operator) == side(relation.operator)
+                    || earlier.operator == Operator::Eq
+                    || relation.operator == Operator::Eq)
+        });
+        if clashes {
+            return Err(nom::Err::Error(nom::error::Error::new(
+                input,
+                nom::error::ErrorKind::Verify,
+            )));
+        }
+    }
+    Ok((rest, relations))
+}
+
+// key >= "foo", key > "foo", key <= "foo", key < "foo" or key = "foo"
+fn relation(input: &str) -> nom::IResult<&str, Relation> {
+    let (input, column) = identifier(input)?;
+    let (input, _) = space0(input)?;
+    let (input, operator) = branch::alt((
+        map(tag(">="), |_| Operator::Ge),
+        map(tag(">"), |_| Operator::Gt),
+        map(tag("<="), |_| Operator::Le),
+        map(tag("<"), |_| Operator::Lt),
+        map(tag("="), |_| Operator::Eq),
+    ))(input)?;
+    let (input, _) = space0(input)?;
+    let (input, value) = literal(input)?;
+    Ok((
+        input,
+        Relation {
+            column: column.to_string(),
+            operator,
+            value,
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn the_table() -> TableName {
+        TableName {
+            keyspace: None,
+            name: "the_table".to_string(),
+        }
+    }
+
+    fn users() -> TableName {
+        TableName {
+            keyspace: Some("ks".to_string()),
+            name: "users".to_string(),
+        }
+    }
+
+    fn key(operator: Operator, value: &str) -> Relation {
+        Relation {
+            column: "key".to_string(),
+            operator,
+            value: Literal::String(value.to_string()),
+        }
+    }
+
+    fn insert_foo(timestamp: Option<u64>, ttl: Option<u32>) -> Operation {
+        Operation::Insert {
+            table: the_table(),
+            columns: vec!["foo".to_string()],
+            values: vec![Literal::String("bar".to_string())],
+            timestamp,
+            ttl,
+        }
+    }
+
+    #[test]
+    fn test_parse_range_scan() {
+        assert_eq!(
+            Operation::from_str(
+                "SELECT * FROM the_table WHERE key >= \"a\" AND key < \"m\" LIMIT 10;\n"
+            )
+            .unwrap(),
+            Operation::Select {
+                table: the_table(),
+                columns: None,
+                relations: vec![key(Operator::Ge, "a"), key(Operator::Lt, "m")],
+                order_by: vec![],
+                limit: Some(10),
+            }
+        );
+        assert_eq!(
+            Operation::from_str("SELECT * FROM the_table WHERE key > \"a\";\n").unwrap(),
+            Operation::Select {
+                table: the_table(),
+                columns: None,
+                relations: vec![key(Operator::Gt, "a")],
+                order_by: vec![],
+                limit: None,
+            }
+        );
+        assert_eq!(
+            Operation::from_str("SELECT * FROM the_table;\n").unwrap(),
+            Operation::Select {
+                table: the_table(),
+                columns: None,
+                relations: vec![],
+                order_by: vec![],
+                limit: None,
+            }
+        );
+        assert_eq!(
+            Operation::from_str("SELECT foo FROM the_table;\n").unwrap(),
+            Operation::Select {
+                table: the_table(),
+                columns: Some(vec!["foo".to_string()]),
+                relations: vec![],
+                order_by: vec![],
+                limit: None,
+            }
+        );
+        assert!(Operation::from_str(
+            "SELECT * FROM the_table WHERE key > \"a\" AND key >= \"b\";\n"
+        )
+        .is_err());
+        assert!(Operation::from_str(
+            "SELECT * FROM the_table WHERE key = \"a\" AND key < \"b\";\n"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_parse_using_timestamp() {
+        assert_eq!(
+            Operation::from_str(
+                "INSERT INTO the_table (foo) VALUES (\"bar\") USING TIMESTAMP 42;\n"
+            )
+            .unwrap(),
+            insert_foo(Some(42), None)
+        );
+        assert_eq!(
+            Operation::from_str("INSERT INTO the_table (foo) VALUES ('bar');\n").unwrap(),
+            insert_foo(None, None)
+        );
+        assert_eq!(
+            Operation::from_str("DELETE FROM the_table USING TIMESTAMP 42 WHERE key = \"foo\";\n")
+                .unwrap(),
+            Operation::Delete {
+                table: the_table(),
+                columns: vec![],
+                relations: vec![key(Operator::Eq, "foo")],
+                timestamp: Some(42),
+            }
+        );
+        assert!(Operation::from_str(
+            "INSERT INTO the_table (foo) VALUES (\"bar\") USING TIMESTAMP soon;\n"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_parse_using_ttl() {
+        assert_eq!(
+            Operation::from_str("INSERT INTO the_table (foo) VALUES (\"bar\") USING TTL 60;\n")
+                .unwrap(),
+            insert_foo(None, Some(60))
+        );
+        assert_eq!(
+            Operation::from_str(
+                "INSERT INTO the_table (foo) VALUES (\"bar\") USING TIMESTAMP 42 AND TTL 60;\n"
+            )
+            .unwrap(),
+            insert_foo(Some(42), Some(60))
+        );
+        assert!(Operation::from_str(
+            "INSERT INTO the_table (foo) VALUES (\"bar\") USING TTL 60 AND TTL 5;\n"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_parse_table_statements() {
+        assert_eq!(
+            Operation::from_str("CREATE TABLE ks.users;\n").unwrap(),
+            Operation::CreateTable {
+                table: users(),
+                schema: None,
+                if_not_exists: false,
+            }
+        );
+        assert_eq!(
+            Operation::from_str("CREATE TABLE IF NOT EXISTS ks.users;\n").unwrap(),
+            Operation::CreateTable {
+                table: users(),
+                schema: None,
+                if_not_exists: true,
+            }
+        );
+        assert_eq!(
+            Operation::from_str("DROP TABLE IF EXISTS the_table;\n").unwrap(),
+            Operation::DropTable {
+                table: the_table(),
+                if_exists: true,
+            }
+        );
+        assert_eq!(
+            Operation::from_str("TRUNCATE ks.users;\n").unwrap(),
+            Operation::Truncate(users())
+        );
+        assert_eq!(
+            Operation::from_str("TRUNCATE TABLE ks.users;\n").unwrap(),
+            Operation::Truncate(users())
+        );
+        assert!(Operation::from_str("CREATE TABLE ks.us-ers;\n").is_err());
+        assert!(Operation::from_str("DROP TABLE ks.;\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_create_table_with_columns() {
+        let schema = TableSchema {
+            columns: vec![
+                ColumnDefinition {
+                    name: "id".to_string(),
+                    column_type: ColumnType::Uuid,
+                },
+                ColumnDefinition {
+                    name: "name".to_string(),
+                    column_type: ColumnType::Text,
+                },
+                ColumnDefinition {
+                    name: "age".to_string(),
+                    column_type: ColumnType::Int,
+                },
+            ],
+            partition_key: vec!["id".to_string()],
+            clustering_key: vec![],
+        };
+        assert_eq!(
+            Operation::from_str(
+                "CREATE TABLE ks.users (id uuid PRIMARY KEY, name text, age int);\n"
+            )
+            .unwrap(),
+            Operation::CreateTable {
+                table: users(),
+                schema: Some(schema.clone()),
+                if_not_exists: false,
+            }
+        );
+        assert_eq!(
+            Operation::from_str(
+                "CREATE TABLE ks.users (id UUID, name TEXT, age INT, PRIMARY KEY (id));\n"
+            )
+            .unwrap(),
+            Operation::CreateTable {
+                table: users(),
+                schema: Some(schema),
+                if_not_exists: false,
+            }
+        );
+        // no primary key, two of them, or a type that doesn't exist
+        assert!(Operation::from_str("CREATE TABLE ks.users (id uuid, name text);\n").is_err());
+        assert!(Operation::from_str(
+            "CREATE TABLE ks.users (id uuid PRIMARY KEY, name text PRIMARY KEY);\n"
+        )
+        .is_err());
+        assert!(
+            Operation::from_str("CREATE TABLE ks.users (id uuid PRIMARY KEY, n varchar);\n")
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_parse_rows() {
+        let id = "5f0d1c8e-3b8a-4c5e-9a3f-2b1d7e6c4a90";
+        assert_eq!(
+            Operation::from_str(&format!(
+                "INSERT INTO ks.users (id, name, age, score, admin, avatar, nickname) VALUES ({}, 'It''s me', 42, -1.5e3, true, 0xCAFE, null);\n",
+                id
+            ))
+            .unwrap(),
+            Operation::Insert {
+                table: users(),
+                columns: ["id", "name", "age", "score", "admin", "avatar", "nickname"]
+                    .iter()
+                    .map(|name| name.to_string())
+                    .collect(),
+                values: vec![
+                    Literal::Uuid(Uuid::parse_str(id).unwrap()),
+                    Literal::String("It's me".to_string()),
+                    Literal::Number("42".to_string()),
+                    Literal::Number("-1.5e3".to_string()),
+                    Literal::Boolean(true),
+                    Literal::Blob(vec![0xca, 0xfe]),
+                    Literal::Null,
+                ],
+                timestamp: None,
+                ttl: None,
+            }
+        );
+        assert_eq!(
+            Operation::from_str("SELECT name, age FROM ks.users WHERE id = 7;\n").unwrap(),
+            Operation::Select {
+                table: users(),
+                columns: Some(vec!["name".to_string(), "age".to_string()]),
+                relations: vec![Relation {
+                    column: "id".to_string(),
+                    operator: Operator::Eq,
+                    value: Literal::Number("7".to_string()),
+                }],
+                order_by: vec![],
+                limit: None,
+            }
+        );
+        assert_eq!(
+            Operation::from_str("DELETE name, age FROM ks.users WHERE id = 7;\n").unwrap(),
+            Operation::Delete {
+                table: users(),
+                columns: vec!["name".to_string(), "age".to_string()],
+                relations: vec![Relation {
+                    column: "id".to_string(),
+                    operator: Operator::Eq,
+                    value: Literal::Number("7".to_string()),
+                }],
+                timestamp: None,
+            }
+        );
+        // as many values as columns
+        assert!(Operation::from_str("INSERT INTO ks.users (id, name) VALUES (7);\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_clustering_columns() {
+        let column = |name: &str, column_type| ColumnDefinition {
+            name: name.to_string(),
+            column_type,
+        };
+        let clustering = |name: &str, order| ClusteringColumn {
+            name: name.to_string(),
+            order,
+        };
+        assert_eq!(
+            Operation::from_str(
+                "CREATE TABLE ks.events (a int, b text, at timestamp, seq int, x text, PRIMARY KEY ((a, b), at, seq)) WITH CLUSTERING ORDER BY (at DESC);\n"
+            )
+            .unwrap(),
+            Operation::CreateTable {
+                table: TableName {
+                    keyspace: Some("ks".to_string()),
+                    name: "events".to_string(),
+                },
+                schema: Some(TableSchema {
+                    columns: vec![
+                        column("a", ColumnType::Int),
+                        column("b", ColumnType::Text),
+                        column("at", ColumnType::Timestamp),
+                        column("seq", ColumnType::Int),
+                        column("x", ColumnType::Text),
+                    ],
+                    partition_key: vec!["a".to_string(), "b".to_string()],
+                    clustering_key: vec![
+                        clustering("at", ClusteringOrder::Desc),
+                        clustering("seq", ClusteringOrder::Asc),
+                    ],
+                }),
+                if_not_exists: false,
+            }
+        );
+        assert_eq!(
+            Operation::from_str("CREATE TABLE t (a int, b int, PRIMARY KEY (a, b));\n").unwrap(),
+            Operation::CreateTable {
+                table: TableName {
+                    keyspace: None,
+                    name: "t".to_string(),
+                },
+                schema: Some(TableSchema {
+                    columns: vec![column("a", ColumnType::Int), column("b", ColumnType::Int)],
+                    partition_key: vec!["a".to_string()],
+                    clustering_key: vec![clustering("b", ClusteringOrder::Asc)],
+                }),
+                if_not_exists: false,
+            }
+        );
+        // the clustering order has to follow the clustering columns
+        assert!(Operation::from_str(
+            "CREATE TABLE t (a int, b int, c int, PRIMARY KEY (a, b, c)) WITH CLUSTERING ORDER BY (c DESC);\n"
+        )
+        .is_err());
+        assert!(Operation::from_str(
+            "CREATE TABLE t (a int, b int, PRIMARY KEY (a, b)) WITH CLUSTERING ORDER BY (a DESC);\n"
+        )
+        .is_err());
+
+        assert_eq!(
+            Operation::from_str(
+                "SELECT * FROM t WHERE a = 1 AND b > 2 ORDER BY b DESC LIMIT 10;\n"
+            )
+            .unwrap(),
+            Operation::Select {
+                table: TableName {
+                    keyspace: None,
+                    name: "t".to_string(),
+                },
+                columns: None,
+                relations: vec![
+                    Relation {
+                        column: "a".to_string(),
+                        operator: Operator::Eq,
+                        value: Literal::Number("1".to_string()),
+                    },
+                    Relation {
+                        column: "b".to_string(),
+                        operator: Operator::Gt,
+                        value: Literal::Number("2".to_string()),
+                    },
+                ],
+                order_by: vec![("b".to_string(), ClusteringOrder::Desc)],
+                limit: Some(10),
+            }
+        );
+    }
+
+    #[test]
+    fn test_literal_to_value() {
+        assert_eq!(
+            Literal::Number("42".to_string()).to_value(ColumnType::BigInt),
+            Ok(Some(Value::BigInt(42)))
+        );
+        assert_eq!(
+            Literal::Number("1.5".to_string()).to_value(ColumnType::Double),
+            Ok(Some(Value::Double(1.5)))
+        );
+        assert_eq!(Literal::Null.to_value(ColumnType::Int), Ok(None));
+        assert!(Literal::Number("1.5".to_string())
+            .to_value(ColumnType::Int)
+            .is_err());
+        assert!(Literal::Number("3000000000".to_string())
+            .to_value(ColumnType::Int)
+            .is_err());
+        assert!(Literal::String("42".to_string())
+            .to_value(ColumnType::Int)
+            .is_err());
+    }
+}
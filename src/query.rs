@@ -0,0 +1,371 @@
+use std::ops::Bound;
+use std::sync::Arc;
+
+use tokio_stream::StreamExt;
+
+use crate::engine::operation::next_timestamp;
+use crate::engine::schema::{ClusteringOrder, TableSchema};
+use crate::error::{Error, Result};
+use crate::keyspace::{Node, DEFAULT_KEYSPACE};
+use crate::ql::parser::{Literal, Operation, Operator, Relation, TableName};
+use crate::table::{Row, Slice, Table};
+use crate::types::Value;
+
+/// Parses and runs a request as the server gets it. Requests are text, blob values are
+/// written as 0x hex literals, so a request that isn't valid UTF-8 is rejected before
+/// anything is written.
+pub async fn execute_request(node: &Node, request: &[u8]) -> Result<String> {
+    let request = std::str::from_utf8(request).map_err(|_| {
+        Error::InvalidRequest(
+            "the request is not valid UTF-8, write blobs as 0x hex literals".to_string(),
+        )
+    })?;
+    // ql::parser::Operation implements the from_str trait
+    let operation = Operation::from_str(request).map_err(|e| Error::Parse(e.to_string()))?;
+    execute(node, operation).await
+}
+
+// Table names without a keyspace are in the default one
+fn keyspace(table: &TableName) -> &str {
+    table.keyspace.as_deref().unwrap_or(DEFAULT_KEYSPACE)
+}
+
+async fn table(node: &Node, table: &TableName) -> Result<Arc<Table>> {
+    node.table(keyspace(table), &table.name).await
+}
+
+/// Runs a parsed statement against the node's tables and returns the response for the
+/// client: "OK" for writes and schema changes, the rows or value found for a SELECT.
+pub async fn execute(node: &Node, operation: Operation) -> Result<String> {
+    match operation {
+        Operation::Insert {
+            table: table_name,
+            columns,
+            values,
+            timestamp,
+            ttl,
+        } => {
+            let table = table(node, &table_name).await?;
+            let timestamp = timestamp.unwrap_or_else(next_timestamp);
+            let Some(schema) = &table.schema else {
+                let (key, value) = key_value_pair(columns, values)?;
+                match ttl {
+                    Some(ttl) => {
+                        table
+                            .database
+                            .set_with_ttl_and_timestamp(key, value, ttl, timestamp)
+                            .await?
+                    }
+                    None => {
+                        table
+                            .database
+                            .set_with_timestamp(key, value, timestamp)
+                            .await?
+                    }
+                }
+                return Ok("OK".to_string());
+            };
+            let columns = columns
+                .into_iter()
+                .zip(values)
+                .map(|(name, literal)| {
+                    let value = literal_value(schema, &name, &literal)?;
+                    Ok((name, value))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            table.insert(columns, timestamp, ttl.unwrap_or(0)).await?;
+            Ok("OK".to_string())
+        }
+        Operation::Select {
+            table: table_name,
+            columns,
+            relations,
+            order_by,
+            limit,
+        } => {
+            let table = table(node, &table_name).await?;
+            let Some(schema) = &table.schema else {
+                if !order_by.is_empty() {
+                    return Err(Error::InvalidRequest(
+                        "a key-value table can't be ordered".to_string(),
+                    ));
+                }
+                return select_key_value(&table, columns, relations, limit).await;
+            };
+            if let Some(columns) = &columns {
+                for name in columns {
+                    schema.column(name).ok_or_else(|| undefined_column(name))?;
+                }
+            }
+            // keep only the selected columns, in the order they were selected
+            let project = |row: Row| -> Row {
+                match &columns {
+                    Some(columns) => columns
+                        .iter()
+                        .filter_map(|name| row.iter().find(|(column, _)| column == name).cloned())
+                        .collect(),
+                    None => row,
+                }
+            };
+            let slice = slice(schema, &relations, &order_by)?;
+            let rows = table.select(&slice, limit);
+            tokio::pin!(rows);
+            let mut response = String::new();
+            while let Some(row) = rows.next().await.transpose()? {
+                response.push_str(&format_row(&project(row)));
+            }
+            if response.is_empty() {
+                response = "No rows found".to_string();
+            }
+            Ok(response)
+        }
+        Operation::Delete {
+            table: table_name,
+            columns,
+            relations,
+            timestamp,
+        } => {
+            let table = table(node, &table_name).await?;
+            let timestamp = timestamp.unwrap_or_else(next_timestamp);
+            match &table.schema {
+                Some(schema) => {
+                    let key = primary_key_values(schema, &relations)?;
+                    table.delete(&key, &columns, timestamp).await?;
+                }
+                None => {
+                    let key = match (&columns[..], &relations[..]) {
+                        ([], [relation]) if is_key_equals(relation) => relation.value.to_string(),
+                        _ => {
+                            return Err(Error::InvalidRequest(
+                                "deletes from a key-value table need a WHERE key = ...".to_string(),
+                            ))
+                        }
+                    };
+                    table
+                        .database
+                        .delete_with_timestamp(&key, timestamp)
+                        .await?;
+                }
+            }
+            Ok("OK".to_string())
+        }
+        Operation::CreateTable {
+            table,
+            schema,
+            if_not_exists,
+        } => {
+            node.create_table(keyspace(&table), &table.name, schema, if_not_exists)
+                .await?;
+            Ok("OK".to_string())
+        }
+        Operation::DropTable { table, if_exists } => {
+            node.drop_table(keyspace(&table), &table.name, if_exists)
+                .await?;
+            Ok("OK".to_string())
+        }
+        Operation::Truncate(table) => {
+            node.truncate(keyspace(&table), &table.name).await?;
+            Ok("OK".to_string())
+        }
+    }
+}
+
+// A key-value table takes 'INSERT INTO t (foo) VALUES ("bar")', which sets the key foo,
+// or 'INSERT INTO t (key, value) VALUES ("foo", "bar")'
+fn key_value_pair(columns: Vec<String>, values: Vec<Literal>) -> Result<(String, String)> {
+    match (&columns[..], &values[..]) {
+        ([key], [value]) if *value != Literal::Null => Ok((key.clone(), value.to_string())),
+        ([key_column, value_column], [key, value])
+            if key_column == "key"
+                && value_column == "value"
+                && *key != Literal::Null
+                && *value != Literal::Null =>
+        {
+            Ok((key.to_string(), value.to_string()))
+        }
+        _ => Err(Error::InvalidRequest(
+            "a key-value table takes a single key and value".to_string(),
+        )),
+    }
+}
+
+// 'SELECT foo FROM t;' gets the key foo, 'WHERE key = "foo"' does the same and any other
+// relations on key scan that range
+async fn select_key_value(
+    table: &Table,
+    columns: Option<Vec<String>>,
+    relations: Vec<Relation>,
+    limit: Option<usize>,
+) -> Result<String> {
+    let key = match (&columns, &relations[..]) {
+        (Some(columns), []) if columns.len() == 1 => Some(columns[0].clone()),
+        (_, [relation]) if is_key_equals(relation) => Some(relation.value.to_string()),
+        _ => None,
+    };
+    if let Some(key) = key {
+        return match table.database.get(&key).await? {
+            Some(value) if limit != Some(0) => Ok(value),
+            _ => Ok("Key not found".to_string()),
+        };
+    }
+
+    let mut start = Bound::Unbounded;
+    let mut end = Bound::Unbounded;
+    for relation in relations {
+        let value = relation.value.to_string();
+        match (relation.column.as_str(), relation.operator) {
+            ("key", Operator::Gt) => start = Bound::Excluded(value),
+            ("key", Operator::Ge) => start = Bound::Included(value),
+            ("key", Operator::Lt) => end = Bound::Excluded(value),
+            ("key", Operator::Le) => end = Bound::Included(value),
+            _ => {
+                return Err(Error::InvalidRequest(format!(
+                    "a key-value table can't be queried by {}",
+                    relation.column
+                )))
+            }
+        }
+    }
+    // one "key: value" line per row
+    let rows = table.database.scan((start, end), limit);
+    tokio::pin!(rows);
+    let mut response = String::new();
+    while let Some((key, value)) = rows.next().await.transpose()? {
+        response.push_str(&format!("{}: {}\n", key, value));
+    }
+    if response.is_empty() {
+        response = "No keys found".to_string();
+    }
+    Ok(response)
+}
+
+fn is_key_equals(relation: &Relation) -> bool {
+    relation.column == "key" && relation.operator == Operator::Eq
+}
+
+// The primary key values of the one row 'WHERE pk = ... AND ck = ...' picks
+fn primary_key_values(schema: &TableSchema, relations: &[Relation]) -> Result<Vec<Value>> {
+    let key = schema
+        .primary_key()
+        .map(|name| equal_to(schema, relations, name))
+        .collect::<Result<Option<Vec<_>>>>()?;
+    match key {
+        Some(key) if key.len() == relations.len() => Ok(key),
+        _ => Err(Error::InvalidRequest(format!(
+            "only WHERE {} = ... is supported",
+            schema.primary_key().collect::<Vec<_>>().join(" = ... AND ")
+        ))),
+    }
+}
+
+// The rows the relations and ORDER BY of a SELECT pick: the whole table, or a partition
+// with equal to relations on its first clustering columns and a range of the next one
+fn slice(
+    schema: &TableSchema,
+    relations: &[Relation],
+    order_by: &[(String, ClusteringOrder)],
+) -> Result<Slice> {
+    let partition = schema
+        .partition_key
+        .iter()
+        .map(|name| equal_to(schema, relations, name))
+        .collect::<Result<Option<Vec<_>>>>()?;
+    let Some(partition) = partition else {
+        if !relations.is_empty() || !order_by.is_empty() {
+            return Err(Error::InvalidRequest(format!(
+                "only the rows of a partition can be filtered or ordered, restrict all of {} with =",
+                schema.partition_key.join(", ")
+            )));
+        }
+        return Ok(Slice::all());
+    };
+    let mut slice = Slice::partition(partition);
+    let mut used = schema.partition_key.len();
+    for column in schema.clustering_key.iter() {
+        if let Some(value) = equal_to(schema, relations, &column.name)? {
+            slice.clustering_prefix.push(value);
+            used += 1;
+            continue;
+        }
+        for relation in relations.iter().filter(|r| r.column == column.name) {
+            let value = literal_value(schema, &relation.column, &relation.value)?
+                .ok_or_else(|| Error::InvalidRequest(format!("{} can't be null", column.name)))?;
+            match relation.operator {
+                Operator::Gt => slice.start = Bound::Excluded(value),
+                Operator::Ge => slice.start = Bound::Included(value),
+                Operator::Lt => slice.end = Bound::Excluded(value),
+                Operator::Le => slice.end = Bound::Included(value),
+                Operator::Eq => unreachable!(),
+            }
+            used += 1;
+        }
+        break;
+    }
+    if used != relations.len() {
+        return Err(Error::InvalidRequest(
+            "only = on the partition key and the first clustering columns, and a range of the \
+             clustering column after those, are supported"
+                .to_string(),
+        ));
+    }
+
+    // the ORDER BY has to be the table's clustering order or the reverse of it
+    let ordered_like_table = order_by.len() <= schema.clustering_key.len()
+        && order_by
+            .iter()
+            .zip(schema.clustering_key.iter())
+            .all(|((name, _), column)| *name == column.name);
+    let orders = order_by
+        .iter()
+        .zip(schema.clustering_key.iter())
+        .map(|((_, order), column)| *order == column.order)
+        .collect::<Vec<_>>();
+    slice.reversed =
+        match (ordered_like_table, &orders[..]) {
+            (true, []) => false,
+            (true, [first, ..]) if orders.iter().all(|same| same == first) => !first,
+            _ => return Err(Error::InvalidRequest(
+                "ORDER BY has to list the clustering columns in order, all in their clustering \
+                 order or all in the reverse of it"
+                    .to_string(),
+            )),
+        };
+    Ok(slice)
+}
+
+// The value a column is restricted to with =, if it is
+fn equal_to(schema: &TableSchema, relations: &[Relation], column: &str) -> Result<Option<Value>> {
+    let Some(relation) = relations
+        .iter()
+        .find(|relation| relation.column == column && relation.operator == Operator::Eq)
+    else {
+        return Ok(None);
+    };
+    let value = literal_value(schema, column, &relation.value)?;
+    match value {
+        Some(value) => Ok(Some(value)),
+        None => Err(Error::InvalidRequest(format!("{} can't be null", column))),
+    }
+}
+
+fn literal_value(schema: &TableSchema, column: &str, literal: &Literal) -> Result<Option<Value>> {
+    let column = schema
+        .column(column)
+        .ok_or_else(|| undefined_column(column))?;
+    literal
+        .to_value(column.column_type)
+        .map_err(|reason| Error::InvalidRequest(format!("{} for column {}", reason, column.name)))
+}
+
+fn undefined_column(name: &str) -> Error {
+    Error::InvalidRequest(format!("undefined column name {}", name))
+}
+
+// "id: bob, name: Bob, age: 42"
+fn format_row(row: &Row) -> String {
+    let columns = row
+        .iter()
+        .map(|(name, value)| format!("{}: {}", name, value))
+        .collect::<Vec<_>>();
+    format!("{}\n", columns.join(", "))
+}
@@ -0,0 +1,504 @@
+use std::collections::VecDeque;
+use std::ops::Bound;
+
+use async_stream::try_stream;
+use tokio_stream::{Stream, StreamExt};
+use uuid::Uuid;
+
+use crate::engine::operation::{Cell, Operation};
+use crate::engine::schema::{ClusteringOrder, ColumnDefinition, TableSchema};
+use crate::error::{Error, Result};
+use crate::types::{decode_hex, encode_hex, Value};
+use crate::Database;
+
+/// A row's columns in the order the schema declares them, the primary key included.
+/// Columns the row doesn't have a value for are left out.
+pub type Row = Vec<(String, Value)>;
+
+// A table with a schema stores each column of a row as a cell of its own, under the row's
+// key followed by the column name, so writing a column leaves the others alone. The row key
+// is the partition key values as text, each followed by a \0, and then, for tables with
+// clustering columns, the hex of their values' comparable bytes (inverted for descending
+// columns) and another \0. The rows of a partition sort by their clustering columns right
+// after each other, and every cell of a row before the next row's. Every write also writes
+// a cell with an empty column name, which keeps a row that only has its primary key around.
+const KEY_SEPARATOR: char = '\0';
+const ROW_MARKER: &str = "";
+// Sorts after every hex digit, so a clustering prefix followed by it is past every row
+// starting with that prefix
+const PAST_CLUSTERING_PREFIX: char = 'g';
+
+/// The rows of a table a query reads, in the order the table keeps them in unless
+/// `reversed` is set.
+#[derive(Debug, Clone)]
+pub struct Slice {
+    /// The partition key values, or `None` to read every partition. Partitions come in the
+    /// order of their keys' text.
+    pub partition: Option<Vec<Value>>,
+    /// Values the first clustering columns have to have.
+    pub clustering_prefix: Vec<Value>,
+    /// Bounds on the values of the clustering column after those.
+    pub start: Bound<Value>,
+    pub end: Bound<Value>,
+    pub reversed: bool,
+}
+
+impl Slice {
+    /// Every row of the table.
+    pub fn all() -> Slice {
+        Slice {
+            partition: None,
+            clustering_prefix: Vec::new(),
+            start: Bound::Unbounded,
+            end: Bound::Unbounded,
+            reversed: false,
+        }
+    }
+
+    /// Every row in a partition.
+    pub fn partition(key: Vec<Value>) -> Slice {
+        Slice {
+            partition: Some(key),
+            ..Slice::all()
+        }
+    }
+}
+
+/// A table in a keyspace: a `Database` of its own, with its own MemTable, SSTables and
+/// compaction in `data_dir/<keyspace>/<table>/`. Only the WAL is shared with other tables.
+///
+/// Tables without a schema hold plain key-value pairs and are used through `database`
+/// directly. The row methods are for tables with one.
+pub struct Table {
+    pub keyspace: String,
+    pub name: String,
+    /// What the table's records in the shared WAL are tagged with.
+    pub id: Uuid,
+    pub schema: Option<TableSchema>,
+    pub database: Database,
+}
+
+impl Table {
+    /// The schema, or an error for a key-value table.
+    pub fn schema(&self) -> Result<&TableSchema> {
+        self.schema.as_ref().ok_or_else(|| {
+            Error::InvalidRequest(format!(
+                "{}.{} is a key-value table without columns",
+                self.keyspace, self.name
+            ))
+        })
+    }
+
+    /// Writes the given columns of a row, leaving the rest of its columns as they are. All
+    /// the primary key columns have to be among them, and a `None` for any other column
+    /// deletes it. With a `ttl` other than 0 the values expire after that many seconds.
+    pub async fn insert(
+        &self,
+        columns: Vec<(String, Option<Value>)>,
+        timestamp: u64,
+        ttl: u32,
+    ) -> Result<()> {
+        let schema = self.schema()?;
+        let mut values = Vec::new();
+        for (i, (name, value)) in columns.iter().enumerate() {
+            let column = schema.column(name).ok_or_else(|| unknown_column(name))?;
+            if columns[..i].iter().any(|(other, _)| other == name) {
+                return Err(Error::InvalidRequest(format!(
+                    "column {} is given twice",
+                    name
+                )));
+            }
+            if let Some(value) = value {
+                check_type(value, column)?;
+            }
+            if !schema.is_primary_key(name) {
+                values.push((name, value));
+            }
+        }
+        let key = schema
+            .primary_key()
+            .map(
+                |name| match columns.iter().find(|(other, _)| other == name) {
+                    Some((_, Some(value))) => Ok(value.clone()),
+                    _ => Err(Error::InvalidRequest(format!(
+                        "the primary key column {} is missing",
+                        name
+                    ))),
+                },
+            )
+            .collect::<Result<Vec<_>>>()?;
+        let key = row_key(schema, &key)?;
+
+        let live = |value: String| Cell::new(Operation::expiring(value, ttl), timestamp);
+        let mut cells = vec![(cell_key(&key, ROW_MARKER), live(String::new()))];
+        for (name, value) in values {
+            let cell = match value {
+                Some(value) => live(value.encode()),
+                None => Cell::new(Operation::tombstone(), timestamp),
+            };
+            cells.push((cell_key(&key, name), cell));
+        }
+        self.database.write_cells(cells).await
+    }
+
+    /// Deletes the given columns of the row with the given primary key values, or the whole
+    /// row if `columns` is empty.
+    pub async fn delete(&self, key: &[Value], columns: &[String], timestamp: u64) -> Result<()> {
+        let schema = self.schema()?;
+        let key = row_key(schema, key)?;
+        let mut names = Vec::new();
+        for name in columns {
+            if schema.is_primary_key(name) {
+                return Err(Error::InvalidRequest(format!(
+                    "{} is part of the primary key, delete the row instead",
+                    name
+                )));
+            }
+            names.push(
+                schema
+                    .column(name)
+                    .ok_or_else(|| unknown_column(name))?
+                    .name
+                    .as_str(),
+            );
+        }
+        if names.is_empty() {
+            names = std::iter::once(ROW_MARKER)
+                .chain(
+                    schema
+                        .columns
+                        .iter()
+                        .filter(|column| !schema.is_primary_key(&column.name))
+                        .map(|column| column.name.as_str()),
+                )
+                .collect();
+        }
+        let cells = names
+            .into_iter()
+            .map(|name| {
+                let cell = Cell::new(Operation::tombstone(), timestamp);
+                (cell_key(&key, name), cell)
+            })
+            .collect();
+        self.database.write_cells(cells).await
+    }
+
+    /// Reads the row with the given primary key values.
+    pub async fn get_row(&self, key: &[Value]) -> Result<Option<Row>> {
+        let schema = self.schema()?;
+        let (partition, clustering) = key.split_at(key.len().min(schema.partition_key.len()));
+        if clustering.len() != schema.clustering_key.len() {
+            return Err(Error::InvalidRequest(format!(
+                "a row's key is {} values",
+                schema.partition_key.len() + schema.clustering_key.len()
+            )));
+        }
+        let slice = Slice {
+            clustering_prefix: clustering.to_vec(),
+            ..Slice::partition(partition.to_vec())
+        };
+        let rows = self.select(&slice, Some(1));
+        tokio::pin!(rows);
+        rows.next().await.transpose()
+    }
+
+    /// Streams the rows in a slice of the table, stopping after `limit` rows if one is
+    /// given. A reversed slice is read in full before the first row comes out, keeping
+    /// only the last `limit` rows.
+    pub fn select<'a>(
+        &'a self,
+        slice: &Slice,
+        limit: Option<usize>,
+    ) -> impl Stream<Item = Result<Row>> + 'a {
+        let slice = slice.clone();
+        let limit = limit.unwrap_or(usize::MAX);
+        try_stream! {
+            let schema = self.schema()?;
+            let range = slice_range(schema, &slice)?;
+            let rows = self.rows_in(schema, range);
+            tokio::pin!(rows);
+            if slice.reversed {
+                let mut last_rows = VecDeque::new();
+                while let Some(row) = rows.next().await.transpose()? {
+                    last_rows.push_back(row);
+                    if last_rows.len() > limit {
+                        last_rows.pop_front();
+                    }
+                }
+                for row in last_rows.into_iter().rev() {
+                    yield row;
+                }
+            } else {
+                let mut returned = 0;
+                while returned < limit {
+                    match rows.next().await.transpose()? {
+                        Some(row) => yield row,
+                        None => break,
+                    }
+                    returned += 1;
+                }
+            }
+        }
+    }
+
+    // The rows with cells in the range of keys, in key order
+    fn rows_in<'a>(
+        &'a self,
+        schema: &'a TableSchema,
+        range: (Bound<String>, Bound<String>),
+    ) -> impl Stream<Item = Result<Row>> + 'a {
+        try_stream! {
+            let cells = self.database.scan(range, None);
+            tokio::pin!(cells);
+            // the cells of the row being put together
+            let mut row: Option<(String, Vec<(String, String)>)> = None;
+            loop {
+                let next = cells.next().await.transpose()?;
+                let next = match next {
+                    Some((cell_key, value)) => {
+                        let (key, column) = split_cell_key(&cell_key)
+                            .map_err(|reason| self.corruption(reason))?;
+                        Some((key.to_string(), column.to_string(), value))
+                    }
+                    None => None,
+                };
+                let same_row = matches!(
+                    (&row, &next),
+                    (Some((key, _)), Some((next_key, _, _))) if key == next_key
+                );
+                if !same_row {
+                    if let Some((key, row_cells)) = row.take() {
+                        yield self.decode_row(schema, &key, row_cells)?;
+                    }
+                }
+                match next {
+                    Some((key, column, value)) => {
+                        row.get_or_insert_with(|| (key, Vec::new())).1.push((column, value))
+                    }
+                    None => break,
+                }
+            }
+        }
+    }
+
+    // Puts a row together from its key and its live cells
+    fn decode_row(
+        &self,
+        schema: &TableSchema,
+        key: &str,
+        cells: Vec<(String, String)>,
+    ) -> Result<Row> {
+        let key = decode_row_key(schema, key).map_err(|reason| self.corruption(reason))?;
+        let mut row = Vec::with_capacity(schema.columns.len());
+        for column in schema.columns.iter() {
+            let value = match key.iter().find(|(name, _)| *name == column.name) {
+                Some((_, value)) => value.clone(),
+                None => match cells.iter().find(|(name, _)| *name == column.name) {
+                    Some((_, encoded)) => column
+                        .column_type
+                        .decode(encoded)
+                        .map_err(|reason| self.corruption(reason))?,
+                    None => continue,
+                },
+            };
+            row.push((column.name.clone(), value));
+        }
+        Ok(row)
+    }
+
+    fn corruption(&self, reason: String) -> Error {
+        Error::corruption(&self.database.config.data_dir, 0, reason)
+    }
+}
+
+// The key the cells of the row with these primary key values are stored under
+fn row_key(schema: &TableSchema, key: &[Value]) -> Result<String> {
+    if key.len() != schema.partition_key.len() + schema.clustering_key.len() {
+        return Err(Error::InvalidRequest(format!(
+            "a row's key is {} values",
+            schema.partition_key.len() + schema.clustering_key.len()
+        )));
+    }
+    let (partition, clustering) = key.split_at(schema.partition_key.len());
+    let mut row_key = partition_key(schema, partition)?;
+    if !schema.clustering_key.is_empty() {
+        row_key.push_str(&clustering_key(schema, clustering)?);
+        row_key.push(KEY_SEPARATOR);
+    }
+    Ok(row_key)
+}
+
+// What the keys of a partition's cells start with
+fn partition_key(schema: &TableSchema, values: &[Value]) -> Result<String> {
+    if values.len() != schema.partition_key.len() {
+        return Err(Error::InvalidRequest(format!(
+            "a partition's key is {} values",
+            schema.partition_key.len()
+        )));
+    }
+    let mut key = String::new();
+    for (name, value) in schema.partition_key.iter().zip(values) {
+        check_type(value, schema.column(name).unwrap())?;
+        let encoded = value.encode();
+        if encoded.contains(KEY_SEPARATOR) {
+            return Err(Error::InvalidRequest(
+                "partition keys can't contain NUL characters".to_string(),
+            ));
+        }
+        key.push_str(&encoded);
+        key.push(KEY_SEPARATOR);
+    }
+    Ok(key)
+}
+
+// The hex of the comparable bytes of the first clustering columns' values
+fn clustering_key(schema: &TableSchema, values: &[Value]) -> Result<String> {
+    if values.len() > schema.clustering_key.len() {
+        return Err(Error::InvalidRequest(format!(
+            "the table has {} clustering columns",
+            schema.clustering_key.len()
+        )));
+    }
+    let mut bytes = Vec::new();
+    for (column, value) in schema.clustering_key.iter().zip(values) {
+        check_type(value, schema.column(&column.name).unwrap())?;
+        let start = bytes.len();
+        value.write_comparable(&mut bytes);
+        if column.order == ClusteringOrder::Desc {
+            bytes[start..].iter_mut().for_each(|byte| *byte = !*byte);
+        }
+    }
+    Ok(encode_hex(&bytes))
+}
+
+// The primary key values a row key stands for, by column name
+fn decode_row_key(
+    schema: &TableSchema,
+    key: &str,
+) -> std::result::Result<Vec<(String, Value)>, String> {
+    let mut parts = key.split(KEY_SEPARATOR);
+    let mut values = Vec::new();
+    for name in schema.partition_key.iter() {
+        let encoded = parts
+            .next()
+            .ok_or("row key is missing partition key values")?;
+        let column = schema.column(name).unwrap();
+        values.push((name.clone(), column.column_type.decode(encoded)?));
+    }
+    if schema.clustering_key.is_empty() {
+        return Ok(values);
+    }
+    let hex = parts.next().ok_or("row key is missing clustering values")?;
+    let bytes = decode_hex(hex).ok_or("row key has bad clustering values")?;
+    let mut rest = &bytes[..];
+    for clustering in schema.clustering_key.iter() {
+        let column_type = schema.column(&clustering.name).unwrap().column_type;
+        let value = match clustering.order {
+            ClusteringOrder::Asc => {
+                let (value, after) = column_type.read_comparable(rest)?;
+                rest = after;
+                value
+            }
+            ClusteringOrder::Desc => {
+                let inverted = rest.iter().map(|byte| !byte).collect::<Vec<_>>();
+                let (value, after) = column_type.read_comparable(&inverted)?;
+                rest = &rest[rest.len() - after.len()..];
+                value
+            }
+        };
+        values.push((clustering.name.clone(), value));
+    }
+    match rest.is_empty() {
+        true => Ok(values),
+        false => Err("row key has trailing clustering bytes".to_string()),
+    }
+}
+
+// The range of cell keys the rows of a slice are in
+fn slice_range(schema: &TableSchema, slice: &Slice) -> Result<(Bound<String>, Bound<String>)> {
+    let has_range = !matches!(
+        (&slice.start, &slice.end),
+        (Bound::Unbounded, Bound::Unbounded)
+    );
+    let Some(partition) = &slice.partition else {
+        if !slice.clustering_prefix.is_empty() || has_range {
+            return Err(Error::InvalidRequest(
+                "clustering columns can only be restricted within a partition".to_string(),
+            ));
+        }
+        return Ok((Bound::Unbounded, Bound::Unbounded));
+    };
+    let partition = partition_key(schema, partition)?;
+    if slice.clustering_prefix.is_empty() && !has_range {
+        // past the \0 the partition's key ends with
+        let mut end = partition.clone();
+        end.pop();
+        end.push(char::from(KEY_SEPARATOR as u8 + 1));
+        return Ok((Bound::Included(partition), Bound::Excluded(end)));
+    }
+
+    let prefix = format!(
+        "{}{}",
+        partition,
+        clustering_key(schema, &slice.clustering_prefix)?
+    );
+    let mut start = Bound::Included(prefix.clone());
+    let mut end = Bound::Excluded(format!("{}{}", prefix, PAST_CLUSTERING_PREFIX));
+    if has_range {
+        let Some(column) = schema.clustering_key.get(slice.clustering_prefix.len()) else {
+            return Err(Error::InvalidRequest(
+                "the range is past the last clustering column".to_string(),
+            ));
+        };
+        // the keys of the rows before and after the ones with a value in the column
+        let keys = |value: &Value| -> Result<(String, String)> {
+            let mut values = slice.clustering_prefix.clone();
+            values.push(value.clone());
+            let before = format!("{}{}", partition, clustering_key(schema, &values)?);
+            let after = format!("{}{}", before, PAST_CLUSTERING_PREFIX);
+            Ok((before, after))
+        };
+        // a descending column's lower values come later
+        let (lower, upper) = match column.order {
+            ClusteringOrder::Asc => (&slice.start, &slice.end),
+            ClusteringOrder::Desc => (&slice.end, &slice.start),
+        };
+        match lower {
+            Bound::Included(value) => start = Bound::Included(keys(value)?.0),
+            Bound::Excluded(value) => start = Bound::Included(keys(value)?.1),
+            Bound::Unbounded => {}
+        }
+        match upper {
+            Bound::Included(value) => end = Bound::Excluded(keys(value)?.1),
+            Bound::Excluded(value) => end = Bound::Excluded(keys(value)?.0),
+            Bound::Unbounded => {}
+        }
+    }
+    Ok((start, end))
+}
+
+fn cell_key(row_key: &str, column: &str) -> String {
+    format!("{}{}", row_key, column)
+}
+
+// The row key and column name a cell is stored under
+fn split_cell_key(cell_key: &str) -> std::result::Result<(&str, &str), String> {
+    match cell_key.rfind(KEY_SEPARATOR) {
+        Some(i) => Ok(cell_key.split_at(i + 1)),
+        None => Err(format!("{:?} is not the key of a row's cell", cell_key)),
+    }
+}
+
+fn check_type(value: &Value, column: &ColumnDefinition) -> Result<()> {
+    match value.column_type() == column.column_type {
+        true => Ok(()),
+        false => Err(Error::InvalidRequest(format!(
+            "{} is not a valid {} for column {}",
+            value, column.column_type, column.name
+        ))),
+    }
+}
+
+fn unknown_column(name: &str) -> Error {
+    Error::InvalidRequest(format!("undefined column name {}", name))
+}
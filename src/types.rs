@@ -0,0 +1,332 @@
+use std::fmt::Display;
+
+use uuid::Uuid;
+
+/// The types a column can have, named like their CQL counterparts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColumnType {
+    Text,
+    Int,
+    BigInt,
+    Boolean,
+    Double,
+    Uuid,
+    /// Milliseconds since the epoch.
+    Timestamp,
+    Blob,
+}
+
+const COLUMN_TYPES: [ColumnType; 8] = [
+    ColumnType::Text,
+    ColumnType::Int,
+    ColumnType::BigInt,
+    ColumnType::Boolean,
+    ColumnType::Double,
+    ColumnType::Uuid,
+    ColumnType::Timestamp,
+    ColumnType::Blob,
+];
+
+impl ColumnType {
+    /// The byte the type is recorded as in the schema file.
+    pub fn id(self) -> u8 {
+        match self {
+            ColumnType::Text => 0,
+            ColumnType::Int => 1,
+            ColumnType::BigInt => 2,
+            ColumnType::Boolean => 3,
+            ColumnType::Double => 4,
+            ColumnType::Uuid => 5,
+            ColumnType::Timestamp => 6,
+            ColumnType::Blob => 7,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<ColumnType> {
+        COLUMN_TYPES
+            .into_iter()
+            .find(|column_type| column_type.id() == id)
+    }
+
+    /// The type's name in CQL.
+    pub fn name(self) -> &'static str {
+        match self {
+            ColumnType::Text => "text",
+            ColumnType::Int => "int",
+            ColumnType::BigInt => "bigint",
+            ColumnType::Boolean => "boolean",
+            ColumnType::Double => "double",
+            ColumnType::Uuid => "uuid",
+            ColumnType::Timestamp => "timestamp",
+            ColumnType::Blob => "blob",
+        }
+    }
+
+    /// Looks a type up by its CQL name, ignoring case.
+    pub fn from_name(name: &str) -> Option<ColumnType> {
+        COLUMN_TYPES
+            .into_iter()
+            .find(|column_type| column_type.name().eq_ignore_ascii_case(name))
+    }
+
+    /// Turns a value stored with `Value::encode` back into a value of this type, or
+    /// returns why it isn't one.
+    pub fn decode(self, encoded: &str) -> Result<Value, String> {
+        let invalid = || format!("{:?} is not a valid {}", encoded, self.name());
+        let value = match self {
+            ColumnType::Text => Value::Text(encoded.to_string()),
+            ColumnType::Int => Value::Int(encoded.parse().map_err(|_| invalid())?),
+            ColumnType::BigInt => Value::BigInt(encoded.parse().map_err(|_| invalid())?),
+            ColumnType::Boolean => Value::Boolean(encoded.parse().map_err(|_| invalid())?),
+            ColumnType::Double => Value::Double(encoded.parse().map_err(|_| invalid())?),
+            ColumnType::Uuid => Value::Uuid(Uuid::parse_str(encoded).map_err(|_| invalid())?),
+            ColumnType::Timestamp => Value::Timestamp(encoded.parse().map_err(|_| invalid())?),
+            ColumnType::Blob => Value::Blob(
+                decode_hex(encoded.strip_prefix("0x").ok_or_else(invalid)?).ok_or_else(invalid)?,
+            ),
+        };
+        Ok(value)
+    }
+
+    /// Reads a value written with `Value::write_comparable` off the front of `bytes`,
+    /// returning it and the bytes after it.
+    pub fn read_comparable(self, bytes: &[u8]) -> Result<(Value, &[u8]), String> {
+        let fixed = |length: usize| match bytes.len() >= length {
+            true => Ok(bytes.split_at(length)),
+            false => Err(format!("truncated {}", self.name())),
+        };
+        let value = match self {
+            ColumnType::Int => {
+                let (value, rest) = fixed(4)?;
+                let value = u32::from_be_bytes(value.try_into().unwrap()) ^ (1 << 31);
+                (Value::Int(value as i32), rest)
+            }
+            ColumnType::BigInt | ColumnType::Timestamp => {
+                let (value, rest) = fixed(8)?;
+                let value = (u64::from_be_bytes(value.try_into().unwrap()) ^ (1 << 63)) as i64;
+                match self {
+                    ColumnType::BigInt => (Value::BigInt(value), rest),
+                    _ => (Value::Timestamp(value), rest),
+                }
+            }
+            ColumnType::Boolean => {
+                let (value, rest) = fixed(1)?;
+                (Value::Boolean(value[0] != 0), rest)
+            }
+            ColumnType::Double => {
+                let (value, rest) = fixed(8)?;
+                let bits = u64::from_be_bytes(value.try_into().unwrap());
+                let bits = match bits & (1 << 63) {
+                    0 => !bits,
+                    _ => bits ^ (1 << 63),
+                };
+                (Value::Double(f64::from_bits(bits)), rest)
+            }
+            ColumnType::Uuid => {
+                let (value, rest) = fixed(16)?;
+                (Value::Uuid(Uuid::from_slice(value).unwrap()), rest)
+            }
+            ColumnType::Text | ColumnType::Blob => {
+                let mut value = Vec::new();
+                let mut i = 0;
+                loop {
+                    match (bytes.get(i), bytes.get(i + 1)) {
+                        (Some(0), Some(0)) => break,
+                        (Some(0), Some(0xff)) => {
+                            value.push(0);
+                            i += 2;
+                        }
+                        (Some(0), _) | (None, _) => {
+                            return Err(format!("unterminated {}", self.name()))
+                        }
+                        (Some(byte), _) => {
+                            value.push(*byte);
+                            i += 1;
+                        }
+                    }
+                }
+                let value = match self {
+                    ColumnType::Text => Value::Text(
+                        String::from_utf8(value).map_err(|_| "text is not valid UTF-8")?,
+                    ),
+                    _ => Value::Blob(value),
+                };
+                (value, &bytes[i + 2..])
+            }
+        };
+        Ok(value)
+    }
+}
+
+impl Display for ColumnType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// A column value.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Text(String),
+    Int(i32),
+    BigInt(i64),
+    Boolean(bool),
+    Double(f64),
+    Uuid(Uuid),
+    /// Milliseconds since the epoch.
+    Timestamp(i64),
+    Blob(Vec<u8>),
+}
+
+impl Value {
+    pub fn column_type(&self) -> ColumnType {
+        match self {
+            Value::Text(_) => ColumnType::Text,
+            Value::Int(_) => ColumnType::Int,
+            Value::BigInt(_) => ColumnType::BigInt,
+            Value::Boolean(_) => ColumnType::Boolean,
+            Value::Double(_) => ColumnType::Double,
+            Value::Uuid(_) => ColumnType::Uuid,
+            Value::Timestamp(_) => ColumnType::Timestamp,
+            Value::Blob(_) => ColumnType::Blob,
+        }
+    }
+
+    /// The value as it's stored in a cell, which is how it's displayed too. The column
+    /// type's `decode` turns it back into the value.
+    pub fn encode(&self) -> String {
+        self.to_string()
+    }
+
+    /// Appends bytes that compare the way the values do, with no value's bytes a prefix of
+    /// another's, so values written one after another sort like a tuple of them would.
+    /// Numbers are big-endian with the sign flipped, and text and blobs end in 00 00 with
+    /// the zeroes in them written as 00 ff.
+    pub fn write_comparable(&self, buf: &mut Vec<u8>) {
+        match self {
+            Value::Int(value) => {
+                buf.extend_from_slice(&((*value as u32) ^ (1 << 31)).to_be_bytes())
+            }
+            Value::BigInt(value) | Value::Timestamp(value) => {
+                buf.extend_from_slice(&((*value as u64) ^ (1 << 63)).to_be_bytes())
+            }
+            Value::Boolean(value) => buf.push(*value as u8),
+            Value::Double(value) => {
+                // negative doubles sort backwards by their bits, so flip all of them
+                let bits = value.to_bits();
+                let bits = match bits & (1 << 63) {
+                    0 => bits ^ (1 << 63),
+                    _ => !bits,
+                };
+                buf.extend_from_slice(&bits.to_be_bytes())
+            }
+            Value::Uuid(value) => buf.extend_from_slice(value.as_bytes()),
+            Value::Text(value) => write_escaped(value.as_bytes(), buf),
+            Value::Blob(bytes) => write_escaped(bytes, buf),
+        }
+    }
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::Text(value) => write!(f, "{}", value),
+            Value::Int(value) => write!(f, "{}", value),
+            Value::BigInt(value) => write!(f, "{}", value),
+            Value::Boolean(value) => write!(f, "{}", value),
+            // shortest form that parses back to the same double
+            Value::Double(value) => write!(f, "{}", value),
+            Value::Uuid(value) => write!(f, "{}", value.hyphenated()),
+            Value::Timestamp(value) => write!(f, "{}", value),
+            Value::Blob(bytes) => write!(f, "0x{}", encode_hex(bytes)),
+        }
+    }
+}
+
+fn write_escaped(bytes: &[u8], buf: &mut Vec<u8>) {
+    for byte in bytes {
+        match byte {
+            0 => buf.extend_from_slice(&[0, 0xff]),
+            byte => buf.push(*byte),
+        }
+    }
+    buf.extend_from_slice(&[0, 0]);
+}
+
+/// Encodes bytes as lowercase hex digits, without the 0x. The digits sort like the bytes.
+pub fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Decodes hex digits (without the 0x) into bytes.
+pub fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn comparable(value: &Value) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        value.write_comparable(&mut bytes);
+        bytes
+    }
+
+    #[test]
+    fn test_comparable_bytes_sort_like_values() {
+        let sorted = [
+            vec![
+                Value::Int(i32::MIN),
+                Value::Int(-1),
+                Value::Int(0),
+                Value::Int(7),
+            ],
+            vec![
+                Value::BigInt(-300),
+                Value::BigInt(2),
+                Value::BigInt(i64::MAX),
+            ],
+            vec![
+                Value::Double(f64::NEG_INFINITY),
+                Value::Double(-2.5),
+                Value::Double(-0.5),
+                Value::Double(0.0),
+                Value::Double(1e-9),
+                Value::Double(3.0),
+            ],
+            vec![Value::Boolean(false), Value::Boolean(true)],
+            vec![
+                Value::Text("".to_string()),
+                Value::Text("a".to_string()),
+                Value::Text("a\0".to_string()),
+                Value::Text("a\0b".to_string()),
+                Value::Text("ab".to_string()),
+            ],
+            vec![
+                Value::Blob(vec![]),
+                Value::Blob(vec![0]),
+                Value::Blob(vec![0, 0]),
+            ],
+        ];
+        for values in sorted.iter() {
+            for pair in values.windows(2) {
+                assert!(comparable(&pair[0]) < comparable(&pair[1]), "{:?}", pair);
+            }
+            for value in values {
+                // with something after it, which it has to know to stop before
+                let mut bytes = comparable(value);
+                bytes.push(0x42);
+                let (decoded, rest) = value.column_type().read_comparable(&bytes).unwrap();
+                assert_eq!(&decoded, value);
+                assert_eq!(rest, &[0x42]);
+            }
+        }
+    }
+}
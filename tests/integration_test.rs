@@ -1,127 +1,191 @@
-use kassantra::engine::operation::Operation;
-use kassantra::Database;
+use kassantra::engine::compaction::{
+    CompactionConfig, CompactionStrategy, CompactionTask, LeveledCompactionStrategy,
+    SizeTieredCompactionStrategy, TimeWindowCompactionStrategy, TimeWindowUnit,
+};
+use kassantra::engine::compression::Compression;
+use kassantra::engine::operation::{next_timestamp, Cell, Operation};
+use kassantra::engine::sstable::{SSTable, SSTABLE_FORMAT_VERSION};
+use kassantra::engine::wal::WalSyncMode;
+use kassantra::keyspace::{DEFAULT_KEYSPACE, DEFAULT_TABLE};
+use kassantra::ql::parser::Operation as Statement;
+use kassantra::table::Slice;
+use kassantra::types::Value;
+use kassantra::{CorruptionPolicy, Database, DatabaseConfig, Error, Node, Table};
+use std::ops::Bound;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio_stream::StreamExt;
 use uuid::Uuid;
 
 #[tokio::test]
 async fn test_wal_replay() {
     let ctx = setup().await;
-    let database = Database::new(&ctx.data_dir);
+    let database = Database::new(&ctx.data_dir).unwrap();
 
-    database.set("foo".to_string(), "bar".to_string()).await;
-    database.set("foo".to_string(), "baz".to_string()).await;
+    database
+        .set("foo".to_string(), "bar".to_string())
+        .await
+        .unwrap();
+    database
+        .set("foo".to_string(), "baz".to_string())
+        .await
+        .unwrap();
 
     let ctx2 = setup().await;
 
-    let database2 = Database::new(&ctx2.data_dir);
+    let database2 = Database::new(&ctx2.data_dir).unwrap();
 
-    assert_eq!(database2.get("foo").await, None);
+    assert_eq!(database2.get("foo").await.unwrap(), None);
 
     database2
         .replay_from_wal(database.wal_path().await.as_str())
-        .await;
+        .await
+        .unwrap();
 
-    assert_eq!(database2.get("foo").await, Some("baz".to_string()));
+    assert_eq!(database2.get("foo").await.unwrap(), Some("baz".to_string()));
 }
 
 #[tokio::test]
 async fn test_read_from_sstable_when_memtable_is_empty() {
     let ctx = setup().await;
-    let database = Database::new(ctx.data_dir.as_str());
+    let database = Database::new(ctx.data_dir.as_str()).unwrap();
 
-    database.set("foo".to_string(), "bar".to_string()).await;
+    database
+        .set("foo".to_string(), "bar".to_string())
+        .await
+        .unwrap();
 
     database.flush_memtable_to_sstable().await.unwrap();
 
-    assert_eq!(database.memtable_is_empty().await, true);
+    assert!(database.memtable_is_empty().await);
 
-    assert_eq!(database.get("foo").await, Some("bar".to_string()));
+    assert_eq!(database.get("foo").await.unwrap(), Some("bar".to_string()));
 }
 
 #[tokio::test]
 async fn test_read_from_sstable_find_in_older_sstable() {
     let ctx = setup().await;
-    let database = Database::new(ctx.data_dir.as_str());
+    let database = Database::new(ctx.data_dir.as_str()).unwrap();
 
-    database.set("foo".to_string(), "bar".to_string()).await;
+    database
+        .set("foo".to_string(), "bar".to_string())
+        .await
+        .unwrap();
 
     database.flush_memtable_to_sstable().await.unwrap();
 
-    database.set("boo".to_string(), "waz".to_string()).await;
+    database
+        .set("boo".to_string(), "waz".to_string())
+        .await
+        .unwrap();
 
     database.flush_memtable_to_sstable().await.unwrap();
 
-    assert_eq!(database.memtable_is_empty().await, true);
+    assert!(database.memtable_is_empty().await);
 
-    assert_eq!(database.get("foo").await, Some("bar".to_string()));
-    assert_eq!(database.get("boo").await, Some("waz".to_string()));
+    assert_eq!(database.get("foo").await.unwrap(), Some("bar".to_string()));
+    assert_eq!(database.get("boo").await.unwrap(), Some("waz".to_string()));
 }
 
 #[tokio::test]
 async fn test_deletions_work_in_memtable() {
     let ctx = setup().await;
-    let database = Database::new(ctx.data_dir.as_str());
+    let database = Database::new(ctx.data_dir.as_str()).unwrap();
 
-    database.set("foo".to_string(), "bar".to_string()).await;
-    database.set("boo".to_string(), "waz".to_string()).await;
+    database
+        .set("foo".to_string(), "bar".to_string())
+        .await
+        .unwrap();
+    database
+        .set("boo".to_string(), "waz".to_string())
+        .await
+        .unwrap();
 
-    database.delete(&"foo".to_string()).await;
+    database.delete("foo").await.unwrap();
 
-    assert_eq!(database.get("foo").await, None);
-    assert_eq!(database.get("boo").await, Some("waz".to_string()));
+    assert_eq!(database.get("foo").await.unwrap(), None);
+    assert_eq!(database.get("boo").await.unwrap(), Some("waz".to_string()));
 }
 
 #[tokio::test]
 async fn test_deletions_work_in_sstable() {
     let ctx = setup().await;
-    let database = Database::new(ctx.data_dir.as_str());
+    let database = Database::new(ctx.data_dir.as_str()).unwrap();
 
-    database.set("foo".to_string(), "bar".to_string()).await;
-    database.set("boo".to_string(), "waz".to_string()).await;
+    database
+        .set("foo".to_string(), "bar".to_string())
+        .await
+        .unwrap();
+    database
+        .set("boo".to_string(), "waz".to_string())
+        .await
+        .unwrap();
 
     database.flush_memtable_to_sstable().await.unwrap();
 
-    database.delete(&"foo".to_string()).await;
+    database.delete("foo").await.unwrap();
 
-    assert_eq!(database.get("foo").await, None);
-    assert_eq!(database.get("boo").await, Some("waz".to_string()));
+    assert_eq!(database.get("foo").await.unwrap(), None);
+    assert_eq!(database.get("boo").await.unwrap(), Some("waz".to_string()));
 }
 
 #[tokio::test]
 async fn test_updates_work_in_memtable() {
     let ctx = setup().await;
-    let database = Database::new(ctx.data_dir.as_str());
-
-    database.set("foo".to_string(), "bar".to_string()).await;
-    database.set("foo".to_string(), "baz".to_string()).await;
-
-    assert_eq!(database.get("foo").await, Some("baz".to_string()));
+    let database = Database::new(ctx.data_dir.as_str()).unwrap();
+
+    database
+        .set("foo".to_string(), "bar".to_string())
+        .await
+        .unwrap();
+    database
+        .set("foo".to_string(), "baz".to_string())
+        .await
+        .unwrap();
+
+    assert_eq!(database.get("foo").await.unwrap(), Some("baz".to_string()));
 }
 
 #[tokio::test]
 async fn test_updates_work_in_sstable() {
     let ctx = setup().await;
-    let database = Database::new(ctx.data_dir.as_str());
+    let database = Database::new(ctx.data_dir.as_str()).unwrap();
 
-    database.set("foo".to_string(), "bar".to_string()).await;
-    database.set("foo".to_string(), "baz".to_string()).await;
+    database
+        .set("foo".to_string(), "bar".to_string())
+        .await
+        .unwrap();
+    database
+        .set("foo".to_string(), "baz".to_string())
+        .await
+        .unwrap();
 
     database.flush_memtable_to_sstable().await.unwrap();
 
-    assert_eq!(database.get("foo").await, Some("baz".to_string()));
+    assert_eq!(database.get("foo").await.unwrap(), Some("baz".to_string()));
 }
 
 #[tokio::test]
 async fn test_sstable_entries_are_written_in_alphabetical_order() {
     let ctx = setup().await;
-    let database = Database::new(ctx.data_dir.as_str());
-
-    database.set("foo".to_string(), "bar".to_string()).await;
-    database.set("boo".to_string(), "waz".to_string()).await;
-    database.set("baz".to_string(), "qux".to_string()).await;
+    let database = Database::new(ctx.data_dir.as_str()).unwrap();
+
+    database
+        .set("foo".to_string(), "bar".to_string())
+        .await
+        .unwrap();
+    database
+        .set("boo".to_string(), "waz".to_string())
+        .await
+        .unwrap();
+    database
+        .set("baz".to_string(), "qux".to_string())
+        .await
+        .unwrap();
 
     database.flush_memtable_to_sstable().await.unwrap();
 
-    assert_eq!(database.memtable_is_empty().await, true);
+    assert!(database.memtable_is_empty().await);
 
     let mut sstables = database.sstables.lock().await;
     let sstable = &mut sstables[0];
@@ -136,31 +200,47 @@ async fn test_sstable_entries_are_written_in_alphabetical_order() {
 #[tokio::test]
 async fn test_sstable_compaction() {
     let ctx = setup().await;
-    let database = Database::new(ctx.data_dir.as_str());
-
-    database.set("foo".to_string(), "bar".to_string()).await;
-    database.set("boo".to_string(), "waz".to_string()).await;
-    database.set("baz".to_string(), "qux".to_string()).await;
+    let database = Database::new(ctx.data_dir.as_str()).unwrap();
+
+    database
+        .set("foo".to_string(), "bar".to_string())
+        .await
+        .unwrap();
+    database
+        .set("boo".to_string(), "waz".to_string())
+        .await
+        .unwrap();
+    database
+        .set("baz".to_string(), "qux".to_string())
+        .await
+        .unwrap();
 
     database.flush_memtable_to_sstable().await.unwrap();
 
-    database.set("foo".to_string(), "baz2".to_string()).await;
-    database.set("boo".to_string(), "waz2".to_string()).await;
-    database.delete(&"baz".to_string()).await;
+    database
+        .set("foo".to_string(), "baz2".to_string())
+        .await
+        .unwrap();
+    database
+        .set("boo".to_string(), "waz2".to_string())
+        .await
+        .unwrap();
+    database.delete("baz").await.unwrap();
 
     database.flush_memtable_to_sstable().await.unwrap();
 
-    assert_eq!(database.memtable_is_empty().await, true);
+    assert!(database.memtable_is_empty().await);
 
     database.compact_sstables().await.unwrap();
 
     let mut sstables = database.sstables.lock().await;
     let sstable = &mut sstables[0];
 
-    let operations = sstable.read_all().await.unwrap();
+    let operations = read_operations(sstable).await;
 
     assert!(operations.len() == 3);
-    assert_eq!(operations[0], ("baz".to_string(), Operation::Delete));
+    assert_eq!(operations[0].0, "baz");
+    assert!(matches!(operations[0].1, Operation::Delete(_)));
     assert_eq!(
         operations[1],
         ("boo".to_string(), Operation::Insert("waz2".to_string()))
@@ -177,17 +257,35 @@ async fn test_sstable_compaction() {
 #[tokio::test]
 async fn test_sstable_compaction_keys_are_ordered_after_compaction() {
     let ctx = setup().await;
-    let database = Database::new(ctx.data_dir.as_str());
-
-    database.set("fff".to_string(), "fff".to_string()).await;
-    database.set("eee".to_string(), "eee".to_string()).await;
-    database.set("ddd".to_string(), "ddd".to_string()).await;
+    let database = Database::new(ctx.data_dir.as_str()).unwrap();
+
+    database
+        .set("fff".to_string(), "fff".to_string())
+        .await
+        .unwrap();
+    database
+        .set("eee".to_string(), "eee".to_string())
+        .await
+        .unwrap();
+    database
+        .set("ddd".to_string(), "ddd".to_string())
+        .await
+        .unwrap();
 
     database.flush_memtable_to_sstable().await.unwrap();
 
-    database.set("ccc".to_string(), "ccc".to_string()).await;
-    database.set("bbb".to_string(), "bbb".to_string()).await;
-    database.set("aaa".to_string(), "aaa".to_string()).await;
+    database
+        .set("ccc".to_string(), "ccc".to_string())
+        .await
+        .unwrap();
+    database
+        .set("bbb".to_string(), "bbb".to_string())
+        .await
+        .unwrap();
+    database
+        .set("aaa".to_string(), "aaa".to_string())
+        .await
+        .unwrap();
 
     database.flush_memtable_to_sstable().await.unwrap();
 
@@ -197,7 +295,7 @@ async fn test_sstable_compaction_keys_are_ordered_after_compaction() {
 
     let sstable = &mut sstables[0];
 
-    let operations = sstable.read_all().await.unwrap();
+    let operations = read_operations(sstable).await;
 
     println!("{:?}", operations);
     assert!(operations.len() == 6);
@@ -228,6 +326,2459 @@ async fn test_sstable_compaction_keys_are_ordered_after_compaction() {
     );
 }
 
+#[tokio::test]
+async fn test_sstable_index_is_loaded_after_restart() {
+    let ctx = setup().await;
+    let database = Database::new(ctx.data_dir.as_str()).unwrap();
+
+    for i in 0..35 {
+        database
+            .set(format!("key{:02}", i), format!("value{}", i))
+            .await
+            .unwrap();
+    }
+    database.flush_memtable_to_sstable().await.unwrap();
+    drop(database);
+
+    let database = Database::load(ctx.data_dir.as_str()).await.unwrap();
+    assert!(database.memtable_is_empty().await);
+
+    for i in 0..35 {
+        assert_eq!(
+            database.get(&format!("key{:02}", i)).await.unwrap(),
+            Some(format!("value{}", i))
+        );
+    }
+    assert_eq!(database.get("key99").await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn test_sstable_sections_are_found_through_the_footer() {
+    let ctx = setup().await;
+    let database = Database::new(ctx.data_dir.as_str()).unwrap();
+
+    for i in 0..25 {
+        database
+            .set(format!("key{:02}", i), format!("value{}", i))
+            .await
+            .unwrap();
+    }
+    database.delete("key07").await.unwrap();
+    database.flush_memtable_to_sstable().await.unwrap();
+    let stats = database.sstables.lock().await[0].stats().clone();
+    drop(database);
+
+    assert_eq!(stats.entry_count, 25);
+    assert_eq!(stats.tombstone_count, 1);
+    assert_eq!(
+        stats.key_range,
+        Some(("key00".to_string(), "key24".to_string()))
+    );
+    assert_eq!(stats.level, 0);
+    assert!(stats.created_at > 0 && stats.max_write_time >= stats.created_at);
+
+    let database = Database::load(ctx.data_dir.as_str()).await.unwrap();
+    assert_eq!(database.sstables.lock().await[0].stats(), &stats);
+    assert_eq!(
+        database.get("key13").await.unwrap(),
+        Some("value13".to_string())
+    );
+    assert_eq!(database.get("key07").await.unwrap(), None);
+    drop(database);
+}
+
+#[tokio::test]
+async fn test_damaged_sstable_sections_are_rebuilt_from_the_data_blocks() {
+    let ctx = setup().await;
+    let config = DatabaseConfig::builder()
+        .data_dir(&ctx.data_dir)
+        .sstable_block_size_bytes(64)
+        .build()
+        .unwrap();
+    let database = Database::new(config.clone()).unwrap();
+
+    for i in 0..25 {
+        database
+            .set(format!("key{:02}", i), format!("value{}", i))
+            .await
+            .unwrap();
+    }
+    database.delete("key07").await.unwrap();
+    database.flush_memtable_to_sstable().await.unwrap();
+    let (sstable_path, stats) = {
+        let sstables = database.sstables.lock().await;
+        (sstables[0].get_path(), sstables[0].stats().clone())
+    };
+    drop(database);
+
+    let assert_rebuilt = |stats: &kassantra::engine::sstable::SSTableStats| {
+        assert_eq!(stats.entry_count, 25);
+        assert_eq!(stats.tombstone_count, 1);
+        assert_eq!(
+            stats.key_range,
+            Some(("key00".to_string(), "key24".to_string()))
+        );
+    };
+    // the footer's checksum covers the sections, which start with the index, and a bad
+    // footer has to do without knowing where they are
+    let file_length = std::fs::metadata(&sstable_path).unwrap().len() as usize;
+    let footer = file_length - 36;
+    let bytes = std::fs::read(&sstable_path).unwrap();
+    let index_offset = u64::from_le_bytes(bytes[footer..footer + 8].try_into().unwrap());
+    for damaged in [index_offset as usize + 8, file_length - 1] {
+        let mut bytes = std::fs::read(&sstable_path).unwrap();
+        bytes[damaged] ^= 0xff;
+        std::fs::write(&sstable_path, bytes).unwrap();
+
+        let database = Database::load(config.clone()).await.unwrap();
+        assert_rebuilt(database.sstables.lock().await[0].stats());
+        for i in (0..25).filter(|i| *i != 7) {
+            assert_eq!(
+                database.get(&format!("key{:02}", i)).await.unwrap(),
+                Some(format!("value{}", i))
+            );
+        }
+        assert_eq!(database.get("key07").await.unwrap(), None);
+        assert_eq!(database.get("key99").await.unwrap(), None);
+        drop(database);
+
+        // the rebuilt sections were written back
+        assert_eq!(
+            std::fs::metadata(&sstable_path).unwrap().len() as usize,
+            file_length
+        );
+        let sstable = SSTable::from_file(&sstable_path).await.unwrap();
+        assert_rebuilt(sstable.stats());
+        assert_eq!(sstable.stats().max_timestamp, stats.max_timestamp);
+    }
+}
+
+#[tokio::test]
+async fn test_bloom_filter_skips_sstables_without_the_key() {
+    let ctx = setup().await;
+    let database = Database::new(ctx.data_dir.as_str()).unwrap();
+
+    database
+        .set("foo".to_string(), "bar".to_string())
+        .await
+        .unwrap();
+    database.flush_memtable_to_sstable().await.unwrap();
+    database
+        .set("boo".to_string(), "waz".to_string())
+        .await
+        .unwrap();
+    database.flush_memtable_to_sstable().await.unwrap();
+
+    // "foo" is only in the older table, so the newer one is skipped
+    assert_eq!(database.get("foo").await.unwrap(), Some("bar".to_string()));
+    assert_eq!(database.bloom_filter_metrics.misses(), 1);
+    assert_eq!(database.bloom_filter_metrics.hits(), 1);
+
+    assert_eq!(database.get("nope").await.unwrap(), None);
+    assert_eq!(database.bloom_filter_metrics.misses(), 3);
+    assert_eq!(database.bloom_filter_metrics.hits(), 1);
+    drop(database);
+
+    // filters are persisted and loaded back on restart
+    let database = Database::load(ctx.data_dir.as_str()).await.unwrap();
+    assert_eq!(database.get("boo").await.unwrap(), Some("waz".to_string()));
+    assert_eq!(database.bloom_filter_metrics.hits(), 1);
+    let misses = database.bloom_filter_metrics.misses();
+    assert_eq!(database.get("nope").await.unwrap(), None);
+    assert_eq!(database.bloom_filter_metrics.misses(), misses + 2);
+    assert_eq!(database.bloom_filter_metrics.hits(), 1);
+}
+
+#[tokio::test]
+async fn test_tombstone_string_values_round_trip() {
+    let ctx = setup().await;
+    let database = Database::new(ctx.data_dir.as_str()).unwrap();
+
+    database
+        .set("foo".to_string(), "TOMBSTONE".to_string())
+        .await
+        .unwrap();
+    database
+        .set("bar".to_string(), "".to_string())
+        .await
+        .unwrap();
+    database.delete("baz").await.unwrap();
+    database.flush_memtable_to_sstable().await.unwrap();
+
+    assert_eq!(
+        database.get("foo").await.unwrap(),
+        Some("TOMBSTONE".to_string())
+    );
+    assert_eq!(database.get("bar").await.unwrap(), Some("".to_string()));
+    assert_eq!(database.get("baz").await.unwrap(), None);
+
+    database
+        .set("qux".to_string(), "1".to_string())
+        .await
+        .unwrap();
+    database.flush_memtable_to_sstable().await.unwrap();
+    database.compact_sstables().await.unwrap();
+
+    let sstables = database.sstables.lock().await;
+    let mut operations = read_operations(&sstables[0]).await;
+    assert!(matches!(operations[1].1, Operation::Delete(_)));
+    operations[1].1 = Operation::Delete(0);
+    assert_eq!(
+        operations,
+        vec![
+            ("bar".to_string(), Operation::Insert("".to_string())),
+            ("baz".to_string(), Operation::Delete(0)),
+            (
+                "foo".to_string(),
+                Operation::Insert("TOMBSTONE".to_string())
+            ),
+            ("qux".to_string(), Operation::Insert("1".to_string())),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_v0_sstables_can_still_be_read() {
+    let ctx = setup().await;
+    std::fs::create_dir_all(&ctx.data_dir).unwrap();
+
+    // v0 layout: no file header, [key length][value length][key][value], deletes stored as "TOMBSTONE"
+    let mut bytes = vec![];
+    for (key, value) in [("aaa", "111"), ("bbb", "TOMBSTONE"), ("ccc", "333")] {
+        bytes.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(key.as_bytes());
+        bytes.extend_from_slice(value.as_bytes());
+    }
+    let path = format!("{}/sstable_1_{}", ctx.data_dir, Uuid::new_v4());
+    std::fs::write(&path, bytes).unwrap();
+
+    let database = Database::load(ctx.data_dir.as_str()).await.unwrap();
+    assert_eq!(database.sstables.lock().await[0].format_version(), 0);
+    assert_eq!(database.get("aaa").await.unwrap(), Some("111".to_string()));
+    assert_eq!(database.get("bbb").await.unwrap(), None);
+    assert_eq!(database.get("ccc").await.unwrap(), Some("333".to_string()));
+
+    // compacting rewrites it in the current format, dropping the old tombstone since it has no
+    // deletion time and nothing older is left for it to hide
+    database
+        .set("ddd".to_string(), "444".to_string())
+        .await
+        .unwrap();
+    database.flush_memtable_to_sstable().await.unwrap();
+    database.compact_sstables().await.unwrap();
+    let sstables = database.sstables.lock().await;
+    assert_eq!(sstables[0].format_version(), SSTABLE_FORMAT_VERSION);
+    assert_eq!(sstables[0].read_all().await.unwrap().len(), 3);
+}
+
+#[tokio::test]
+async fn test_v0_sstables_written_in_the_same_second_keep_their_order() {
+    let ctx = setup().await;
+    std::fs::create_dir_all(&ctx.data_dir).unwrap();
+
+    // v0 tables were named after the second they were flushed in, so these two sort in
+    // whatever order their uuids do
+    let second = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+    write_v0_sstable(
+        &ctx.data_dir,
+        &[("foo", "zzz"), ("boo", "waz")],
+        second + Duration::from_millis(100),
+    );
+    write_v0_sstable(
+        &ctx.data_dir,
+        &[("foo", "aaa"), ("boo", "TOMBSTONE")],
+        second + Duration::from_millis(600),
+    );
+
+    let database = Database::load(ctx.data_dir.as_str()).await.unwrap();
+    assert_eq!(database.get("foo").await.unwrap(), Some("aaa".to_string()));
+    assert_eq!(database.get("boo").await.unwrap(), None);
+
+    database.compact_sstables().await.unwrap();
+    assert_eq!(database.sstables.lock().await.len(), 1);
+    assert_eq!(database.get("foo").await.unwrap(), Some("aaa".to_string()));
+    assert_eq!(database.get("boo").await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn test_wal_from_before_framing_overwrites_a_v0_sstable_from_the_same_second() {
+    let ctx = setup().await;
+    std::fs::create_dir_all(&ctx.data_dir).unwrap();
+
+    // the WAL was cleared by the flush that wrote the table, so what's in it came after, even
+    // when the clock it was written with is ahead of this one
+    let written_at = SystemTime::now() + Duration::from_secs(10);
+    write_v0_sstable(&ctx.data_dir, &[("foo", "zzz"), ("boo", "waz")], written_at);
+    let wal_path = format!("{}/wal_{}", ctx.data_dir, Uuid::new_v4());
+    std::fs::write(&wal_path, "INSERT\tfoo\taaa\nDELETE\tboo\n").unwrap();
+    set_modified(&wal_path, written_at);
+
+    let database = Database::load(ctx.data_dir.as_str()).await.unwrap();
+    assert_eq!(database.get("foo").await.unwrap(), Some("aaa".to_string()));
+    assert_eq!(database.get("boo").await.unwrap(), None);
+
+    // writes made after the upgrade still beat what was replayed
+    database
+        .set("foo".to_string(), "bar".to_string())
+        .await
+        .unwrap();
+    database.flush_memtable_to_sstable().await.unwrap();
+    database.compact_sstables().await.unwrap();
+    assert_eq!(database.get("foo").await.unwrap(), Some("bar".to_string()));
+    assert_eq!(database.get("boo").await.unwrap(), None);
+}
+
+// Writes a v0 table with `records`, modified at `written_at`: no file header,
+// [key length][value length][key][value], deletes stored as "TOMBSTONE"
+fn write_v0_sstable(data_dir: &str, records: &[(&str, &str)], written_at: SystemTime) {
+    let mut bytes = vec![];
+    for (key, value) in records {
+        bytes.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(key.as_bytes());
+        bytes.extend_from_slice(value.as_bytes());
+    }
+    let seconds = written_at.duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let path = format!("{}/sstable_{}_{}", data_dir, seconds, Uuid::new_v4());
+    std::fs::write(&path, bytes).unwrap();
+    set_modified(&path, written_at);
+}
+
+fn set_modified(path: &str, time: SystemTime) {
+    std::fs::File::options()
+        .write(true)
+        .open(path)
+        .unwrap()
+        .set_modified(time)
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_wal_replay_handles_tabs_and_newlines_in_values() {
+    let ctx = setup().await;
+    let database = Database::new(&ctx.data_dir).unwrap();
+
+    database
+        .set("foo".to_string(), "bar\tbaz\nqux".to_string())
+        .await
+        .unwrap();
+    database
+        .set("boo\t".to_string(), "\n".to_string())
+        .await
+        .unwrap();
+    database.delete("boo\t").await.unwrap();
+    drop(database);
+
+    let database = Database::load(&ctx.data_dir).await.unwrap();
+    assert_eq!(
+        database.get("foo").await.unwrap(),
+        Some("bar\tbaz\nqux".to_string())
+    );
+    assert_eq!(database.get("boo\t").await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn test_wal_replay_truncates_torn_tail_record() {
+    let ctx = setup().await;
+    let database = Database::new(&ctx.data_dir).unwrap();
+
+    database
+        .set("foo".to_string(), "bar".to_string())
+        .await
+        .unwrap();
+    database
+        .set("boo".to_string(), "waz".to_string())
+        .await
+        .unwrap();
+    let wal_path = database.wal_path().await;
+    drop(database);
+
+    // simulate a crash in the middle of writing the last record
+    let wal_length = std::fs::metadata(&wal_path).unwrap().len();
+    let file = std::fs::OpenOptions::new()
+        .write(true)
+        .open(&wal_path)
+        .unwrap();
+    file.set_len(wal_length - 2).unwrap();
+    drop(file);
+
+    let database = Database::load(&ctx.data_dir).await.unwrap();
+    assert_eq!(database.get("foo").await.unwrap(), Some("bar".to_string()));
+    assert_eq!(database.get("boo").await.unwrap(), None);
+
+    // the torn record is gone, so new writes replay fine
+    database
+        .set("baz".to_string(), "qux".to_string())
+        .await
+        .unwrap();
+    drop(database);
+    let database = Database::load(&ctx.data_dir).await.unwrap();
+    assert_eq!(database.get("foo").await.unwrap(), Some("bar".to_string()));
+    assert_eq!(database.get("baz").await.unwrap(), Some("qux".to_string()));
+}
+
+#[tokio::test]
+async fn test_wal_replay_reports_corruption_in_the_middle_of_the_log() {
+    let ctx = setup().await;
+    let database = Database::new(&ctx.data_dir).unwrap();
+
+    database
+        .set("foo".to_string(), "bar".to_string())
+        .await
+        .unwrap();
+    database
+        .set("boo".to_string(), "waz".to_string())
+        .await
+        .unwrap();
+    let wal_path = database.wal_path().await;
+    drop(database);
+
+    // flip a byte in the first record's payload
+    let mut bytes = std::fs::read(&wal_path).unwrap();
+    bytes[10] ^= 0xff;
+    std::fs::write(&wal_path, bytes).unwrap();
+
+    let ctx2 = setup().await;
+    let database = Database::new(&ctx2.data_dir).unwrap();
+    let result = database.replay_from_wal(&wal_path).await;
+    assert!(matches!(result, Err(Error::Corruption { offset: 0, .. })));
+}
+
+#[tokio::test]
+async fn test_wal_from_before_framing_is_replayed_on_upgrade() {
+    let ctx = setup().await;
+    std::fs::create_dir_all(&ctx.data_dir).unwrap();
+    // the tab separated lines the WAL was written as before records were framed
+    let wal_path = format!("{}/wal_{}", ctx.data_dir, Uuid::new_v4());
+    std::fs::write(
+        &wal_path,
+        "INSERT\tfoo\tbar\nINSERT\tboo\twaz\nDELETE\tboo\nINSERT\tbaz\tqu\tx\n",
+    )
+    .unwrap();
+
+    let database = Database::load(&ctx.data_dir).await.unwrap();
+    assert_eq!(database.get("foo").await.unwrap(), Some("bar".to_string()));
+    assert_eq!(database.get("boo").await.unwrap(), None);
+    assert_eq!(
+        database.get("baz").await.unwrap(),
+        Some("qu\tx".to_string())
+    );
+
+    // the old WAL goes away once what it had is in an SSTable
+    database.flush_memtable_to_sstable().await.unwrap();
+    assert!(!std::path::Path::new(&wal_path).exists());
+    drop(database);
+    let database = Database::load(&ctx.data_dir).await.unwrap();
+    assert_eq!(database.get("foo").await.unwrap(), Some("bar".to_string()));
+    assert_eq!(
+        database.get("baz").await.unwrap(),
+        Some("qu\tx".to_string())
+    );
+}
+
+#[tokio::test]
+async fn test_wal_replay_reports_a_corrupt_length_in_the_middle_of_the_log() {
+    let ctx = setup().await;
+    let database = Database::new(&ctx.data_dir).unwrap();
+
+    database
+        .set("foo".to_string(), "bar".to_string())
+        .await
+        .unwrap();
+    database
+        .set("boo".to_string(), "waz".to_string())
+        .await
+        .unwrap();
+    let wal_path = database.wal_path().await;
+    drop(database);
+
+    // make the first record's length point past the end of the segment
+    let mut bytes = std::fs::read(&wal_path).unwrap();
+    let wal_length = bytes.len();
+    bytes[3] = 0x7f;
+    std::fs::write(&wal_path, bytes).unwrap();
+
+    let result = Database::load(&ctx.data_dir).await;
+    assert!(matches!(result, Err(Error::Corruption { offset: 0, .. })));
+    // the records after it are still there
+    assert_eq!(
+        std::fs::metadata(&wal_path).unwrap().len(),
+        wal_length as u64
+    );
+}
+
+#[tokio::test]
+async fn test_batch_wal_sync_mode_syncs_before_acknowledging() {
+    let ctx = setup().await;
+    let mut database = Database::new(&ctx.data_dir).unwrap();
+    database.set_wal_sync_mode(WalSyncMode::Batch);
+
+    database
+        .set("foo".to_string(), "bar".to_string())
+        .await
+        .unwrap();
+    database.delete("foo").await.unwrap();
+
+    let wal = database.wal.lock().await;
+    assert_eq!(wal.last_sequence(), 2);
+    assert_eq!(wal.synced_sequence(), 2);
+}
+
+#[tokio::test]
+async fn test_group_wal_sync_mode_coalesces_concurrent_writers() {
+    let ctx = setup().await;
+    let mut database = Database::new(&ctx.data_dir).unwrap();
+    database.set_wal_sync_mode(WalSyncMode::Group {
+        window: Duration::from_millis(50),
+    });
+    let database = Arc::new(database);
+
+    let start = std::time::Instant::now();
+    let mut handles = vec![];
+    for i in 0..20 {
+        let database = database.clone();
+        handles.push(tokio::spawn(async move {
+            database
+                .set(format!("key{}", i), "value".to_string())
+                .await
+                .unwrap();
+        }));
+    }
+    for handle in handles {
+        handle.await.unwrap();
+    }
+
+    // one window for everyone instead of one per writer
+    assert!(start.elapsed() < Duration::from_millis(20 * 50));
+    let wal = database.wal.lock().await;
+    assert_eq!(wal.synced_sequence(), 20);
+}
+
+#[tokio::test]
+async fn test_periodic_wal_sync_mode_syncs_in_the_background() {
+    let ctx = setup().await;
+    let mut database = Database::new(&ctx.data_dir).unwrap();
+    database.set_wal_sync_mode(WalSyncMode::Periodic {
+        period: Duration::from_millis(20),
+    });
+
+    database
+        .set("foo".to_string(), "bar".to_string())
+        .await
+        .unwrap();
+    assert_eq!(database.wal.lock().await.last_sequence(), 1);
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(database.wal.lock().await.synced_sequence(), 1);
+}
+
+#[tokio::test]
+async fn test_wal_rotates_segments_and_replays_them_in_order() {
+    let ctx = setup().await;
+    let database = Database::new(&ctx.data_dir).unwrap();
+    database.wal.lock().await.segment_size_bytes = 64;
+
+    for i in 0..10 {
+        database
+            .set("foo".to_string(), format!("bar{}", i))
+            .await
+            .unwrap();
+        database
+            .set(format!("key{}", i), "value".to_string())
+            .await
+            .unwrap();
+    }
+
+    let segment_paths = database.wal.lock().await.segment_paths();
+    assert!(segment_paths.len() > 2);
+    let mut sorted_paths = segment_paths.clone();
+    sorted_paths.sort();
+    assert_eq!(segment_paths, sorted_paths);
+    drop(database);
+
+    let database = Database::load(&ctx.data_dir).await.unwrap();
+    assert_eq!(database.get("foo").await.unwrap(), Some("bar9".to_string()));
+    for i in 0..10 {
+        assert_eq!(
+            database.get(&format!("key{}", i)).await.unwrap(),
+            Some("value".to_string())
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_flush_removes_wal_segments_covered_by_the_sstable() {
+    let ctx = setup().await;
+    let database = Database::new(&ctx.data_dir).unwrap();
+    database.wal.lock().await.segment_size_bytes = 64;
+
+    for i in 0..10 {
+        database
+            .set(format!("key{}", i), "value".to_string())
+            .await
+            .unwrap();
+    }
+    let old_segments = database.wal.lock().await.segment_paths();
+    database.flush_memtable_to_sstable().await.unwrap();
+    database
+        .set("after".to_string(), "flush".to_string())
+        .await
+        .unwrap();
+
+    let segments = database.wal.lock().await.segment_paths();
+    assert_eq!(segments.len(), 1);
+    assert!(old_segments
+        .iter()
+        .all(|path| !std::path::Path::new(path).exists()));
+    drop(database);
+
+    let database = Database::load(&ctx.data_dir).await.unwrap();
+    assert_eq!(
+        database.get("after").await.unwrap(),
+        Some("flush".to_string())
+    );
+    assert_eq!(
+        database.get("key3").await.unwrap(),
+        Some("value".to_string())
+    );
+}
+
+#[tokio::test]
+async fn test_database_uses_configured_thresholds() {
+    let ctx = setup().await;
+    let config = DatabaseConfig::builder()
+        .data_dir(&ctx.data_dir)
+        .memtable_flush_threshold_bytes(20)
+        .compaction(CompactionConfig::SizeTiered(SizeTieredCompactionStrategy {
+            min_threshold: 3,
+            ..SizeTieredCompactionStrategy::default()
+        }))
+        .build()
+        .unwrap();
+    let database = Database::new(config.clone()).unwrap();
+
+    // every write is 18 bytes with its timestamp, so every second one flushes the memtable
+    database
+        .set("key1".to_string(), "value1".to_string())
+        .await
+        .unwrap();
+    database
+        .set("key2".to_string(), "value2".to_string())
+        .await
+        .unwrap();
+    // flushes happen in the background
+    database.wait_for_background_jobs().await.unwrap();
+    assert!(database.memtable_is_empty().await);
+    assert_eq!(database.sstables.lock().await.len(), 1);
+
+    database
+        .set("key3".to_string(), "value3".to_string())
+        .await
+        .unwrap();
+    database
+        .set("key4".to_string(), "value4".to_string())
+        .await
+        .unwrap();
+    database.wait_for_background_jobs().await.unwrap();
+    database
+        .set("key5".to_string(), "value5".to_string())
+        .await
+        .unwrap();
+    database
+        .set("key6".to_string(), "value6".to_string())
+        .await
+        .unwrap();
+    // the third flush hits the compaction threshold
+    database.wait_for_background_jobs().await.unwrap();
+    assert_eq!(database.sstables.lock().await.len(), 1);
+    drop(database);
+
+    let database = Database::load(config).await.unwrap();
+    assert_eq!(database.config.memtable_flush_threshold_bytes, 20);
+    assert_eq!(
+        database.get("key1").await.unwrap(),
+        Some("value1".to_string())
+    );
+    assert_eq!(
+        database.get("key6").await.unwrap(),
+        Some("value6".to_string())
+    );
+}
+
+#[tokio::test]
+async fn test_corrupt_sstable_is_reported_instead_of_panicking() {
+    let ctx = setup().await;
+    let config = DatabaseConfig::builder()
+        .data_dir(&ctx.data_dir)
+        .sstable_compression(Compression::None)
+        .build()
+        .unwrap();
+    let database = Database::new(config.clone()).unwrap();
+    database
+        .set("foo".to_string(), "bar".to_string())
+        .await
+        .unwrap();
+    database.flush_memtable_to_sstable().await.unwrap();
+    let sstable_path = database.sstables.lock().await[0].get_path();
+    drop(database);
+
+    // clobber the record kind byte of the first record, right after the file header and
+    // the header of the block it's in. The block checksum catches it and the error points
+    // at the block.
+    let mut bytes = std::fs::read(&sstable_path).unwrap();
+    bytes[9 + 12] = 0xff;
+    std::fs::write(&sstable_path, bytes).unwrap();
+
+    let database = Database::load(config).await.unwrap();
+    let result = database.get("foo").await;
+    assert!(matches!(result, Err(Error::Corruption { offset: 9, .. })));
+    database
+        .set("boo".to_string(), "waz".to_string())
+        .await
+        .unwrap();
+    database.flush_memtable_to_sstable().await.unwrap();
+    assert!(matches!(
+        database.compact_sstables().await,
+        Err(Error::Corruption { .. })
+    ));
+}
+
+#[tokio::test]
+async fn test_invalid_config_is_rejected() {
+    let ctx = setup().await;
+    std::fs::create_dir_all(&ctx.data_dir).unwrap();
+    let config = DatabaseConfig {
+        index_every_n_entries: 0,
+        ..DatabaseConfig::from(&ctx.data_dir)
+    };
+    assert!(matches!(Database::new(config), Err(Error::Config(_))));
+}
+
+#[tokio::test]
+async fn test_scan_merges_memtable_and_sstables() {
+    let ctx = setup().await;
+    let config = DatabaseConfig::builder()
+        .data_dir(&ctx.data_dir)
+        .index_every_n_entries(3)
+        .build()
+        .unwrap();
+    let database = Database::new(config).unwrap();
+
+    for i in 0..20 {
+        database
+            .set(format!("key{:02}", i), format!("old{}", i))
+            .await
+            .unwrap();
+    }
+    database.flush_memtable_to_sstable().await.unwrap();
+    // newer versions in a second sstable and in the memtable shadow the old ones
+    for i in (0..20).step_by(3) {
+        database
+            .set(format!("key{:02}", i), format!("new{}", i))
+            .await
+            .unwrap();
+    }
+    database.delete("key04").await.unwrap();
+    database.flush_memtable_to_sstable().await.unwrap();
+    database.delete("key06").await.unwrap();
+    database
+        .set("key05".to_string(), "newest5".to_string())
+        .await
+        .unwrap();
+
+    let rows = database
+        .scan("key03".."key09", None)
+        .collect::<Vec<_>>()
+        .await
+        .into_iter()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    assert_eq!(
+        rows,
+        vec![
+            ("key03".to_string(), "new3".to_string()),
+            ("key05".to_string(), "newest5".to_string()),
+            ("key07".to_string(), "old7".to_string()),
+            ("key08".to_string(), "old8".to_string()),
+        ]
+    );
+
+    let rows = database
+        .scan("key17".to_string().., Some(2))
+        .collect::<Vec<_>>()
+        .await;
+    let keys = rows
+        .into_iter()
+        .map(|row| row.unwrap().0)
+        .collect::<Vec<_>>();
+    assert_eq!(keys, vec!["key17", "key18"]);
+
+    let all_rows = database.scan::<&str>(.., None).collect::<Vec<_>>().await;
+    assert_eq!(all_rows.len(), 18);
+    let empty = database.scan("b".."a", None).collect::<Vec<_>>().await;
+    assert!(empty.is_empty());
+}
+
+#[tokio::test]
+async fn test_compaction_splits_output_by_target_size() {
+    let ctx = setup().await;
+    let config = DatabaseConfig::builder()
+        .data_dir(&ctx.data_dir)
+        .index_every_n_entries(4)
+        .compaction_memory_budget_bytes(64)
+        .sstable_target_size_bytes(512)
+        .build()
+        .unwrap();
+    let database = Database::new(config.clone()).unwrap();
+
+    for round in 0..3 {
+        for i in 0..50 {
+            database
+                .set(format!("key{:03}", i), format!("value{}_{}", i, round))
+                .await
+                .unwrap();
+        }
+        database.flush_memtable_to_sstable().await.unwrap();
+    }
+    database.compact_sstables().await.unwrap();
+
+    {
+        let mut sstables = database.sstables.lock().await;
+        assert!(sstables.len() > 1);
+        let mut keys = vec![];
+        for sstable in sstables.iter_mut() {
+            // every output stays around the target size, give or take one memory budget
+            assert!(sstable.size_bytes() < 512 + 64);
+            keys.extend(
+                sstable
+                    .read_all()
+                    .await
+                    .unwrap()
+                    .into_iter()
+                    .map(|(key, _)| key),
+            );
+        }
+        // the outputs hold every key once, in order
+        let expected_keys = (0..50).map(|i| format!("key{:03}", i)).collect::<Vec<_>>();
+        assert_eq!(keys, expected_keys);
+    }
+
+    for i in 0..50 {
+        assert_eq!(
+            database.get(&format!("key{:03}", i)).await.unwrap(),
+            Some(format!("value{}_2", i))
+        );
+    }
+    drop(database);
+
+    let database = Database::load(config).await.unwrap();
+    assert_eq!(
+        database.get("key042").await.unwrap(),
+        Some("value42_2".to_string())
+    );
+}
+
+#[tokio::test]
+async fn test_compaction_drops_tombstones_past_gc_grace() {
+    let ctx = setup().await;
+    let config = DatabaseConfig::builder()
+        .data_dir(&ctx.data_dir)
+        .gc_grace_seconds(0)
+        .build()
+        .unwrap();
+    let database = Database::new(config).unwrap();
+
+    database
+        .set("foo".to_string(), "bar".to_string())
+        .await
+        .unwrap();
+    database
+        .set("boo".to_string(), "waz".to_string())
+        .await
+        .unwrap();
+    database.flush_memtable_to_sstable().await.unwrap();
+    database.delete("foo").await.unwrap();
+    database.delete("nothing").await.unwrap();
+    database.flush_memtable_to_sstable().await.unwrap();
+
+    database.compact_sstables().await.unwrap();
+
+    let operations = read_operations(&database.sstables.lock().await[0]).await;
+    assert_eq!(
+        operations,
+        vec![("boo".to_string(), Operation::Insert("waz".to_string()))]
+    );
+    assert_eq!(database.get("foo").await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn test_compaction_keeps_tombstones_hiding_an_older_write_in_the_memtable() {
+    let ctx = setup().await;
+    let config = DatabaseConfig::builder()
+        .data_dir(&ctx.data_dir)
+        .gc_grace_seconds(0)
+        .build()
+        .unwrap();
+    let database = Database::new(config).unwrap();
+
+    database.delete("foo").await.unwrap();
+    database.flush_memtable_to_sstable().await.unwrap();
+    database
+        .set("boo".to_string(), "waz".to_string())
+        .await
+        .unwrap();
+    database.flush_memtable_to_sstable().await.unwrap();
+    // a write that arrives late with a timestamp from before the delete
+    database
+        .set_with_timestamp("foo".to_string(), "old".to_string(), 100)
+        .await
+        .unwrap();
+
+    database.compact_sstables().await.unwrap();
+    assert_eq!(database.get("foo").await.unwrap(), None);
+    database.flush_memtable_to_sstable().await.unwrap();
+    assert_eq!(database.get("foo").await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn test_compaction_keeps_tombstones_within_gc_grace() {
+    let ctx = setup().await;
+    let database = Database::new(&ctx.data_dir).unwrap();
+
+    database
+        .set("foo".to_string(), "bar".to_string())
+        .await
+        .unwrap();
+    database.flush_memtable_to_sstable().await.unwrap();
+    let before_delete = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    database.delete("foo").await.unwrap();
+    database.flush_memtable_to_sstable().await.unwrap();
+
+    database.compact_sstables().await.unwrap();
+    drop(database);
+
+    // the deletion time survives being flushed, compacted and reloaded
+    let database = Database::load(&ctx.data_dir).await.unwrap();
+    let operations = read_operations(&database.sstables.lock().await[0]).await;
+    assert_eq!(operations.len(), 1);
+    match operations[0].1 {
+        Operation::Delete(deleted_at) => assert!(deleted_at >= before_delete),
+        ref operation => panic!("expected a tombstone, got {:?}", operation),
+    }
+    assert_eq!(database.get("foo").await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn test_size_tiered_compaction_merges_similar_sized_tables() {
+    let ctx = setup().await;
+    let config = DatabaseConfig::builder()
+        .data_dir(&ctx.data_dir)
+        .memtable_flush_threshold_bytes(200)
+        // the sizes below are the sizes of the uncompressed data
+        .sstable_compression(Compression::None)
+        .compaction(CompactionConfig::SizeTiered(SizeTieredCompactionStrategy {
+            min_threshold: 4,
+            min_sstable_size_bytes: 0,
+            ..SizeTieredCompactionStrategy::default()
+        }))
+        .build()
+        .unwrap();
+    let database = Database::new(config).unwrap();
+
+    // every write is 20 bytes with its timestamp, so every 10 writes flush a table of the same size
+    async fn write_keys(database: &Database, keys: std::ops::Range<usize>) {
+        for i in keys {
+            database
+                .set(format!("key{:04}", i), "value".to_string())
+                .await
+                .unwrap();
+            // let the flush happen before the MemTable grows any further
+            database.wait_for_background_jobs().await.unwrap();
+        }
+    }
+    async fn sstable_sizes(database: &Database) -> Vec<u64> {
+        database.wait_for_background_jobs().await.unwrap();
+        let sstables = database.sstables.lock().await;
+        sstables
+            .iter()
+            .map(|sstable| sstable.size_bytes())
+            .collect()
+    }
+
+    write_keys(&database, 0..30).await;
+    assert_eq!(sstable_sizes(&database).await.len(), 3);
+    // the fourth similar table fills the bucket, which gets merged into one four times the size
+    write_keys(&database, 30..40).await;
+    assert_eq!(sstable_sizes(&database).await.len(), 1);
+    // small tables pile up next to the big one without touching it
+    write_keys(&database, 40..70).await;
+    let sizes = sstable_sizes(&database).await;
+    assert_eq!(sizes.len(), 4);
+    assert!(sizes[0] > sizes[1] * 3);
+    write_keys(&database, 70..80).await;
+    let sizes = sstable_sizes(&database).await;
+    assert_eq!(sizes.len(), 2);
+    assert!(sizes[0].abs_diff(sizes[1]) < sizes[0] / 10);
+
+    for i in 0..80 {
+        assert_eq!(
+            database.get(&format!("key{:04}", i)).await.unwrap(),
+            Some("value".to_string())
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_size_tiered_compaction_buckets_tables_wherever_they_are() {
+    let ctx = setup().await;
+    let config = DatabaseConfig::builder()
+        .data_dir(&ctx.data_dir)
+        .memtable_flush_threshold_bytes(200)
+        .sstable_compression(Compression::None)
+        .compaction(CompactionConfig::SizeTiered(SizeTieredCompactionStrategy {
+            min_threshold: 4,
+            min_sstable_size_bytes: 0,
+            ..SizeTieredCompactionStrategy::default()
+        }))
+        .build()
+        .unwrap();
+    let database = Database::new(config).unwrap();
+
+    // every 10 writes flush a table of the same size, as in the test above
+    async fn write_keys(database: &Database, keys: std::ops::Range<usize>) {
+        for i in keys {
+            database
+                .set(format!("key{:04}", i), "value".to_string())
+                .await
+                .unwrap();
+            database.wait_for_background_jobs().await.unwrap();
+        }
+    }
+
+    // a small table between the first similar one and the others doesn't keep them apart
+    write_keys(&database, 0..10).await;
+    write_keys(&database, 10..13).await;
+    database.flush_memtable_to_sstable().await.unwrap();
+    write_keys(&database, 13..43).await;
+    database.wait_for_background_jobs().await.unwrap();
+    let sizes = database
+        .sstables
+        .lock()
+        .await
+        .iter()
+        .map(|sstable| sstable.size_bytes())
+        .collect::<Vec<_>>();
+    assert_eq!(sizes.len(), 2);
+    assert!(sizes[0] > sizes[1] * 10);
+
+    for i in 0..43 {
+        assert_eq!(
+            database.get(&format!("key{:04}", i)).await.unwrap(),
+            Some("value".to_string())
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_leveled_compaction_keeps_levels_non_overlapping() {
+    let ctx = setup().await;
+    let config = DatabaseConfig::builder()
+        .data_dir(&ctx.data_dir)
+        .memtable_flush_threshold_bytes(120)
+        .sstable_target_size_bytes(200)
+        .compaction(CompactionConfig::Leveled(LeveledCompactionStrategy {
+            l0_compaction_threshold: 2,
+            max_bytes_for_level_base: 400,
+            fanout_size: 2,
+        }))
+        .build()
+        .unwrap();
+    let database = Database::new(config.clone()).unwrap();
+
+    // write the keys out of order so every flush overlaps the tables already in L1
+    for i in 0..200 {
+        let key = format!("key{:04}", (i * 37) % 200);
+        database.set(key, format!("value{}", i)).await.unwrap();
+        database.wait_for_background_jobs().await.unwrap();
+    }
+
+    // (level, smallest key, largest key) of every table, oldest first
+    async fn levels(database: &Database) -> Vec<(u32, String, String)> {
+        database.wait_for_background_jobs().await.unwrap();
+        let sstables = database.sstables.lock().await;
+        sstables
+            .iter()
+            .map(|sstable| {
+                let (min, max) = sstable.key_range().unwrap();
+                (sstable.level(), min.to_string(), max.to_string())
+            })
+            .collect()
+    }
+
+    let tables = levels(&database).await;
+    assert!(tables.iter().filter(|(level, _, _)| *level == 0).count() < 2);
+    assert!(tables.iter().any(|(level, _, _)| *level >= 2));
+    // deeper levels come first and the tables of a level are sorted by key without overlapping
+    for pair in tables.windows(2) {
+        let ((level, _, max), (next_level, next_min, _)) = (&pair[0], &pair[1]);
+        assert!(level >= next_level);
+        if level == next_level && *level > 0 {
+            assert!(max < next_min, "{:?} overlaps {:?}", pair[0], pair[1]);
+        }
+    }
+
+    // a read looks at every L0 table but only at the one table per level covering the key
+    let deepest_level = tables[0].0 as u64;
+    let tables_read = |database: &Database| {
+        database.bloom_filter_metrics.hits() + database.bloom_filter_metrics.misses()
+    };
+    let before = tables_read(&database);
+    database.get("key0100").await.unwrap();
+    assert!(tables_read(&database) - before <= deepest_level + 1);
+
+    // levels and key ranges survive a restart
+    drop(database);
+    let database = Database::load(config).await.unwrap();
+    assert_eq!(levels(&database).await, tables);
+    for i in 0..200 {
+        let key = format!("key{:04}", (i * 37) % 200);
+        assert_eq!(
+            database.get(&key).await.unwrap(),
+            Some(format!("value{}", i))
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_time_window_compaction_only_merges_within_a_window() {
+    let ctx = setup().await;
+    let strategy = TimeWindowCompactionStrategy {
+        compaction_window_unit: TimeWindowUnit::Hours,
+        compaction_window_size: 1,
+        min_threshold: 4,
+        max_threshold: 32,
+    };
+    let config = DatabaseConfig::builder()
+        .data_dir(&ctx.data_dir)
+        .memtable_flush_threshold_bytes(1)
+        .compaction(CompactionConfig::TimeWindow(strategy.clone()))
+        .build()
+        .unwrap();
+    let database = Database::new(config).unwrap();
+
+    // every write is flushed into a table of its own. The first and third are written
+    // with timestamps in one window and the second in the next, which finishes off the
+    // first window: its two tables are merged even though they aren't next to each other.
+    for (key, timestamp) in [("key0", 3600), ("key2", 7200), ("key1", 7000)] {
+        database
+            .set_with_timestamp(key.to_string(), "value".to_string(), timestamp * 1_000_000)
+            .await
+            .unwrap();
+        database.wait_for_background_jobs().await.unwrap();
+    }
+    assert_eq!(strategy.window_start(7000), 3600);
+    assert_eq!(strategy.window_start(7200), 7200);
+    let max_timestamps = |sstables: &[Arc<SSTable>]| {
+        sstables
+            .iter()
+            .map(|sstable| sstable.stats().max_timestamp / 1_000_000)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        max_timestamps(&database.sstables.lock().await),
+        vec![7000, 7200]
+    );
+
+    // a write in the current window leaves the others alone
+    database
+        .set("key3".to_string(), "value".to_string())
+        .await
+        .unwrap();
+    database.wait_for_background_jobs().await.unwrap();
+    {
+        let max_timestamps = max_timestamps(&database.sstables.lock().await);
+        assert_eq!(max_timestamps.len(), 3);
+        assert_eq!(&max_timestamps[..2], &[7000, 7200]);
+    }
+
+    for i in 0..4 {
+        assert_eq!(
+            database.get(&format!("key{}", i)).await.unwrap(),
+            Some("value".to_string())
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_time_window_compaction_drops_expired_sstables() {
+    let ctx = setup().await;
+    let config = DatabaseConfig::builder()
+        .data_dir(&ctx.data_dir)
+        .memtable_flush_threshold_bytes(1)
+        .gc_grace_seconds(0)
+        .compaction(CompactionConfig::TimeWindow(
+            TimeWindowCompactionStrategy::default(),
+        ))
+        .build()
+        .unwrap();
+    let database = Database::new(config).unwrap();
+
+    async fn sstable_count(database: &Database) -> usize {
+        database.wait_for_background_jobs().await.unwrap();
+        database.sstables.lock().await.len()
+    }
+
+    database
+        .set("foo".to_string(), "bar".to_string())
+        .await
+        .unwrap();
+    assert_eq!(sstable_count(&database).await, 1);
+
+    // a table holding nothing but an expired tombstone goes away as a whole
+    database.delete("baz").await.unwrap();
+    assert_eq!(sstable_count(&database).await, 1);
+
+    // unless it's still hiding a value in an older table
+    database.delete("foo").await.unwrap();
+    assert_eq!(sstable_count(&database).await, 2);
+    assert_eq!(database.get("foo").await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn test_expired_sstable_stays_while_a_newer_table_has_an_older_write() {
+    let ctx = setup().await;
+    let config = DatabaseConfig::builder()
+        .data_dir(&ctx.data_dir)
+        .memtable_flush_threshold_bytes(1)
+        .gc_grace_seconds(0)
+        .compaction(CompactionConfig::TimeWindow(
+            TimeWindowCompactionStrategy::default(),
+        ))
+        .build()
+        .unwrap();
+    let database = Database::new(config).unwrap();
+
+    let now = next_timestamp();
+    database
+        .set_with_ttl_and_timestamp("foo".to_string(), "expiring".to_string(), 1, now)
+        .await
+        .unwrap();
+    // flushed after the expiring value but written before it, so it's hidden
+    database
+        .set_with_timestamp("foo".to_string(), "older".to_string(), now - 1_000_000)
+        .await
+        .unwrap();
+    database.wait_for_background_jobs().await.unwrap();
+    assert_eq!(database.sstables.lock().await.len(), 2);
+
+    // once the value has expired the next flush checks for tables to drop, and the one
+    // with the expired value is still hiding the older write
+    tokio::time::sleep(Duration::from_millis(2100)).await;
+    database
+        .set("bar".to_string(), "baz".to_string())
+        .await
+        .unwrap();
+    database.wait_for_background_jobs().await.unwrap();
+    assert_eq!(database.get("foo").await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn test_compaction_does_not_wait_for_open_scans() {
+    let ctx = setup().await;
+    let database = Database::new(&ctx.data_dir).unwrap();
+
+    for i in 0..20 {
+        database
+            .set(format!("key{:02}", i), format!("value{}", i))
+            .await
+            .unwrap();
+        if i % 5 == 4 {
+            database.flush_memtable_to_sstable().await.unwrap();
+        }
+    }
+    assert_eq!(database.sstables.lock().await.len(), 4);
+
+    let rows = database.scan::<&str>(.., None);
+    tokio::pin!(rows);
+    assert_eq!(
+        rows.next().await.unwrap().unwrap(),
+        ("key00".to_string(), "value0".to_string())
+    );
+
+    // the scan reads from a snapshot, so compaction swaps the tables out from under it
+    database.compact_sstables().await.unwrap();
+    assert_eq!(database.sstables.lock().await.len(), 1);
+    database
+        .set("key20".to_string(), "value20".to_string())
+        .await
+        .unwrap();
+    assert_eq!(
+        database.get("key10").await.unwrap(),
+        Some("value10".to_string())
+    );
+
+    // and still finishes reading the old ones
+    let rest = rows.collect::<Result<Vec<_>, _>>().await.unwrap();
+    assert_eq!(rest.len(), 19);
+    assert_eq!(rest[18], ("key19".to_string(), "value19".to_string()));
+}
+
+// Holds up the background task in its first compaction check until `release` is dropped
+struct BlockingCompactionStrategy {
+    entered: tokio::sync::mpsc::UnboundedSender<()>,
+    release: std::sync::Mutex<std::sync::mpsc::Receiver<()>>,
+}
+
+impl CompactionStrategy for BlockingCompactionStrategy {
+    fn next_compaction(&self, _sstables: &[Arc<SSTable>]) -> Option<CompactionTask> {
+        self.entered.send(()).ok();
+        self.release.lock().unwrap().recv().ok();
+        None
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_immutable_memtables_stay_readable_until_flushed() {
+    let ctx = setup().await;
+    let config = DatabaseConfig::builder()
+        .data_dir(&ctx.data_dir)
+        .memtable_flush_threshold_bytes(200)
+        .max_immutable_memtables(1)
+        .build()
+        .unwrap();
+    let database = Database::new(config).unwrap();
+    let (entered_tx, mut entered) = tokio::sync::mpsc::unbounded_channel();
+    let (release, release_rx) = std::sync::mpsc::channel();
+    database
+        .set_compaction_strategy(BlockingCompactionStrategy {
+            entered: entered_tx,
+            release: std::sync::Mutex::new(release_rx),
+        })
+        .await
+        .unwrap();
+
+    // every write is 20 bytes with its timestamp, so every 10th one fills up the MemTable
+    async fn write_keys(database: &Database, keys: std::ops::Range<usize>) {
+        for i in keys {
+            database
+                .set(format!("key{:04}", i), "value".to_string())
+                .await
+                .unwrap();
+        }
+    }
+
+    // the first MemTable gets flushed, then the background task is stuck
+    write_keys(&database, 0..10).await;
+    entered.recv().await.unwrap();
+
+    // the second one is swapped out but can't be flushed yet, writes go on regardless
+    write_keys(&database, 10..25).await;
+    assert_eq!(database.immutable_memtables.lock().await.len(), 1);
+    assert_eq!(database.sstables.lock().await.len(), 1);
+    assert_eq!(
+        database.get("key0015").await.unwrap(),
+        Some("value".to_string())
+    );
+    let rows = database
+        .scan::<&str>(.., None)
+        .collect::<Result<Vec<_>, _>>()
+        .await
+        .unwrap();
+    assert_eq!(rows.len(), 25);
+
+    // with no room for another immutable MemTable the write that fills this one up waits
+    write_keys(&database, 25..29).await;
+    let write = database.set("key0029".to_string(), "value".to_string());
+    assert!(tokio::time::timeout(Duration::from_millis(200), write)
+        .await
+        .is_err());
+
+    drop(release);
+    database.flush_memtable_to_sstable().await.unwrap();
+    assert!(database.immutable_memtables.lock().await.is_empty());
+    assert_eq!(database.sstables.lock().await.len(), 3);
+    for i in 0..30 {
+        assert_eq!(
+            database.get(&format!("key{:04}", i)).await.unwrap(),
+            Some("value".to_string())
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_load_only_trusts_the_manifest() {
+    let ctx = setup().await;
+    let database = Database::new(&ctx.data_dir).unwrap();
+
+    database
+        .set("foo".to_string(), "1".to_string())
+        .await
+        .unwrap();
+    database.flush_memtable_to_sstable().await.unwrap();
+    let old_sstable = database.sstables.lock().await[0].get_path();
+    let old_bytes = std::fs::read(&old_sstable).unwrap();
+    database.delete("foo").await.unwrap();
+    database.flush_memtable_to_sstable().await.unwrap();
+    database.compact_sstables().await.unwrap();
+    let compacted = database.sstables.lock().await[0].get_path();
+    drop(database);
+
+    // a crash right after the compaction swapped in its output but before it deleted
+    // the inputs, in the middle of writing another output and the manifest
+    std::fs::write(&old_sstable, old_bytes).unwrap();
+    let partial = format!("{}/sstable_0_{}", ctx.data_dir, Uuid::new_v4());
+    std::fs::write(&partial, b"KSST\x02").unwrap();
+    let manifest_tmp = format!("{}/MANIFEST.tmp", ctx.data_dir);
+    std::fs::write(&manifest_tmp, b"KMAN").unwrap();
+
+    let database = Database::load(&ctx.data_dir).await.unwrap();
+    assert_eq!(database.sstables.lock().await.len(), 1);
+    assert_eq!(database.get("foo").await.unwrap(), None);
+    assert!(!std::path::Path::new(&old_sstable).exists());
+    assert!(!std::path::Path::new(&partial).exists());
+    assert!(!std::path::Path::new(&manifest_tmp).exists());
+    assert!(std::path::Path::new(&compacted).exists());
+}
+
+#[tokio::test]
+async fn test_new_refuses_a_data_dir_that_already_has_a_database() {
+    let ctx = setup().await;
+    let database = Database::new(&ctx.data_dir).unwrap();
+    database
+        .set("foo".to_string(), "1".to_string())
+        .await
+        .unwrap();
+    database.flush_memtable_to_sstable().await.unwrap();
+    database
+        .set("bar".to_string(), "2".to_string())
+        .await
+        .unwrap();
+    drop(database);
+
+    assert!(matches!(
+        Database::new(&ctx.data_dir),
+        Err(Error::Config(_))
+    ));
+
+    // neither the flushed SSTable nor the WAL got touched
+    let database = Database::load(&ctx.data_dir).await.unwrap();
+    assert_eq!(database.sstables.lock().await.len(), 1);
+    assert_eq!(database.get("foo").await.unwrap(), Some("1".to_string()));
+    assert_eq!(database.get("bar").await.unwrap(), Some("2".to_string()));
+}
+
+#[tokio::test]
+async fn test_sstable_blocks_are_compressed_and_read_back() {
+    let ctx = setup().await;
+    let config = |compression| {
+        DatabaseConfig::builder()
+            .data_dir(&ctx.data_dir)
+            .memtable_flush_threshold_bytes(1024 * 1024)
+            .sstable_compression(compression)
+            .sstable_block_size_bytes(256)
+            .build()
+            .unwrap()
+    };
+    let value = |i: usize| {
+        format!(
+            r#"{{"id": {}, "name": "user", "tags": ["a", "b", "c"]}}"#,
+            i
+        )
+    };
+
+    let database = Database::new(config(Compression::None)).unwrap();
+    for i in 0..100 {
+        database
+            .set(format!("key{:03}", i), value(i))
+            .await
+            .unwrap();
+    }
+    database.flush_memtable_to_sstable().await.unwrap();
+    drop(database);
+
+    // the same data again, compressed this time
+    let database = Database::load(config(Compression::Lz4)).await.unwrap();
+    for i in 100..200 {
+        database
+            .set(format!("key{:03}", i - 100), value(i))
+            .await
+            .unwrap();
+    }
+    database.flush_memtable_to_sstable().await.unwrap();
+    let (uncompressed, compressed) = {
+        let sstables = database.sstables.lock().await;
+        assert_eq!(sstables[0].compression(), Compression::None);
+        assert_eq!(sstables[1].compression(), Compression::Lz4);
+        (sstables[0].size_bytes(), sstables[1].size_bytes())
+    };
+    assert!(
+        compressed < uncompressed / 2,
+        "{} vs {}",
+        compressed,
+        uncompressed
+    );
+
+    for i in 0..100 {
+        assert_eq!(
+            database.get(&format!("key{:03}", i)).await.unwrap(),
+            Some(value(i + 100))
+        );
+    }
+    let scanned = database
+        .scan("key042".."key045", None)
+        .collect::<Result<Vec<_>, _>>()
+        .await
+        .unwrap();
+    assert_eq!(
+        scanned,
+        (142..145)
+            .map(|i| (format!("key{:03}", i - 100), value(i)))
+            .collect::<Vec<_>>()
+    );
+
+    // the block index is loaded back along with the rest of the table
+    drop(database);
+    let database = Database::load(config(Compression::Lz4)).await.unwrap();
+    assert_eq!(database.get("key077").await.unwrap(), Some(value(177)));
+
+    // compaction rewrites everything with the configured compression
+    database.compact_sstables().await.unwrap();
+    {
+        let sstables = database.sstables.lock().await;
+        assert_eq!(sstables.len(), 1);
+        assert_eq!(sstables[0].compression(), Compression::Lz4);
+        assert_eq!(sstables[0].read_all().await.unwrap().len(), 100);
+    }
+    assert_eq!(database.get("key099").await.unwrap(), Some(value(199)));
+}
+
+#[tokio::test]
+async fn test_corruption_policy_skips_or_quarantines_corrupt_sstables() {
+    for policy in [CorruptionPolicy::Skip, CorruptionPolicy::Quarantine] {
+        let ctx = setup().await;
+        let config = DatabaseConfig::builder()
+            .data_dir(&ctx.data_dir)
+            .corruption_policy(policy)
+            .build()
+            .unwrap();
+        let database = Database::new(config.clone()).unwrap();
+        database
+            .set("foo".to_string(), "old".to_string())
+            .await
+            .unwrap();
+        database.flush_memtable_to_sstable().await.unwrap();
+        database
+            .set("foo".to_string(), "new".to_string())
+            .await
+            .unwrap();
+        database
+            .set("bar".to_string(), "baz".to_string())
+            .await
+            .unwrap();
+        database.flush_memtable_to_sstable().await.unwrap();
+        let corrupt_path = database.sstables.lock().await[1].get_path();
+        drop(database);
+
+        // flip a bit inside the first block of the newer table
+        let mut bytes = std::fs::read(&corrupt_path).unwrap();
+        bytes[9 + 12] ^= 1;
+        std::fs::write(&corrupt_path, bytes).unwrap();
+
+        let database = Database::load(config).await.unwrap();
+        assert_eq!(database.get("foo").await.unwrap(), Some("old".to_string()));
+        assert_eq!(database.get("bar").await.unwrap(), None);
+        assert_eq!(
+            database
+                .scan("a".."z", None)
+                .collect::<Vec<_>>()
+                .await
+                .into_iter()
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap(),
+            vec![("foo".to_string(), "old".to_string())]
+        );
+        database.wait_for_background_jobs().await.unwrap();
+
+        let file_name = std::path::Path::new(&corrupt_path).file_name().unwrap();
+        let quarantine_path = std::path::Path::new(&ctx.data_dir)
+            .join("quarantine")
+            .join(file_name);
+        let sstable_count = database.sstables.lock().await.len();
+        if policy == CorruptionPolicy::Skip {
+            assert_eq!(sstable_count, 2);
+            assert!(std::path::Path::new(&corrupt_path).exists());
+        } else {
+            assert_eq!(sstable_count, 1);
+            assert!(!std::path::Path::new(&corrupt_path).exists());
+            assert!(quarantine_path.exists());
+            // the manifest no longer lists it either
+            drop(database);
+            let database = Database::load(
+                DatabaseConfig::builder()
+                    .data_dir(&ctx.data_dir)
+                    .build()
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+            assert_eq!(database.get("foo").await.unwrap(), Some("old".to_string()));
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_corruption_policy_decides_what_loading_does_with_an_sstable_that_doesnt_open() {
+    for policy in [
+        CorruptionPolicy::Fail,
+        CorruptionPolicy::Skip,
+        CorruptionPolicy::Quarantine,
+    ] {
+        let ctx = setup().await;
+        let config = DatabaseConfig::builder()
+            .data_dir(&ctx.data_dir)
+            .corruption_policy(policy)
+            .build()
+            .unwrap();
+        let database = Database::new(config.clone()).unwrap();
+        database
+            .set("foo".to_string(), "bar".to_string())
+            .await
+            .unwrap();
+        database.flush_memtable_to_sstable().await.unwrap();
+        database
+            .set("boo".to_string(), "waz".to_string())
+            .await
+            .unwrap();
+        database.flush_memtable_to_sstable().await.unwrap();
+        let corrupt_path = database.sstables.lock().await[1].get_path();
+        drop(database);
+
+        // clobber the format version in the header
+        let mut bytes = std::fs::read(&corrupt_path).unwrap();
+        bytes[4] = 0xff;
+        std::fs::write(&corrupt_path, bytes).unwrap();
+
+        let result = Database::load(config).await;
+        if policy == CorruptionPolicy::Fail {
+            assert!(matches!(result, Err(Error::Corruption { offset: 4, .. })));
+            assert!(std::path::Path::new(&corrupt_path).exists());
+            continue;
+        }
+        let database = result.unwrap();
+        assert_eq!(database.sstables.lock().await.len(), 1);
+        assert_eq!(database.get("foo").await.unwrap(), Some("bar".to_string()));
+        assert_eq!(database.get("boo").await.unwrap(), None);
+        let file_name = std::path::Path::new(&corrupt_path).file_name().unwrap();
+        assert!(std::path::Path::new(&ctx.data_dir)
+            .join("quarantine")
+            .join(file_name)
+            .exists());
+        assert!(!std::path::Path::new(&corrupt_path).exists());
+
+        // the manifest no longer lists it, so the next load doesn't trip over it
+        drop(database);
+        let database = Database::load(ctx.data_dir.as_str()).await.unwrap();
+        assert_eq!(database.sstables.lock().await.len(), 1);
+    }
+}
+
+#[tokio::test]
+async fn test_writes_are_resolved_by_timestamp_not_by_where_they_are() {
+    let ctx = setup().await;
+    let database = Database::new(&ctx.data_dir).unwrap();
+
+    // the newer writes to foo and bar end up in the older SSTable
+    database
+        .set_with_timestamp("foo".to_string(), "new".to_string(), 200)
+        .await
+        .unwrap();
+    database
+        .set_with_timestamp("bar".to_string(), "old".to_string(), 100)
+        .await
+        .unwrap();
+    database.flush_memtable_to_sstable().await.unwrap();
+    database
+        .set_with_timestamp("foo".to_string(), "old".to_string(), 100)
+        .await
+        .unwrap();
+    database.delete_with_timestamp("bar", 200).await.unwrap();
+    database
+        .set_with_timestamp("baz".to_string(), "new".to_string(), 200)
+        .await
+        .unwrap();
+    database
+        .set_with_timestamp("baz".to_string(), "old".to_string(), 100)
+        .await
+        .unwrap();
+
+    async fn assert_newest_wins(database: &Database) {
+        assert_eq!(database.get("foo").await.unwrap(), Some("new".to_string()));
+        assert_eq!(database.get("bar").await.unwrap(), None);
+        assert_eq!(database.get("baz").await.unwrap(), Some("new".to_string()));
+        let rows = database
+            .scan::<&str>(.., None)
+            .collect::<Result<Vec<_>, _>>()
+            .await
+            .unwrap();
+        assert_eq!(
+            rows,
+            vec![
+                ("baz".to_string(), "new".to_string()),
+                ("foo".to_string(), "new".to_string()),
+            ]
+        );
+    }
+    assert_newest_wins(&database).await;
+
+    // the timestamps survive WAL replay, flushing and compaction
+    drop(database);
+    let database = Database::load(&ctx.data_dir).await.unwrap();
+    assert_newest_wins(&database).await;
+    database.flush_memtable_to_sstable().await.unwrap();
+    assert_newest_wins(&database).await;
+    database.compact_sstables().await.unwrap();
+    assert_newest_wins(&database).await;
+
+    let sstables = database.sstables.lock().await;
+    let cells = sstables[0].read_all().await.unwrap();
+    assert_eq!(
+        cells
+            .iter()
+            .map(|(key, cell)| (key.as_str(), cell.timestamp))
+            .collect::<Vec<_>>(),
+        vec![("bar", 200), ("baz", 200), ("foo", 200)]
+    );
+    assert_eq!(sstables[0].stats().max_timestamp, 200);
+}
+
+#[tokio::test]
+async fn test_expired_values_read_as_deleted_and_compact_away() {
+    let ctx = setup().await;
+    std::fs::create_dir_all(&ctx.data_dir).unwrap();
+
+    // a table with a value whose TTL ran out long ago and one that won't for a while
+    let path = format!("{}/sstable_1_{}", ctx.data_dir, Uuid::new_v4());
+    let mut sstable = SSTable::new(&path).await.unwrap();
+    let expired = Operation::Expiring {
+        value: "gone".to_string(),
+        ttl: 60,
+        expires_at: 1060,
+    };
+    let live = Operation::Expiring {
+        value: "here".to_string(),
+        ttl: 60,
+        expires_at: 4_000_000_000,
+    };
+    sstable.write("aaa", &Cell::new(expired, 1)).await.unwrap();
+    sstable
+        .write("bbb", &Cell::new(live.clone(), 1))
+        .await
+        .unwrap();
+    sstable.write_to_index("aaa".to_string(), 0);
+    sstable.finish().await.unwrap();
+    // every value in it expires, so the table can be dropped once the last one has
+    assert_eq!(sstable.max_deletion_time(), 4_000_000_000);
+    drop(sstable);
+
+    let config = DatabaseConfig::builder()
+        .data_dir(&ctx.data_dir)
+        .gc_grace_seconds(0)
+        .build()
+        .unwrap();
+    let database = Database::load(config.clone()).await.unwrap();
+    assert_eq!(database.get("aaa").await.unwrap(), None);
+    assert_eq!(database.get("bbb").await.unwrap(), Some("here".to_string()));
+    // the expired value still hides older writes
+    database
+        .set_with_timestamp("aaa".to_string(), "older".to_string(), 0)
+        .await
+        .unwrap();
+    assert_eq!(database.get("aaa").await.unwrap(), None);
+    let rows = database
+        .scan::<&str>(.., None)
+        .collect::<Result<Vec<_>, _>>()
+        .await
+        .unwrap();
+    assert_eq!(rows, vec![("bbb".to_string(), "here".to_string())]);
+
+    // compaction turns it into a tombstone from when it was written, which is way past
+    // gc_grace_seconds, so it goes along with what it was hiding
+    database.flush_memtable_to_sstable().await.unwrap();
+    database.compact_sstables().await.unwrap();
+    assert_eq!(
+        read_operations(&database.sstables.lock().await[0]).await,
+        vec![("bbb".to_string(), live)]
+    );
+
+    // TTLs set through the API survive WAL replay and flushing
+    database
+        .set_with_ttl("ccc".to_string(), "soon".to_string(), 3600)
+        .await
+        .unwrap();
+    drop(database);
+    let database = Database::load(config).await.unwrap();
+    assert_eq!(database.get("ccc").await.unwrap(), Some("soon".to_string()));
+    database.flush_memtable_to_sstable().await.unwrap();
+    let operations = read_operations(&database.sstables.lock().await[1]).await;
+    assert!(matches!(
+        &operations[..],
+        [(key, Operation::Expiring { ttl: 3600, .. })] if key == "ccc"
+    ));
+    assert_eq!(database.get("ccc").await.unwrap(), Some("soon".to_string()));
+}
+
+#[tokio::test]
+async fn test_tables_have_their_own_data_and_share_the_commit_log() {
+    let ctx = setup().await;
+    let node = Node::load(&ctx.data_dir).await.unwrap();
+    assert_eq!(
+        node.keyspace_names().await,
+        vec![DEFAULT_KEYSPACE.to_string()]
+    );
+    let users = node.create_table("ks", "users", None, false).await.unwrap();
+    let orders = node
+        .create_table("ks", "orders", None, false)
+        .await
+        .unwrap();
+    assert!(matches!(
+        node.create_table("ks", "users", None, false).await,
+        Err(Error::InvalidRequest(_))
+    ));
+    assert!(node.create_table("ks", "users", None, true).await.is_ok());
+    assert!(matches!(
+        node.create_table("ks", "no-dashes", None, false).await,
+        Err(Error::InvalidRequest(_))
+    ));
+
+    users
+        .database
+        .set("foo".to_string(), "user".to_string())
+        .await
+        .unwrap();
+    orders
+        .database
+        .set("foo".to_string(), "order".to_string())
+        .await
+        .unwrap();
+    assert_eq!(
+        users.database.get("foo").await.unwrap(),
+        Some("user".to_string())
+    );
+    assert_eq!(
+        orders.database.get("foo").await.unwrap(),
+        Some("order".to_string())
+    );
+
+    // flushing one table leaves the segment alone, the other still needs it
+    users.database.flush_memtable_to_sstable().await.unwrap();
+    let sstable_path = users.database.sstables.lock().await[0].get_path();
+    assert!(sstable_path.starts_with(&format!("{}/ks/users/", ctx.data_dir)));
+    assert!(orders.database.sstables.lock().await.is_empty());
+    let first_segment = users.database.wal.lock().await.segment_paths()[0].clone();
+    assert!(std::path::Path::new(&first_segment).exists());
+
+    // both were replayed from the shared WAL into the right table
+    drop((users, orders, node));
+    let node = Node::load(&ctx.data_dir).await.unwrap();
+    let users = node.table("ks", "users").await.unwrap();
+    let orders = node.table("ks", "orders").await.unwrap();
+    assert_eq!(
+        users.database.get("foo").await.unwrap(),
+        Some("user".to_string())
+    );
+    assert_eq!(
+        orders.database.get("foo").await.unwrap(),
+        Some("order".to_string())
+    );
+    assert!(users.database.memtable_is_empty().await);
+
+    // once every table that wrote to a segment has flushed, it's gone
+    orders.database.flush_memtable_to_sstable().await.unwrap();
+    assert!(!std::path::Path::new(&first_segment).exists());
+    assert_eq!(orders.database.wal.lock().await.segment_paths().len(), 1);
+}
+
+#[tokio::test]
+async fn test_drop_and_truncate_tables() {
+    let ctx = setup().await;
+    let node = Node::load(&ctx.data_dir).await.unwrap();
+    let users = node.create_table("ks", "users", None, false).await.unwrap();
+    users
+        .database
+        .set("flushed".to_string(), "1".to_string())
+        .await
+        .unwrap();
+    users.database.flush_memtable_to_sstable().await.unwrap();
+    users
+        .database
+        .set("unflushed".to_string(), "2".to_string())
+        .await
+        .unwrap();
+
+    node.truncate("ks", "users").await.unwrap();
+    assert_eq!(users.database.get("flushed").await.unwrap(), None);
+    assert_eq!(users.database.get("unflushed").await.unwrap(), None);
+    assert!(users.database.sstables.lock().await.is_empty());
+    users
+        .database
+        .set("after".to_string(), "truncate".to_string())
+        .await
+        .unwrap();
+    drop(users);
+
+    node.drop_table("ks", "users", false).await.unwrap();
+    assert!(matches!(
+        node.table("ks", "users").await,
+        Err(Error::InvalidRequest(_))
+    ));
+    assert!(matches!(
+        node.drop_table("ks", "users", false).await,
+        Err(Error::InvalidRequest(_))
+    ));
+    node.drop_table("ks", "users", true).await.unwrap();
+    // it was the last table in the keyspace
+    assert!(!std::path::Path::new(&format!("{}/ks", ctx.data_dir)).exists());
+    assert_eq!(
+        node.keyspace_names().await,
+        vec![DEFAULT_KEYSPACE.to_string()]
+    );
+
+    // a new table with the same name doesn't get the old one's writes from the WAL
+    drop(node);
+    let node = Node::load(&ctx.data_dir).await.unwrap();
+    assert!(node.table("ks", "users").await.is_err());
+    let users = node.create_table("ks", "users", None, false).await.unwrap();
+    assert_eq!(users.database.get("after").await.unwrap(), None);
+    drop((users, node));
+    let node = Node::load(&ctx.data_dir).await.unwrap();
+    let users = node.table("ks", "users").await.unwrap();
+    assert_eq!(users.database.get("after").await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn test_drop_table_rejects_new_operations_without_waiting_for_open_scans() {
+    let ctx = setup().await;
+    let node = Node::load(&ctx.data_dir).await.unwrap();
+    let users = node.create_table("ks", "users", None, false).await.unwrap();
+    for key in ["a", "b"] {
+        users
+            .database
+            .set(key.to_string(), "1".to_string())
+            .await
+            .unwrap();
+    }
+    users.database.flush_memtable_to_sstable().await.unwrap();
+
+    // a scan that has started doesn't hold up the drop, it finishes from what it started with
+    let mut rows = Box::pin(users.database.scan::<&str>(.., None));
+    assert_eq!(
+        rows.next().await.unwrap().unwrap(),
+        ("a".to_string(), "1".to_string())
+    );
+    tokio::time::timeout(
+        Duration::from_secs(5),
+        node.drop_table("ks", "users", false),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    assert!(!std::path::Path::new(&format!("{}/ks", ctx.data_dir)).exists());
+    assert_eq!(
+        rows.next().await.unwrap().unwrap(),
+        ("b".to_string(), "1".to_string())
+    );
+    assert!(rows.next().await.is_none());
+
+    // whoever still holds the table can't use it anymore
+    assert!(matches!(
+        users.database.set("c".to_string(), "1".to_string()).await,
+        Err(Error::Closed)
+    ));
+    assert!(matches!(users.database.get("a").await, Err(Error::Closed)));
+    assert!(matches!(
+        users.database.flush_memtable_to_sstable().await,
+        Err(Error::Closed)
+    ));
+    let rows = users.database.scan::<&str>(.., None);
+    tokio::pin!(rows);
+    assert!(matches!(rows.next().await, Some(Err(Error::Closed))));
+}
+
+#[tokio::test]
+async fn test_data_dir_from_before_tables_becomes_the_default_table() {
+    let ctx = setup().await;
+    let database = Database::new(&ctx.data_dir).unwrap();
+    database
+        .set("flushed".to_string(), "1".to_string())
+        .await
+        .unwrap();
+    database.flush_memtable_to_sstable().await.unwrap();
+    database
+        .set("unflushed".to_string(), "2".to_string())
+        .await
+        .unwrap();
+    drop(database);
+
+    let node = Node::load(&ctx.data_dir).await.unwrap();
+    let table = node.table(DEFAULT_KEYSPACE, DEFAULT_TABLE).await.unwrap();
+    assert_eq!(
+        table.database.get("flushed").await.unwrap(),
+        Some("1".to_string())
+    );
+    assert_eq!(
+        table.database.get("unflushed").await.unwrap(),
+        Some("2".to_string())
+    );
+    assert_eq!(
+        table.database.config.data_dir,
+        format!("{}/{}/{}", ctx.data_dir, DEFAULT_KEYSPACE, DEFAULT_TABLE)
+    );
+    assert!(std::path::Path::new(&format!("{}/MANIFEST", table.database.config.data_dir)).exists());
+}
+
+#[tokio::test]
+async fn test_rows_with_a_typed_schema() {
+    let ctx = setup().await;
+    let node = Node::load(&ctx.data_dir).await.unwrap();
+    let Statement::CreateTable { schema, .. } = Statement::from_str(
+        "CREATE TABLE ks.users (id int PRIMARY KEY, name text, admin boolean, avatar blob);\n",
+    )
+    .unwrap() else {
+        panic!("not a CREATE TABLE");
+    };
+    let users = node
+        .create_table("ks", "users", schema, false)
+        .await
+        .unwrap();
+    let text = |value: &str| Some(Value::Text(value.to_string()));
+    let row = |values: &[(&str, Value)]| {
+        values
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.clone()))
+            .collect::<Vec<_>>()
+    };
+
+    users
+        .insert(
+            vec![
+                ("id".to_string(), Some(Value::Int(1))),
+                ("name".to_string(), text("Bob")),
+                ("avatar".to_string(), Some(Value::Blob(vec![0xca, 0xfe]))),
+            ],
+            10,
+            0,
+        )
+        .await
+        .unwrap();
+    users
+        .insert(vec![("id".to_string(), Some(Value::Int(2)))], 10, 0)
+        .await
+        .unwrap();
+    users.database.flush_memtable_to_sstable().await.unwrap();
+    // writing one column leaves the others alone
+    users
+        .insert(
+            vec![
+                ("id".to_string(), Some(Value::Int(1))),
+                ("admin".to_string(), Some(Value::Boolean(true))),
+            ],
+            20,
+            0,
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        users.get_row(&[Value::Int(1)]).await.unwrap(),
+        Some(row(&[
+            ("id", Value::Int(1)),
+            ("name", Value::Text("Bob".to_string())),
+            ("admin", Value::Boolean(true)),
+            ("avatar", Value::Blob(vec![0xca, 0xfe])),
+        ]))
+    );
+    // a row with nothing but its primary key is still there
+    assert_eq!(
+        users.get_row(&[Value::Int(2)]).await.unwrap(),
+        Some(row(&[("id", Value::Int(2))]))
+    );
+    assert_eq!(users.get_row(&[Value::Int(3)]).await.unwrap(), None);
+
+    // values have to match the column types, and the primary key has to be given
+    assert!(matches!(
+        users
+            .insert(
+                vec![
+                    ("id".to_string(), Some(Value::Int(3))),
+                    ("name".to_string(), Some(Value::Int(3))),
+                ],
+                30,
+                0,
+            )
+            .await,
+        Err(Error::InvalidRequest(_))
+    ));
+    assert!(matches!(
+        users
+            .insert(vec![("name".to_string(), text("Al"))], 30, 0)
+            .await,
+        Err(Error::InvalidRequest(_))
+    ));
+
+    users
+        .delete(&[Value::Int(1)], &["avatar".to_string()], 30)
+        .await
+        .unwrap();
+    users.delete(&[Value::Int(2)], &[], 30).await.unwrap();
+    // an older write doesn't bring a deleted column back
+    users
+        .insert(
+            vec![
+                ("id".to_string(), Some(Value::Int(1))),
+                ("avatar".to_string(), Some(Value::Blob(vec![]))),
+            ],
+            25,
+            0,
+        )
+        .await
+        .unwrap();
+
+    drop((users, node));
+    let node = Node::load(&ctx.data_dir).await.unwrap();
+    let users = node.table("ks", "users").await.unwrap();
+    let rows = users
+        .select(&Slice::all(), None)
+        .collect::<Result<Vec<_>, _>>()
+        .await
+        .unwrap();
+    assert_eq!(
+        rows,
+        vec![row(&[
+            ("id", Value::Int(1)),
+            ("name", Value::Text("Bob".to_string())),
+            ("admin", Value::Boolean(true)),
+        ])]
+    );
+    assert_eq!(
+        users
+            .select(&Slice::all(), Some(0))
+            .collect::<Vec<_>>()
+            .await
+            .len(),
+        0
+    );
+}
+
+#[tokio::test]
+async fn test_partitions_sorted_by_clustering_columns() {
+    let ctx = setup().await;
+    let node = Node::load(&ctx.data_dir).await.unwrap();
+    let Statement::CreateTable { schema, .. } = Statement::from_str(
+        "CREATE TABLE ks.readings (sensor text, day int, at bigint, seq int, value double, PRIMARY KEY ((sensor, day), at, seq)) WITH CLUSTERING ORDER BY (at DESC);\n",
+    )
+    .unwrap() else {
+        panic!("not a CREATE TABLE");
+    };
+    let readings = node
+        .create_table("ks", "readings", schema, false)
+        .await
+        .unwrap();
+    let partition = |sensor: &str| vec![Value::Text(sensor.to_string()), Value::Int(1)];
+    for (i, (sensor, at, seq)) in [
+        ("a", 10, 0),
+        ("a", -5, 0),
+        ("a", 20, 1),
+        ("a", 20, 0),
+        ("b", 15, 0),
+        ("a", 30, 0),
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        let mut columns = vec![
+            ("sensor".to_string(), Some(Value::Text(sensor.to_string()))),
+            ("day".to_string(), Some(Value::Int(1))),
+            ("at".to_string(), Some(Value::BigInt(at))),
+            ("seq".to_string(), Some(Value::Int(seq))),
+        ];
+        columns.push(("value".to_string(), Some(Value::Double(i as f64))));
+        readings.insert(columns, 10, 0).await.unwrap();
+        if i == 2 {
+            readings.database.flush_memtable_to_sstable().await.unwrap();
+        }
+    }
+    // the at and seq of the rows a slice reads
+    let keys = |readings: Arc<Table>, slice: Slice, limit: Option<usize>| async move {
+        readings
+            .select(&slice, limit)
+            .map(|row| {
+                let row = row.unwrap();
+                (row[2].1.clone(), row[3].1.clone())
+            })
+            .collect::<Vec<_>>()
+            .await
+    };
+    let key = |at: i64, seq: i32| (Value::BigInt(at), Value::Int(seq));
+
+    // newest first, as the table sorts them
+    assert_eq!(
+        keys(readings.clone(), Slice::partition(partition("a")), None).await,
+        vec![key(30, 0), key(20, 0), key(20, 1), key(10, 0), key(-5, 0)]
+    );
+    assert_eq!(
+        keys(
+            readings.clone(),
+            Slice {
+                start: Bound::Excluded(Value::BigInt(10)),
+                reversed: true,
+                ..Slice::partition(partition("a"))
+            },
+            Some(2)
+        )
+        .await,
+        vec![key(20, 1), key(20, 0)]
+    );
+    // a limit on a reversed slice takes the rows from its end
+    for (limit, expected) in [
+        (0, vec![]),
+        (3, vec![key(-5, 0), key(10, 0), key(20, 1)]),
+        (
+            10,
+            vec![key(-5, 0), key(10, 0), key(20, 1), key(20, 0), key(30, 0)],
+        ),
+    ] {
+        assert_eq!(
+            keys(
+                readings.clone(),
+                Slice {
+                    reversed: true,
+                    ..Slice::partition(partition("a"))
+                },
+                Some(limit)
+            )
+            .await,
+            expected
+        );
+    }
+    assert_eq!(
+        keys(
+            readings.clone(),
+            Slice {
+                start: Bound::Included(Value::BigInt(-5)),
+                end: Bound::Excluded(Value::BigInt(20)),
+                ..Slice::partition(partition("a"))
+            },
+            None
+        )
+        .await,
+        vec![key(10, 0), key(-5, 0)]
+    );
+    assert_eq!(
+        keys(
+            readings.clone(),
+            Slice {
+                clustering_prefix: vec![Value::BigInt(20)],
+                start: Bound::Excluded(Value::Int(0)),
+                ..Slice::partition(partition("a"))
+            },
+            None
+        )
+        .await,
+        vec![key(20, 1)]
+    );
+    assert_eq!(
+        keys(readings.clone(), Slice::partition(partition("b")), None).await,
+        vec![key(15, 0)]
+    );
+    assert_eq!(
+        keys(readings.clone(), Slice::partition(partition("c")), None).await,
+        vec![]
+    );
+    // a clustering range needs a partition
+    assert!(matches!(
+        readings
+            .select(
+                &Slice {
+                    start: Bound::Included(Value::BigInt(0)),
+                    ..Slice::all()
+                },
+                None
+            )
+            .collect::<Vec<_>>()
+            .await[..],
+        [Err(Error::InvalidRequest(_))]
+    ));
+
+    let mut row_key = partition("a");
+    row_key.extend([Value::BigInt(20), Value::Int(0)]);
+    readings.delete(&row_key, &[], 20).await.unwrap();
+    drop((readings, node));
+    let node = Node::load(&ctx.data_dir).await.unwrap();
+    let readings = node.table("ks", "readings").await.unwrap();
+    assert_eq!(
+        keys(readings.clone(), Slice::partition(partition("a")), None).await,
+        vec![key(30, 0), key(20, 1), key(10, 0), key(-5, 0)]
+    );
+    let mut row_key = partition("b");
+    row_key.extend([Value::BigInt(15), Value::Int(0)]);
+    assert_eq!(
+        readings.get_row(&row_key).await.unwrap(),
+        Some(vec![
+            ("sensor".to_string(), Value::Text("b".to_string())),
+            ("day".to_string(), Value::Int(1)),
+            ("at".to_string(), Value::BigInt(15)),
+            ("seq".to_string(), Value::Int(0)),
+            ("value".to_string(), Value::Double(4.0)),
+        ])
+    );
+    assert_eq!(
+        readings
+            .select(&Slice::all(), None)
+            .collect::<Vec<_>>()
+            .await
+            .len(),
+        5
+    );
+}
+
+#[tokio::test]
+async fn test_queries_only_filter_and_order_by_the_primary_key() {
+    let ctx = setup().await;
+    let node = Node::load(&ctx.data_dir).await.unwrap();
+    query(
+        &node,
+        "CREATE TABLE ks.readings (sensor text, at bigint, value double, PRIMARY KEY (sensor, at));\n",
+    )
+    .await
+    .unwrap();
+    query(
+        &node,
+        "INSERT INTO ks.readings (sensor, at, value) VALUES ('a', 10, 1.5);\n",
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        query(
+            &node,
+            "SELECT at, value FROM ks.readings WHERE sensor = 'a' AND at >= 10;\n"
+        )
+        .await
+        .unwrap(),
+        "at: 10, value: 1.5\n"
+    );
+
+    for statement in [
+        // relations on columns that aren't part of the primary key
+        "SELECT * FROM ks.readings WHERE sensor = 'a' AND value > 1.0;\n",
+        "SELECT * FROM ks.readings WHERE sensor = 'a' AND value = 1.5;\n",
+        // an ORDER BY that's neither the clustering order nor its reverse
+        "SELECT * FROM ks.readings WHERE sensor = 'a' ORDER BY value ASC;\n",
+        "SELECT * FROM ks.readings WHERE sensor = 'a' ORDER BY sensor DESC;\n",
+        // filtering or ordering without the partition key
+        "SELECT * FROM ks.readings WHERE at > 5;\n",
+        "SELECT * FROM ks.readings ORDER BY at DESC;\n",
+        "DELETE FROM ks.readings WHERE at = 10;\n",
+        "INSERT INTO ks.readings (at, value) VALUES (20, 2.5);\n",
+    ] {
+        assert!(
+            matches!(query(&node, statement).await, Err(Error::InvalidRequest(_))),
+            "{}",
+            statement
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_order_by_the_reverse_clustering_order_with_a_limit() {
+    let ctx = setup().await;
+    let node = Node::load(&ctx.data_dir).await.unwrap();
+    query(
+        &node,
+        "CREATE TABLE ks.readings (sensor text, at bigint, PRIMARY KEY (sensor, at)) WITH CLUSTERING ORDER BY (at ASC);\n",
+    )
+    .await
+    .unwrap();
+    let readings = node.table("ks", "readings").await.unwrap();
+    // two SSTables and the MemTable, each with rows from both ends of the partition
+    for (i, at) in [5, 1, 3, 6, 2, 4].into_iter().enumerate() {
+        for sensor in ["a", "b"] {
+            query(
+                &node,
+                &format!(
+                    "INSERT INTO ks.readings (sensor, at) VALUES ('{}', {});\n",
+                    sensor, at
+                ),
+            )
+            .await
+            .unwrap();
+        }
+        if i % 2 == 1 && i < 4 {
+            readings.database.flush_memtable_to_sstable().await.unwrap();
+        }
+    }
+    assert_eq!(readings.database.sstables.lock().await.len(), 2);
+
+    let select =
+        |filter: &str| format!("SELECT at FROM ks.readings WHERE sensor = 'a'{};\n", filter);
+    for (filter, expected) in [
+        (" ORDER BY at DESC LIMIT 3", "at: 6\nat: 5\nat: 4\n"),
+        (" AND at < 6 ORDER BY at DESC LIMIT 2", "at: 5\nat: 4\n"),
+        (
+            " AND at <= 3 ORDER BY at DESC LIMIT 10",
+            "at: 3\nat: 2\nat: 1\n",
+        ),
+        (" ORDER BY at ASC LIMIT 2", "at: 1\nat: 2\n"),
+        (" LIMIT 2", "at: 1\nat: 2\n"),
+        (" ORDER BY at DESC LIMIT 0", "No rows found"),
+    ] {
+        assert_eq!(
+            query(&node, &select(filter)).await.unwrap(),
+            expected,
+            "{}",
+            filter
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_blobs_that_are_not_utf8_round_trip_and_raw_bytes_are_rejected() {
+    let ctx = setup().await;
+    let node = Node::load(&ctx.data_dir).await.unwrap();
+    query(
+        &node,
+        "CREATE TABLE ks.files (name text, data blob, PRIMARY KEY (name));\n",
+    )
+    .await
+    .unwrap();
+    query(
+        &node,
+        "INSERT INTO ks.files (name, data) VALUES ('a', 0x00ff80c3);\n",
+    )
+    .await
+    .unwrap();
+
+    // bytes that aren't UTF-8 have to be a 0x literal, they're turned away before any write
+    let mut request = b"INSERT INTO ks.files (name, data) VALUES ('b', '".to_vec();
+    request.extend_from_slice(&[0xff, 0x80]);
+    request.extend_from_slice(b"');\n");
+    assert!(matches!(
+        kassantra::query::execute_request(&node, &request).await,
+        Err(Error::InvalidRequest(_))
+    ));
+
+    let files = node.table("ks", "files").await.unwrap();
+    files.database.flush_memtable_to_sstable().await.unwrap();
+    drop((files, node));
+    let node = Node::load(&ctx.data_dir).await.unwrap();
+    assert_eq!(
+        query(&node, "SELECT * FROM ks.files;\n").await.unwrap(),
+        "name: a, data: 0x00ff80c3\n"
+    );
+}
+
 // #[tokio::test]
 // async fn test_an_arbitrary_sstable_to_see_what_it_contains() {
 //     let path = "data/sstable_10_71aed8fe-2119-4216-9cd6-2244963e2861";
@@ -245,6 +2796,8 @@ async fn test_sstable_compaction_keys_are_ordered_after_compaction() {
 // after all tests, remove all sstables and wal files
 struct Setup {
     data_dir: String,
+    // removed when it's dropped, after `teardown` waited out the background I/O
+    _dir: tempfile::TempDir,
 }
 
 impl Drop for Setup {
@@ -254,14 +2807,43 @@ impl Drop for Setup {
     }
 }
 
+// The entries of an SSTable without their timestamps
+async fn read_operations(sstable: &SSTable) -> Vec<(String, Operation)> {
+    sstable
+        .read_all()
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|(key, cell)| (key, cell.operation))
+        .collect()
+}
+
+// Runs a statement the way the server does
+async fn query(node: &Node, statement: &str) -> Result<String, Error> {
+    kassantra::query::execute_request(node, statement.as_bytes()).await
+}
+
 async fn setup() -> Setup {
-    let random_dir_name = Uuid::new_v4().to_string();
-    return Setup {
-        data_dir: random_dir_name.clone(),
-    };
+    let dir = tempfile::Builder::new()
+        .prefix("kassantra_test_")
+        .tempdir()
+        .unwrap();
+    Setup {
+        data_dir: dir.path().to_str().unwrap().to_string(),
+        _dir: dir,
+    }
 }
 
 fn teardown(data_dir: &str) {
-    // remove data dir
+    // remove data dir. A flush or compaction the test didn't wait for can still be creating
+    // a file in it, which makes the removal fail with the dir not empty, so try again once
+    // that's done
+    for _ in 0..50 {
+        match std::fs::remove_dir_all(data_dir) {
+            Ok(()) => return,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return,
+            Err(_) => std::thread::sleep(Duration::from_millis(20)),
+        }
+    }
     std::fs::remove_dir_all(data_dir).unwrap();
 }